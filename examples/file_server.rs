@@ -1,12 +1,12 @@
 extern crate prox;
 extern crate toml;
 
-/// This file server will attempt to serve requests from the ./public folder.
-///
-/// Prox supports many sites (virtual hosts). But if you only have one,
-/// just create a single site entry where site.host == server.bind.
+// This file server will attempt to serve requests from the ./public folder.
+//
+// Prox supports many sites (virtual hosts). But if you only have one,
+// just create a single site entry where site.host == server.bind.
 
-const CONFIG_TOML: &'static str = r#"
+const CONFIG_TOML: &str = r#"
     [server]
     bind = "localhost:3000"
 
@@ -0,0 +1,158 @@
+//! Balancing a site's requests between its upstream targets.
+//!
+//! Targets are picked round-robin. A target that refused a connection is skipped for
+//! a while. With `sticky`, a client keeps going to the target named by its cookie
+//! as long as that target is up.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use hyper::header::{self, Headers};
+use url::Url;
+
+use config::Site;
+
+// Root tags the request with the index of the chosen target for the proxy.
+header! {
+    (ChosenTarget, "X-Prox-Target") => [usize]
+}
+
+/// How many seconds a target is skipped after it failed.
+const DOWN_FOR_SECS: u64 = 10;
+
+#[derive(Debug, PartialEq)]
+pub struct Choice {
+    pub index: usize,
+    /// The client should be sent a cookie that pins it to this target.
+    pub set_cookie: bool,
+}
+
+/// A site's balancing state.
+pub struct Balancer {
+    next: AtomicUsize,
+    down_until: Mutex<Vec<Option<Instant>>>,
+}
+
+impl Balancer {
+    pub fn new(targets: usize) -> Self {
+        Balancer {
+            next: AtomicUsize::new(0),
+            down_until: Mutex::new(vec![None; targets]),
+        }
+    }
+
+    pub fn choose(&self, site: &Site, headers: &Headers) -> Choice {
+        let down_until = self.down_until.lock().unwrap();
+        let now = Instant::now();
+        let is_up = |i: usize| down_until[i].map(|until| until <= now).unwrap_or(true);
+
+        let cookie = site.sticky.as_ref().map(|sticky| &sticky.cookie);
+
+        // Stay on the pinned target while it's up
+        let pinned = cookie
+            .and_then(|name| headers.get::<header::Cookie>().and_then(|cookies| cookies.get(name)))
+            .and_then(|id| site.targets.iter().position(|url| target_id(url) == id));
+
+        if let Some(index) = pinned {
+            if is_up(index) {
+                return Choice {
+                    index,
+                    set_cookie: false,
+                };
+            }
+        }
+
+        let n = site.targets.len();
+        let start = self.next.fetch_add(1, Ordering::SeqCst);
+
+        // If every target is down, then go round-robin anyway
+        let index = (0..n)
+            .map(|offset| (start + offset) % n)
+            .find(|&i| is_up(i))
+            .unwrap_or(start % n);
+
+        Choice {
+            index,
+            set_cookie: cookie.is_some(),
+        }
+    }
+
+    pub fn mark_down(&self, index: usize) {
+        warn!("[balance] upstream target {} is down", index);
+        let until = Instant::now() + Duration::from_secs(DOWN_FOR_SECS);
+        self.down_until.lock().unwrap()[index] = Some(until);
+    }
+
+    pub fn mark_up(&self, index: usize) {
+        self.down_until.lock().unwrap()[index] = None;
+    }
+}
+
+/// Identifies a target in the sticky cookie without revealing its address.
+pub fn target_id(url: &Url) -> String {
+    let mut hasher = DefaultHasher::new();
+    url.as_str().hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// The Set-Cookie value that pins a client to a target.
+pub fn cookie(name: &str, url: &Url) -> String {
+    format!("{}={}; Path=/; HttpOnly", name, target_id(url))
+}
+
+#[test]
+fn test_choose() {
+    use config::Sticky;
+
+    let site = Site {
+        targets: vec![
+            Url::parse("http://localhost:4001").unwrap(),
+            Url::parse("http://localhost:4002").unwrap(),
+            Url::parse("http://localhost:4003").unwrap(),
+        ],
+        sticky: Some(Sticky {
+            cookie: "prox_srv".to_string(),
+        }),
+        ..Site::default()
+    };
+    let balancer = Balancer::new(3);
+
+    // Round-robin
+    let picks = (0..4)
+        .map(|_| balancer.choose(&site, &Headers::new()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        picks.iter().map(|c| c.index).collect::<Vec<_>>(),
+        vec![0, 1, 2, 0]
+    );
+    assert!(picks.iter().all(|c| c.set_cookie));
+
+    // Down targets are skipped
+    balancer.mark_down(1);
+    assert_eq!(balancer.choose(&site, &Headers::new()).index, 2);
+
+    // Pinned to the third
+    let mut headers = Headers::new();
+    let mut cookies = header::Cookie::new();
+    cookies.set("prox_srv", target_id(&site.targets[2]));
+    headers.set(cookies);
+    assert_eq!(
+        balancer.choose(&site, &headers),
+        Choice {
+            index: 2,
+            set_cookie: false,
+        }
+    );
+
+    // Until it goes down
+    balancer.mark_down(2);
+    let choice = balancer.choose(&site, &headers);
+    assert_eq!(choice.index, 0);
+    assert!(choice.set_cookie);
+
+    balancer.mark_up(2);
+    assert_eq!(balancer.choose(&site, &headers).index, 2);
+}
@@ -0,0 +1,270 @@
+//! Helpers for transforming streamed bodies.
+
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+use futures::sync::mpsc;
+use futures_cpupool::CpuPool;
+use hyper::{self, Body, Chunk};
+use tokio_core::reactor::Handle;
+
+/// Drives a stream of chunks on the pool and returns it as a body.
+//
+// TODO: Replace with Body::wrap_stream() when Hyper 0.12.x releases.
+pub fn spawn<S>(pool: &CpuPool, stream: S) -> Body
+where
+    S: Stream<Item = Chunk, Error = hyper::Error> + Send + 'static,
+{
+    let (tx, body) = Body::pair();
+    pool.spawn(tx.send_all(stream.then(Ok))).forget();
+    body
+}
+
+/// Drives a stream of chunks on the reactor and returns it as a body.
+///
+/// For streams that use the reactor's timers.
+pub fn spawn_local<S>(handle: &Handle, stream: S) -> Body
+where
+    S: Stream<Item = Chunk, Error = hyper::Error> + 'static,
+{
+    let (tx, body) = Body::pair();
+    handle.spawn(tx.send_all(stream.then(Ok)).then(|_| Ok(())));
+    body
+}
+
+/// Passes chunks through untouched while keeping a copy of them.
+///
+/// Once the stream ends, the copy is handed to `on_done`. If the stream grows past
+/// `limit` bytes or fails, the copy is dropped and `on_done` is never called.
+pub struct Tee<S, F> {
+    stream: S,
+    buf: Option<Vec<u8>>,
+    limit: u64,
+    on_done: Option<F>,
+}
+
+impl<S, F> Tee<S, F> {
+    pub fn new(stream: S, limit: u64, on_done: F) -> Self {
+        Tee {
+            stream,
+            buf: Some(Vec::new()),
+            limit,
+            on_done: Some(on_done),
+        }
+    }
+}
+
+impl<S, F> Stream for Tee<S, F>
+where
+    S: Stream<Item = Chunk, Error = hyper::Error>,
+    F: FnOnce(Vec<u8>),
+{
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        let item = match self.stream.poll() {
+            Err(e) => {
+                self.buf = None;
+                return Err(e);
+            }
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(item)) => item,
+        };
+
+        match item {
+            Some(chunk) => {
+                let over_limit = match self.buf {
+                    Some(ref buf) => buf.len() as u64 + chunk.len() as u64 > self.limit,
+                    None => false,
+                };
+                if over_limit {
+                    self.buf = None;
+                } else if let Some(ref mut buf) = self.buf {
+                    buf.extend_from_slice(&chunk);
+                }
+                Ok(Async::Ready(Some(chunk)))
+            }
+            None => {
+                if let (Some(buf), Some(on_done)) = (self.buf.take(), self.on_done.take()) {
+                    on_done(buf);
+                }
+                Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+/// Fails the stream as soon as more than `limit` bytes have passed through.
+///
+/// `exceeded` is set so that whoever was reading the body can tell why it failed.
+pub struct Limit<S> {
+    stream: S,
+    remaining: u64,
+    exceeded: Arc<AtomicBool>,
+}
+
+impl<S> Limit<S> {
+    pub fn new(stream: S, limit: u64, exceeded: Arc<AtomicBool>) -> Self {
+        Limit {
+            stream,
+            remaining: limit,
+            exceeded,
+        }
+    }
+}
+
+impl<S> Stream for Limit<S>
+where
+    S: Stream<Item = Chunk, Error = hyper::Error>,
+{
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        match self.stream.poll()? {
+            Async::Ready(Some(ref chunk)) if chunk.len() as u64 > self.remaining => {
+                self.exceeded.store(true, Ordering::SeqCst);
+                Err(hyper::Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "body exceeded max_body_size",
+                )))
+            }
+            Async::Ready(Some(chunk)) => {
+                self.remaining -= chunk.len() as u64;
+                Ok(Async::Ready(Some(chunk)))
+            }
+            x => Ok(x),
+        }
+    }
+}
+
+/// Copies every chunk of a stream into `n` bodies.
+///
+/// A body that is dropped by its reader stops receiving chunks without disturbing the others.
+pub fn fanout<S>(pool: &CpuPool, stream: S, n: usize) -> Vec<Body>
+where
+    S: Stream<Item = Chunk, Error = hyper::Error> + Send + 'static,
+{
+    let mut bodies = Vec::with_capacity(n);
+    let mut slots = Vec::with_capacity(n);
+
+    for _ in 0..n {
+        let (tx, body) = Body::pair();
+        slots.push(Slot { tx, pending: None });
+        bodies.push(body);
+    }
+
+    pool.spawn(Fanout {
+        stream,
+        slots,
+        done: false,
+    }).forget();
+
+    bodies
+}
+
+struct Slot {
+    tx: mpsc::Sender<Result<Chunk, hyper::Error>>,
+    pending: Option<Result<Chunk, hyper::Error>>,
+}
+
+impl Slot {
+    // Ok(true) once everything was handed to the reader. Err if the reader went away.
+    fn flush(&mut self) -> Result<bool, ()> {
+        if let Some(item) = self.pending.take() {
+            match self.tx.start_send(item) {
+                Err(_) => return Err(()),
+                Ok(AsyncSink::NotReady(item)) => {
+                    self.pending = Some(item);
+                    return Ok(false);
+                }
+                Ok(AsyncSink::Ready) => {}
+            }
+        }
+
+        match self.tx.poll_complete() {
+            Err(_) => Err(()),
+            Ok(Async::Ready(())) => Ok(true),
+            Ok(Async::NotReady) => Ok(false),
+        }
+    }
+}
+
+struct Fanout<S> {
+    stream: S,
+    slots: Vec<Slot>,
+    // Set once the source stream has ended
+    done: bool,
+}
+
+impl<S> Future for Fanout<S>
+where
+    S: Stream<Item = Chunk, Error = hyper::Error>,
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            let mut blocked = false;
+            let mut i = 0;
+            while i < self.slots.len() {
+                match self.slots[i].flush() {
+                    Ok(true) => i += 1,
+                    Ok(false) => {
+                        blocked = true;
+                        i += 1;
+                    }
+                    Err(()) => {
+                        self.slots.swap_remove(i);
+                    }
+                }
+            }
+
+            // Dropping the senders ends the bodies
+            if self.slots.is_empty() || (self.done && !blocked) {
+                return Ok(Async::Ready(()));
+            }
+
+            if blocked {
+                return Ok(Async::NotReady);
+            }
+
+            match self.stream.poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(Some(chunk))) => for slot in &mut self.slots {
+                    slot.pending = Some(Ok(Chunk::from(chunk.to_vec())));
+                },
+                Ok(Async::Ready(None)) => self.done = true,
+                Err(e) => {
+                    // hyper::Error isn't Clone, so every reader gets its own copy.
+                    let msg = e.to_string();
+                    for slot in &mut self.slots {
+                        let e = io::Error::other(msg.clone());
+                        slot.pending = Some(Err(hyper::Error::Io(e)));
+                    }
+                    self.done = true;
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_limit() {
+    use futures::stream;
+
+    let limit = |n| {
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let chunks = vec![Chunk::from("abc"), Chunk::from("def")];
+        let stream = stream::iter_ok::<_, hyper::Error>(chunks);
+        let result = Limit::new(stream, n, exceeded.clone()).collect().wait();
+        (result.is_ok(), exceeded.load(Ordering::SeqCst))
+    };
+
+    assert_eq!(limit(6), (true, false));
+    assert_eq!(limit(5), (false, true));
+}
@@ -1,7 +1,7 @@
 /// The boot message is the pretty heads-up that prints on server boot if stdout is tty.
 use colored::Colorize;
 
-use config::{Config, CorsOrigin, Site, Serve};
+use config::{Config, CorsOrigin, FastCgiAddress, Site, Serve};
 use host::Host;
 
 fn pretty_site(site: &Site) {
@@ -21,17 +21,81 @@ fn pretty_site(site: &Site) {
         "- proxy:  {}",
         match site.url {
             None => "off".to_string(),
-            Some(ref url) => format!("{}   -> {}", "on".green().bold(), url),
+            Some(_) => format!(
+                "{}   -> {}{}",
+                "on".green().bold(),
+                site.targets
+                    .iter()
+                    .map(|url| url.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                match site.sticky {
+                    None => "".to_string(),
+                    Some(ref sticky) => format!(" sticky={}", sticky.cookie.bold()),
+                },
+            ),
         }
     );
 
+    // FASTCGI
+
+    if let Some(ref fastcgi) = site.fastcgi {
+        println!(
+            "- fastcgi: {}   -> {} index={}",
+            "on".green().bold(),
+            match fastcgi.address {
+                FastCgiAddress::Tcp(ref address) => address.clone(),
+                FastCgiAddress::Unix(ref path) => format!("unix:{}", path.to_string_lossy()),
+            },
+            fastcgi.index.bold(),
+        );
+    }
+
+    // MIRROR
+
+    if let Some(ref mirror) = site.mirror {
+        println!(
+            "- mirror: {}   -> {} percent={}",
+            "on".green().bold(),
+            mirror.url,
+            mirror.percent.to_string().bold(),
+        );
+    }
+
     // GZIP
 
     println!(
         "- gzip:   {}",
         match site.gzip.as_ref() {
             None => "off".to_string(),
-            Some(_) => format!("{}", "on".green().bold()),
+            Some(opts) => format!(
+                "{} encodings={}{}",
+                "on".green().bold(),
+                opts.encodings
+                    .iter()
+                    .map(|coding| coding.token())
+                    .collect::<Vec<_>>()
+                    .join(",")
+                    .bold(),
+                if opts.decompress { " +decompress" } else { "" }.bold(),
+            ),
+        }
+    );
+
+    // CACHE
+
+    println!(
+        "- cache:  {}",
+        match site.cache {
+            None => "off".to_string(),
+            Some(ref opts) => format!(
+                "{} store={}",
+                "on".green().bold(),
+                match opts.path {
+                    None => "memory".to_string(),
+                    Some(ref path) => format!("\"{}\"", path.to_string_lossy()),
+                }.bold(),
+            ),
         }
     );
 
@@ -81,12 +145,24 @@ fn pretty_site(site: &Site) {
         "- serve:  {}",
         match site.serve {
             None => "off".to_string(),
-            Some(Serve { ref root, dotfiles, browse }) => format!(
-                "{} root=\"{}\"{}{}",
+            Some(Serve { ref root, dotfiles, browse, ref cgi, ref precompressed, .. }) => format!(
+                "{} root=\"{}\"{}{}{}{}",
                 "on".green().bold(),
                 root.to_str().unwrap_or("").to_string().bright_white().bold(),
                 if browse { " +browse" } else { "" }.bold(),
                 if dotfiles { " +dotfiles" } else { "" }.bold(),
+                match *cgi {
+                    None => "".to_string(),
+                    Some(ref cgi) => format!(" +cgi=/{}/", cgi.dir),
+                }.bold(),
+                if precompressed.is_empty() {
+                    "".to_string()
+                } else {
+                    format!(
+                        " +precompressed={}",
+                        precompressed.iter().map(|x| x.token()).collect::<Vec<_>>().join(",")
+                    )
+                }.bold(),
             ),
         }
     );
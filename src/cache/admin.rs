@@ -0,0 +1,192 @@
+//! Purging and inspecting a site's cache over HTTP.
+//!
+//! - `PURGE /some/url` drops every entry for that url.
+//! - `GET <path>` lists every entry, and the queues of the site's upstreams.
+//! - `DELETE <path>?url=/foo`, `?prefix=/assets/` or `?surrogate_key=products`
+//!   drops the matching entries.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::SystemTime;
+
+use hyper::{header, Method, Request, Response};
+use serde_json;
+use url::form_urlencoded;
+
+use cache::Store;
+use config;
+use queue::Limiter;
+use response;
+
+#[derive(Serialize)]
+struct Listing {
+    size: u64,
+    entries: Vec<Item>,
+    upstreams: Vec<Upstream>,
+}
+
+#[derive(Serialize)]
+struct Item {
+    key: String,
+    status: u16,
+    size: u64,
+    /// Seconds
+    age: u64,
+    hits: u64,
+    surrogate_keys: Vec<String>,
+}
+
+/// The connection queue of an upstream url if `site.upstream` is configured.
+#[derive(Serialize)]
+struct Upstream {
+    url: String,
+    active: usize,
+    depth: usize,
+    rejected: usize,
+    timed_out: usize,
+}
+
+#[derive(Serialize)]
+struct Purged {
+    purged: usize,
+}
+
+/// Returns a response if the request was meant for the cache API.
+pub fn handle(
+    store: &Store,
+    limiters: &HashMap<String, Limiter>,
+    remote_ip: IpAddr,
+    req: &Request,
+) -> Option<Response> {
+    let opts = store.purge_opts()?;
+
+    let is_purge = match *req.method() {
+        Method::Extension(ref method) => method.eq_ignore_ascii_case("PURGE"),
+        _ => false,
+    };
+
+    if !is_purge && req.path() != opts.path {
+        return None;
+    }
+
+    if !is_authorized(opts, remote_ip, req.headers()) {
+        return Some(response::forbidden());
+    }
+
+    if is_purge {
+        let url = match req.query() {
+            None => req.path().to_string(),
+            Some(query) => format!("{}?{}", req.path(), query),
+        };
+        return Some(json(&Purged {
+            purged: store.purge_url(&url),
+        }));
+    }
+
+    match *req.method() {
+        Method::Get | Method::Head => Some(list(store, limiters)),
+        Method::Delete => Some(purge(store, req.query().unwrap_or(""))),
+        _ => Some(response::bad_request("expected GET or DELETE")),
+    }
+}
+
+fn is_authorized(opts: &config::Purge, remote_ip: IpAddr, headers: &header::Headers) -> bool {
+    if opts.allow.contains(&remote_ip) {
+        return true;
+    }
+
+    match (
+        opts.token.as_ref(),
+        headers.get::<header::Authorization<header::Bearer>>(),
+    ) {
+        (Some(token), Some(header::Authorization(bearer))) => *token == bearer.token,
+        _ => false,
+    }
+}
+
+fn list(store: &Store, limiters: &HashMap<String, Limiter>) -> Response {
+    let now = SystemTime::now();
+
+    let mut entries = store
+        .entries()
+        .into_iter()
+        .map(|(key, entry)| Item {
+            status: entry.status.as_u16(),
+            size: entry.size,
+            age: entry.age(now).as_secs(),
+            hits: entry.hits,
+            surrogate_keys: entry.surrogate_keys,
+            key,
+        })
+        .collect::<Vec<_>>();
+
+    entries.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+
+    let mut upstreams = limiters
+        .iter()
+        .map(|(url, limiter)| {
+            let stats = limiter.stats();
+            Upstream {
+                url: url.clone(),
+                active: stats.active,
+                depth: stats.depth,
+                rejected: stats.rejected,
+                timed_out: stats.timed_out,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    upstreams.sort_unstable_by(|a, b| a.url.cmp(&b.url));
+
+    json(&Listing {
+        size: store.size(),
+        entries,
+        upstreams,
+    })
+}
+
+fn purge(store: &Store, query: &str) -> Response {
+    for (k, v) in form_urlencoded::parse(query.as_bytes()) {
+        let purged = match k.as_ref() {
+            "url" => store.purge_url(&v),
+            "prefix" => store.purge_prefix(&v),
+            "surrogate_key" => store.purge_surrogate_key(&v),
+            _ => continue,
+        };
+        return json(&Purged { purged });
+    }
+
+    response::bad_request("expected url, prefix or surrogate_key")
+}
+
+fn json<T: ::serde::Serialize>(value: &T) -> Response {
+    match serde_json::to_string(value) {
+        Ok(body) => response::json(body),
+        Err(e) => {
+            error!("failed to serialize cache api response: {}", e);
+            response::internal_server_error()
+        }
+    }
+}
+
+#[test]
+fn test_is_authorized() {
+    let opts = config::Purge {
+        allow: vec!["127.0.0.1".parse().unwrap()],
+        token: Some("secret".to_string()),
+        path: "/.prox/cache".to_string(),
+    };
+    let bearer = |token: &str| {
+        let mut headers = header::Headers::new();
+        headers.set(header::Authorization(header::Bearer {
+            token: token.to_string(),
+        }));
+        headers
+    };
+    let other_ip = "10.0.0.1".parse().unwrap();
+
+    assert!(is_authorized(&opts, "127.0.0.1".parse().unwrap(), &header::Headers::new()));
+    assert!(!is_authorized(&opts, other_ip, &header::Headers::new()));
+    assert!(is_authorized(&opts, other_ip, &bearer("secret")));
+    assert!(!is_authorized(&opts, other_ip, &bearer("wrong")));
+}
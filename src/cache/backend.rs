@@ -0,0 +1,61 @@
+//! Where cached bodies live.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub enum Backend {
+    /// Bodies are kept in memory.
+    Memory,
+    /// Bodies are written to files in this folder.
+    Disk(PathBuf),
+}
+
+/// A handle to a stored body.
+#[derive(Clone)]
+pub enum Stored {
+    Memory(Arc<Vec<u8>>),
+    Disk(PathBuf),
+}
+
+impl Backend {
+    pub fn new(path: Option<&Path>) -> Self {
+        match path {
+            None => Backend::Memory,
+            Some(path) => Backend::Disk(path.to_path_buf()),
+        }
+    }
+
+    /// Store a body under a filesystem-friendly name.
+    pub fn write(&self, name: &str, body: Vec<u8>) -> io::Result<Stored> {
+        match *self {
+            Backend::Memory => Ok(Stored::Memory(Arc::new(body))),
+            Backend::Disk(ref dir) => {
+                // Create the folder lazily so that a missing folder at boot isn't fatal.
+                fs::create_dir_all(dir)?;
+                let path = dir.join(name);
+                fs::write(&path, body)?;
+                Ok(Stored::Disk(path))
+            }
+        }
+    }
+}
+
+impl Stored {
+    pub fn read(&self) -> io::Result<Vec<u8>> {
+        match *self {
+            Stored::Memory(ref body) => Ok(body.to_vec()),
+            Stored::Disk(ref path) => fs::read(path),
+        }
+    }
+
+    /// Release the stored body. Errors are logged since there's nobody to report them to.
+    pub fn remove(&self) {
+        if let Stored::Disk(ref path) = *self {
+            if let Err(e) = fs::remove_file(path) {
+                error!("failed to remove cached body {:?}: {}", path, e);
+            }
+        }
+    }
+}
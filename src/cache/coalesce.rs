@@ -0,0 +1,110 @@
+//! Collapses concurrent fetches of the same resource into one upstream request.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use futures::sync::oneshot;
+use hyper::Response;
+use hyper::header::Headers;
+
+/// A request waiting on another request's upstream response.
+pub struct Waiter {
+    pub req_headers: Headers,
+    pub tx: oneshot::Sender<Response>,
+}
+
+#[derive(Default)]
+pub struct InFlight {
+    waiters: Mutex<HashMap<String, Vec<Waiter>>>,
+}
+
+pub enum Role<'a> {
+    /// Nobody else is fetching the key, so this request must.
+    Leader(Lock<'a>),
+    /// Another request is already fetching the key. Its response arrives here.
+    Waiter(oneshot::Receiver<Response>),
+}
+
+impl InFlight {
+    pub fn contains(&self, key: &str) -> bool {
+        self.waiters.lock().unwrap().contains_key(key)
+    }
+
+    pub fn join(&self, key: &str, req_headers: &Headers) -> Role<'_> {
+        let mut waiters = self.waiters.lock().unwrap();
+
+        if let Some(xs) = waiters.get_mut(key) {
+            let (tx, rx) = oneshot::channel();
+            xs.push(Waiter {
+                req_headers: req_headers.clone(),
+                tx,
+            });
+            return Role::Waiter(rx);
+        }
+
+        waiters.insert(key.to_string(), Vec::new());
+
+        Role::Leader(Lock {
+            in_flight: self,
+            key: Some(key.to_string()),
+        })
+    }
+}
+
+/// Held by the request that is fetching a key.
+///
+/// Dropping it cancels everyone waiting on the key so that they can fetch it themselves.
+pub struct Lock<'a> {
+    in_flight: &'a InFlight,
+    // Taken once released
+    key: Option<String>,
+}
+
+impl<'a> Lock<'a> {
+    /// Stops accepting waiters and returns the ones that piled up.
+    pub fn release(mut self) -> Vec<Waiter> {
+        let key = match self.key.take() {
+            None => return Vec::new(),
+            Some(key) => key,
+        };
+        self.in_flight
+            .waiters
+            .lock()
+            .unwrap()
+            .remove(&key)
+            .unwrap_or_default()
+    }
+}
+
+impl<'a> Drop for Lock<'a> {
+    fn drop(&mut self) {
+        if let Some(ref key) = self.key {
+            self.in_flight.waiters.lock().unwrap().remove(key);
+        }
+    }
+}
+
+#[test]
+fn test_join() {
+    let in_flight = InFlight::default();
+    let headers = Headers::new();
+
+    let lock = match in_flight.join("a", &headers) {
+        Role::Leader(lock) => lock,
+        Role::Waiter(_) => panic!("first request should lead"),
+    };
+
+    match in_flight.join("a", &headers) {
+        Role::Waiter(_) => {}
+        Role::Leader(_) => panic!("second request should wait"),
+    }
+
+    assert_eq!(lock.release().len(), 1);
+
+    // Released key can be led again
+    let role = in_flight.join("a", &headers);
+    match role {
+        Role::Leader(_) => {}
+        Role::Waiter(_) => panic!("key should be free"),
+    }
+}
@@ -0,0 +1,555 @@
+//! An HTTP response cache that sits in front of a site's upstream.
+
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use hyper::{header, Request, StatusCode};
+use hyper::header::Headers;
+use unicase::Ascii;
+
+use config;
+use host::Host;
+use split::ChosenVariant;
+
+pub mod admin;
+mod backend;
+mod coalesce;
+pub mod policy;
+
+use self::backend::{Backend, Stored};
+pub use self::coalesce::{Lock, Role};
+
+/// Builds the key that identifies a cached resource.
+///
+/// HEAD requests share the key of the GET request for the same uri.
+/// Each split variant gets its own keys since they come from different upstreams.
+pub fn key(req: &Request) -> String {
+    let mut host = req.headers()
+        .get::<header::Host>()
+        .map(|header| Host::from(header.clone()).to_string())
+        .unwrap_or_default();
+
+    if let Some(ChosenVariant(variant)) = req.headers().get::<ChosenVariant>() {
+        host = format!("{}@{}", variant, host);
+    }
+
+    match req.query() {
+        None => format!("{}{}", host, req.path()),
+        Some(query) => format!("{}{}?{}", host, req.path(), query),
+    }
+}
+
+/// A stored response.
+#[derive(Clone)]
+pub struct Entry {
+    pub status: StatusCode,
+    pub headers: Headers,
+    /// When we sent the request that produced this response.
+    pub request_time: SystemTime,
+    /// When we received the response.
+    pub response_time: SystemTime,
+    /// Byte length of the body.
+    pub size: u64,
+    /// The number of times this entry was served from cache.
+    pub hits: u64,
+    /// Tags from the upstream's `Surrogate-Key` header that can be used to purge the entry.
+    pub surrogate_keys: Vec<String>,
+    // The request header values that selected this variant.
+    vary: Vec<(Ascii<String>, Option<Vec<u8>>)>,
+    body: Stored,
+    last_used: u64,
+}
+
+/// A response from the upstream that's ready to be stored.
+pub struct Fetched {
+    pub status: StatusCode,
+    pub headers: Headers,
+    /// When we sent the request that produced this response.
+    pub request_time: SystemTime,
+    /// When we received the response.
+    pub response_time: SystemTime,
+    pub body: Vec<u8>,
+}
+
+impl Entry {
+    pub fn age(&self, now: SystemTime) -> Duration {
+        policy::current_age(&self.headers, self.request_time, self.response_time, now)
+    }
+
+    pub fn is_fresh(&self, req_headers: &Headers, now: SystemTime) -> bool {
+        policy::is_fresh(
+            req_headers,
+            &self.headers,
+            self.age(now),
+            policy::freshness_lifetime(&self.headers, self.response_time),
+        )
+    }
+
+    /// How long ago this entry stopped being fresh.
+    pub fn staleness(&self, now: SystemTime) -> Duration {
+        let lifetime = policy::freshness_lifetime(&self.headers, self.response_time);
+        self.age(now)
+            .checked_sub(lifetime)
+            .unwrap_or_else(|| Duration::from_secs(0))
+    }
+
+    /// Determines if we can ask the upstream whether this entry is still good.
+    pub fn has_validator(&self) -> bool {
+        self.headers.has::<header::ETag>() || self.headers.has::<header::LastModified>()
+    }
+
+    pub fn read_body(&self) -> io::Result<Vec<u8>> {
+        self.body.read()
+    }
+
+    fn matches(&self, req_headers: &Headers) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| raw_value(req_headers, name) == *value)
+    }
+}
+
+/// Holds every cached response for a site.
+pub struct Store {
+    opts: config::Cache,
+    backend: Backend,
+    inner: Mutex<Inner>,
+    in_flight: coalesce::InFlight,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<String, Vec<Entry>>,
+    // Total bytes of stored bodies.
+    size: u64,
+    // Incremented on every access for LRU bookkeeping.
+    tick: u64,
+    next_id: u64,
+}
+
+impl Store {
+    pub fn new(opts: &config::Cache) -> Self {
+        Store {
+            opts: opts.clone(),
+            backend: Backend::new(opts.path.as_deref()),
+            inner: Mutex::new(Inner::default()),
+            in_flight: coalesce::InFlight::default(),
+        }
+    }
+
+    /// How long a request waits on another request's fetch of the same key.
+    pub fn lock_timeout(&self) -> Duration {
+        self.opts.lock_timeout
+    }
+
+    /// The configured stale-while-revalidate window, if any.
+    pub fn stale_while_revalidate(&self) -> Option<Duration> {
+        self.opts.stale_while_revalidate.map(Duration::from_secs)
+    }
+
+    /// The configured stale-if-error window, if any.
+    pub fn stale_if_error(&self) -> Option<Duration> {
+        self.opts.stale_if_error.map(Duration::from_secs)
+    }
+
+    /// Determines if some request is already fetching the key.
+    pub fn is_in_flight(&self, key: &str) -> bool {
+        self.in_flight.contains(key)
+    }
+
+    /// Either take responsibility for fetching the key or wait on whoever already is.
+    pub fn join(&self, key: &str, req_headers: &Headers) -> Role<'_> {
+        self.in_flight.join(key, req_headers)
+    }
+
+    /// The largest body this store will accept.
+    pub fn max_entry_size(&self) -> u64 {
+        self.opts.max_entry_size
+    }
+
+    /// Finds the stored variant that matches the request.
+    pub fn lookup(&self, key: &str, req_headers: &Headers) -> Option<Entry> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let entry = inner
+            .entries
+            .get_mut(key)?
+            .iter_mut()
+            .find(|entry| entry.matches(req_headers))?;
+        entry.last_used = tick;
+        Some(entry.clone())
+    }
+
+    /// Bumps the hit count of the variant that matches the request.
+    pub fn record_hit(&self, key: &str, req_headers: &Headers) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(entry) = inner
+            .entries
+            .get_mut(key)
+            .and_then(|xs| xs.iter_mut().find(|entry| entry.matches(req_headers)))
+        {
+            entry.hits += 1;
+        }
+    }
+
+    /// Stores a response, replacing the variant with the same selecting headers.
+    pub fn insert(&self, key: String, req_headers: &Headers, fetched: Fetched) {
+        let Fetched {
+            status,
+            headers,
+            request_time,
+            response_time,
+            body,
+        } = fetched;
+        let size = body.len() as u64;
+        if size > self.opts.max_entry_size || size > self.opts.max_size {
+            return;
+        }
+
+        let id = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.next_id += 1;
+            inner.next_id
+        };
+
+        let name = {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            format!("{:016x}-{}", hasher.finish(), id)
+        };
+
+        // Write outside of the lock since the disk backend does io.
+        let stored = match self.backend.write(&name, body) {
+            Ok(stored) => stored,
+            Err(e) => {
+                error!("failed to store cached body for {}: {}", key, e);
+                return;
+            }
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+
+        let entry = Entry {
+            status,
+            vary: selecting_headers(&headers, req_headers),
+            surrogate_keys: surrogate_keys(&headers),
+            headers,
+            request_time,
+            response_time,
+            size,
+            hits: 0,
+            body: stored,
+            last_used: inner.tick,
+        };
+
+        inner.remove_variant(&key, req_headers);
+
+        while inner.size + size > self.opts.max_size && inner.evict() {}
+
+        inner.size += size;
+        inner.entries.entry(key).or_default().push(entry);
+    }
+
+    /// Updates a stored variant with the headers of a 304 Not Modified response.
+    ///
+    /// <https://tools.ietf.org/html/rfc7234#section-4.3.4>
+    pub fn freshen(
+        &self,
+        key: &str,
+        req_headers: &Headers,
+        res_headers: &Headers,
+        request_time: SystemTime,
+        response_time: SystemTime,
+    ) -> Option<Entry> {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner
+            .entries
+            .get_mut(key)?
+            .iter_mut()
+            .find(|entry| entry.matches(req_headers))?;
+
+        entry.headers.extend(
+            res_headers
+                .iter()
+                .filter(|h| !h.is::<header::ContentLength>() && !h.is::<header::ContentEncoding>()),
+        );
+        entry.request_time = request_time;
+        entry.response_time = response_time;
+
+        Some(entry.clone())
+    }
+
+    /// Drops every variant stored under the key.
+    pub fn remove(&self, key: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(entries) = inner.entries.remove(key) {
+            for entry in entries {
+                inner.size -= entry.size;
+                entry.body.remove();
+            }
+        }
+    }
+
+    pub fn purge_opts(&self) -> Option<&config::Purge> {
+        self.opts.purge.as_ref()
+    }
+
+    /// Drops every entry for the request path and query (e.g. `/foo?bar`) across all of
+    /// the site's hosts. Returns the number of entries dropped.
+    pub fn purge_url(&self, url: &str) -> usize {
+        self.purge_where(|key, _| key_url(key) == url)
+    }
+
+    /// Drops every entry whose path and query starts with the prefix.
+    pub fn purge_prefix(&self, prefix: &str) -> usize {
+        self.purge_where(|key, _| key_url(key).starts_with(prefix))
+    }
+
+    /// Drops every entry tagged with the surrogate key.
+    pub fn purge_surrogate_key(&self, surrogate_key: &str) -> usize {
+        self.purge_where(|_, entry| entry.surrogate_keys.iter().any(|k| k == surrogate_key))
+    }
+
+    fn purge_where<F>(&self, pred: F) -> usize
+    where
+        F: Fn(&str, &Entry) -> bool,
+    {
+        let mut inner = self.inner.lock().unwrap();
+        let mut purged = Vec::new();
+
+        for (key, entries) in &mut inner.entries {
+            let mut i = 0;
+            while i < entries.len() {
+                if pred(key, &entries[i]) {
+                    purged.push(entries.remove(i));
+                } else {
+                    i += 1;
+                }
+            }
+        }
+
+        inner.entries.retain(|_, entries| !entries.is_empty());
+
+        for entry in &purged {
+            inner.size -= entry.size;
+            entry.body.remove();
+        }
+
+        purged.len()
+    }
+
+    /// A snapshot of every stored entry and its key.
+    pub fn entries(&self) -> Vec<(String, Entry)> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .entries
+            .iter()
+            .flat_map(|(key, entries)| entries.iter().map(move |entry| (key.clone(), entry.clone())))
+            .collect()
+    }
+
+    /// Total bytes of stored bodies.
+    pub fn size(&self) -> u64 {
+        self.inner.lock().unwrap().size
+    }
+}
+
+// The path and query part of a key, e.g. "/foo?bar" of "localhost:80/foo?bar".
+fn key_url(key: &str) -> &str {
+    &key[key.find('/').unwrap_or(key.len())..]
+}
+
+// Space-separated tags, e.g. "Surrogate-Key: product-1 products".
+fn surrogate_keys(headers: &Headers) -> Vec<String> {
+    headers
+        .get_raw("Surrogate-Key")
+        .map(|raw| {
+            raw.iter()
+                .flat_map(|line| {
+                    String::from_utf8_lossy(line)
+                        .split_whitespace()
+                        .map(String::from)
+                        .collect::<Vec<_>>()
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+impl Inner {
+    fn remove_variant(&mut self, key: &str, req_headers: &Headers) {
+        let removed = match self.entries.get_mut(key) {
+            None => return,
+            Some(entries) => match entries.iter().position(|entry| entry.matches(req_headers)) {
+                None => return,
+                Some(idx) => entries.remove(idx),
+            },
+        };
+        self.size -= removed.size;
+        removed.body.remove();
+    }
+
+    // Drops the least recently used entry. Returns false if there was nothing to drop.
+    fn evict(&mut self) -> bool {
+        let oldest = self.entries
+            .iter()
+            .flat_map(|(key, entries)| {
+                entries
+                    .iter()
+                    .enumerate()
+                    .map(move |(idx, entry)| (entry.last_used, key.clone(), idx))
+            })
+            .min();
+
+        let (_, key, idx) = match oldest {
+            None => return false,
+            Some(x) => x,
+        };
+
+        let removed = {
+            let entries = self.entries.get_mut(&key).unwrap();
+            let removed = entries.remove(idx);
+            if entries.is_empty() {
+                self.entries.remove(&key);
+            }
+            removed
+        };
+
+        trace!("[cache] evicted {}", key);
+        self.size -= removed.size;
+        removed.body.remove();
+        true
+    }
+}
+
+/// Determines if two requests select the same variant of a response.
+pub fn same_variant(res_headers: &Headers, a: &Headers, b: &Headers) -> bool {
+    if let Some(&header::Vary::Any) = res_headers.get::<header::Vary>() {
+        return false;
+    }
+    selecting_headers(res_headers, a) == selecting_headers(res_headers, b)
+}
+
+// The request headers named by the response's Vary header.
+fn selecting_headers(
+    res_headers: &Headers,
+    req_headers: &Headers,
+) -> Vec<(Ascii<String>, Option<Vec<u8>>)> {
+    match res_headers.get::<header::Vary>() {
+        Some(header::Vary::Items(names)) => names
+            .iter()
+            .map(|name| (name.clone(), raw_value(req_headers, name)))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn raw_value(headers: &Headers, name: &str) -> Option<Vec<u8>> {
+    headers.get_raw(name).map(|raw| {
+        let mut value = Vec::new();
+        for (i, line) in raw.iter().enumerate() {
+            if i > 0 {
+                value.extend_from_slice(b", ");
+            }
+            value.extend_from_slice(line);
+        }
+        value
+    })
+}
+
+#[test]
+fn test_vary_selects_variant() {
+    use hyper::header::{AcceptEncoding, Encoding, qitem};
+
+    let store = Store::new(&config::Cache::default());
+    let now = SystemTime::now();
+    let fetched = |headers: Headers, body: &[u8]| Fetched {
+        status: StatusCode::Ok,
+        headers,
+        request_time: now,
+        response_time: now,
+        body: body.to_vec(),
+    };
+
+    let mut gzip_req = Headers::new();
+    gzip_req.set(AcceptEncoding(vec![qitem(Encoding::Gzip)]));
+    let plain_req = Headers::new();
+
+    let mut res_headers = Headers::new();
+    res_headers.set(header::Vary::Items(vec![Ascii::new("Accept-Encoding".to_string())]));
+
+    store.insert("k".to_string(), &gzip_req, fetched(res_headers.clone(), b"gz"));
+    store.insert("k".to_string(), &plain_req, fetched(res_headers, b"plain"));
+
+    assert_eq!(store.lookup("k", &gzip_req).unwrap().read_body().unwrap(), b"gz".to_vec());
+    assert_eq!(store.lookup("k", &plain_req).unwrap().read_body().unwrap(), b"plain".to_vec());
+}
+
+#[test]
+fn test_evicts_least_recently_used() {
+    let store = Store::new(&config::Cache {
+        max_size: 10,
+        ..config::Cache::default()
+    });
+    let now = SystemTime::now();
+    let none = Headers::new();
+
+    let insert = |key: &str| {
+        let fetched = Fetched {
+            status: StatusCode::Ok,
+            headers: Headers::new(),
+            request_time: now,
+            response_time: now,
+            body: vec![0; 4],
+        };
+        store.insert(key.to_string(), &none, fetched)
+    };
+
+    insert("a");
+    insert("b");
+    // Touch "a" so that "b" is the oldest
+    assert!(store.lookup("a", &none).is_some());
+    insert("c");
+
+    assert!(store.lookup("a", &none).is_some());
+    assert!(store.lookup("b", &none).is_none());
+    assert!(store.lookup("c", &none).is_some());
+}
+
+#[test]
+fn test_purge() {
+    let store = Store::new(&config::Cache::default());
+    let now = SystemTime::now();
+    let none = Headers::new();
+    let mut tagged = Headers::new();
+    tagged.set_raw("Surrogate-Key", "products product-1");
+
+    let insert = |key: &str, headers: &Headers| {
+        let fetched = Fetched {
+            status: StatusCode::Ok,
+            headers: headers.clone(),
+            request_time: now,
+            response_time: now,
+            body: vec![0; 4],
+        };
+        store.insert(key.to_string(), &none, fetched)
+    };
+
+    insert("localhost:80/a", &none);
+    insert("localhost:80/assets/a.js", &none);
+    insert("localhost:80/assets/b.js", &none);
+    insert("localhost:80/products/1", &tagged);
+
+    assert_eq!(store.purge_url("/a"), 1);
+    assert_eq!(store.purge_url("/a"), 0);
+    assert_eq!(store.purge_prefix("/assets/"), 2);
+    assert_eq!(store.purge_surrogate_key("product-1"), 1);
+    assert!(store.entries().is_empty());
+    assert_eq!(store.size(), 0);
+}
@@ -0,0 +1,370 @@
+//! HTTP caching rules for a shared cache.
+//!
+//! <https://tools.ietf.org/html/rfc7234>
+
+use std::cmp;
+use std::time::{Duration, SystemTime};
+
+use hyper::{header, Method, StatusCode};
+use hyper::header::{CacheDirective, Headers};
+
+header! {
+    (Age, "Age") => [u32]
+}
+
+/// Responses with these statuses may be cached without explicit freshness information.
+///
+/// <https://tools.ietf.org/html/rfc7231#section-6.1>
+fn is_heuristically_cacheable(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 200 | 203 | 204 | 300 | 301 | 404 | 405 | 410 | 414 | 501)
+}
+
+/// Only complete, final responses are stored. A 206 holds just the range one client asked
+/// for, and a 304 answers one client's conditional request, so neither can be served to
+/// anyone else.
+fn is_storable_status(status: StatusCode) -> bool {
+    matches!(
+        status.as_u16(),
+        200 | 203 | 204 | 300 | 301 | 302 | 307 | 308 | 404 | 405 | 410 | 414 | 501
+    )
+}
+
+fn directives(headers: &Headers) -> &[CacheDirective] {
+    match headers.get::<header::CacheControl>() {
+        Some(header::CacheControl(xs)) => xs,
+        None => &[],
+    }
+}
+
+fn has_directive(headers: &Headers, directive: &CacheDirective) -> bool {
+    directives(headers).contains(directive)
+}
+
+/// Determines if the request forbids us from using or storing a cached response at all.
+pub fn is_no_store(req_headers: &Headers) -> bool {
+    has_directive(req_headers, &CacheDirective::NoStore)
+}
+
+/// Determines if we're allowed to store an upstream response.
+///
+/// <https://tools.ietf.org/html/rfc7234#section-3>
+pub fn is_storable(
+    method: &Method,
+    req_headers: &Headers,
+    status: StatusCode,
+    res_headers: &Headers,
+) -> bool {
+    if *method != Method::Get || is_no_store(req_headers) || !is_storable_status(status) {
+        return false;
+    }
+
+    if has_directive(res_headers, &CacheDirective::NoStore)
+        || has_directive(res_headers, &CacheDirective::Private)
+    {
+        return false;
+    }
+
+    // We can't select between variants that vary on everything.
+    if let Some(&header::Vary::Any) = res_headers.get::<header::Vary>() {
+        return false;
+    }
+
+    let is_public = has_directive(res_headers, &CacheDirective::Public);
+
+    // Shared caches must not reuse authenticated responses unless told otherwise.
+    // <https://tools.ietf.org/html/rfc7234#section-3.2>
+    if req_headers.get_raw("Authorization").is_some() && !is_public
+        && !directives(res_headers)
+            .iter()
+            .any(|d| matches!(*d, CacheDirective::SMaxAge(_) | CacheDirective::MustRevalidate))
+    {
+        return false;
+    }
+
+    // Don't hand one client's session cookie to everyone else.
+    if res_headers.has::<header::SetCookie>() && !is_public {
+        return false;
+    }
+
+    has_explicit_freshness(res_headers) || is_public || is_heuristically_cacheable(status)
+}
+
+fn has_explicit_freshness(headers: &Headers) -> bool {
+    headers.get_raw("Expires").is_some()
+        || directives(headers)
+            .iter()
+            .any(|d| matches!(*d, CacheDirective::MaxAge(_) | CacheDirective::SMaxAge(_)))
+}
+
+/// How long a response stays fresh after it was generated by the origin.
+///
+/// <https://tools.ietf.org/html/rfc7234#section-4.2.1>
+pub fn freshness_lifetime(headers: &Headers, response_time: SystemTime) -> Duration {
+    // s-maxage applies to shared caches and overrides max-age
+    let mut max_age = None;
+    for directive in directives(headers) {
+        match *directive {
+            CacheDirective::SMaxAge(n) => return Duration::from_secs(u64::from(n)),
+            CacheDirective::MaxAge(n) => max_age = Some(n),
+            _ => {}
+        }
+    }
+
+    if let Some(n) = max_age {
+        return Duration::from_secs(u64::from(n));
+    }
+
+    let date = headers
+        .get::<header::Date>()
+        .map(|&header::Date(date)| SystemTime::from(date))
+        .unwrap_or(response_time);
+
+    // An invalid Expires value (e.g. "0") means already expired.
+    if headers.get_raw("Expires").is_some() {
+        return match headers.get::<header::Expires>() {
+            Some(&header::Expires(expires)) => SystemTime::from(expires)
+                .duration_since(date)
+                .unwrap_or_else(|_| Duration::from_secs(0)),
+            None => Duration::from_secs(0),
+        };
+    }
+
+    // Heuristic freshness: 10% of the time since last modification, capped at a day.
+    // <https://tools.ietf.org/html/rfc7234#section-4.2.2>
+    match headers.get::<header::LastModified>() {
+        Some(&header::LastModified(modified)) => {
+            let since = date.duration_since(SystemTime::from(modified))
+                .unwrap_or_else(|_| Duration::from_secs(0));
+            cmp::min(since / 10, Duration::from_secs(86_400))
+        }
+        None => Duration::from_secs(0),
+    }
+}
+
+/// How old a stored response is according to the origin and to us.
+///
+/// <https://tools.ietf.org/html/rfc7234#section-4.2.3>
+pub fn current_age(
+    headers: &Headers,
+    request_time: SystemTime,
+    response_time: SystemTime,
+    now: SystemTime,
+) -> Duration {
+    let zero = Duration::from_secs(0);
+
+    let age_value = headers
+        .get::<Age>()
+        .map(|&Age(n)| Duration::from_secs(u64::from(n)))
+        .unwrap_or(zero);
+
+    let apparent_age = headers
+        .get::<header::Date>()
+        .and_then(|&header::Date(date)| response_time.duration_since(SystemTime::from(date)).ok())
+        .unwrap_or(zero);
+
+    let response_delay = response_time.duration_since(request_time).unwrap_or(zero);
+    let corrected_initial_age = cmp::max(apparent_age, age_value + response_delay);
+    let resident_time = now.duration_since(response_time).unwrap_or(zero);
+
+    corrected_initial_age + resident_time
+}
+
+/// Determines if a stored response may be served to this request without revalidation.
+///
+/// <https://tools.ietf.org/html/rfc7234#section-4.2>
+pub fn is_fresh(
+    req_headers: &Headers,
+    res_headers: &Headers,
+    age: Duration,
+    lifetime: Duration,
+) -> bool {
+    // Response must be revalidated on every use
+    if has_directive(res_headers, &CacheDirective::NoCache) {
+        return false;
+    }
+
+    // Client wants an end-to-end reload
+    if has_directive(req_headers, &CacheDirective::NoCache)
+        || req_headers.get::<header::Pragma>() == Some(&header::Pragma::NoCache)
+    {
+        return false;
+    }
+
+    let must_revalidate = has_directive(res_headers, &CacheDirective::MustRevalidate)
+        || has_directive(res_headers, &CacheDirective::ProxyRevalidate);
+
+    let mut lifetime = lifetime;
+
+    for directive in directives(req_headers) {
+        match *directive {
+            CacheDirective::MaxAge(n) if age > Duration::from_secs(u64::from(n)) => return false,
+            CacheDirective::MinFresh(n) => {
+                lifetime = lifetime
+                    .checked_sub(Duration::from_secs(u64::from(n)))
+                    .unwrap_or_else(|| Duration::from_secs(0));
+            }
+            CacheDirective::MaxStale(n) if !must_revalidate => {
+                lifetime += Duration::from_secs(u64::from(n));
+            }
+            _ => {}
+        }
+    }
+
+    age < lifetime
+}
+
+/// Determines if a stored response may ever be served while stale.
+///
+/// <https://tools.ietf.org/html/rfc5861#section-4>
+pub fn may_serve_stale(res_headers: &Headers) -> bool {
+    !directives(res_headers).iter().any(|d| {
+        matches!(
+            *d,
+            CacheDirective::NoCache | CacheDirective::MustRevalidate | CacheDirective::ProxyRevalidate
+        )
+    })
+}
+
+/// Determines if the client is fine with a stale response.
+///
+/// Clients that ask for a reload or bound the age of the response are not.
+pub fn request_allows_stale(req_headers: &Headers) -> bool {
+    if req_headers.get::<header::Pragma>() == Some(&header::Pragma::NoCache) {
+        return false;
+    }
+
+    !directives(req_headers)
+        .iter()
+        .any(|d| matches!(*d, CacheDirective::NoCache | CacheDirective::MaxAge(_)))
+}
+
+/// The window in which a stale response may be served while it is refreshed in the background.
+///
+/// <https://tools.ietf.org/html/rfc5861#section-3>
+pub fn stale_while_revalidate(res_headers: &Headers) -> Option<Duration> {
+    extension_secs(directives(res_headers), "stale-while-revalidate")
+}
+
+/// The window in which a stale response may be served when the upstream fails.
+///
+/// Either the request or the response may set it. The request's value wins.
+///
+/// <https://tools.ietf.org/html/rfc5861#section-4>
+pub fn stale_if_error(req_headers: &Headers, res_headers: &Headers) -> Option<Duration> {
+    extension_secs(directives(req_headers), "stale-if-error")
+        .or_else(|| extension_secs(directives(res_headers), "stale-if-error"))
+}
+
+fn extension_secs(directives: &[CacheDirective], name: &str) -> Option<Duration> {
+    directives.iter().filter_map(|d| match *d {
+        CacheDirective::Extension(ref k, Some(ref v)) if k.eq_ignore_ascii_case(name) => {
+            v.trim_matches('"').parse::<u64>().ok().map(Duration::from_secs)
+        }
+        _ => None,
+    }).next()
+}
+
+/// Determines if an upstream response counts as an error for stale-if-error.
+pub fn is_error_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::InternalServerError
+            | StatusCode::BadGateway
+            | StatusCode::ServiceUnavailable
+            | StatusCode::GatewayTimeout
+    )
+}
+
+#[test]
+fn test_freshness_lifetime() {
+    let now = SystemTime::now();
+    let lifetime = |cc: Vec<CacheDirective>| {
+        let mut headers = Headers::new();
+        headers.set(header::CacheControl(cc));
+        freshness_lifetime(&headers, now)
+    };
+    assert_eq!(lifetime(vec![]), Duration::from_secs(0));
+    assert_eq!(lifetime(vec![CacheDirective::MaxAge(60)]), Duration::from_secs(60));
+    assert_eq!(
+        lifetime(vec![CacheDirective::MaxAge(60), CacheDirective::SMaxAge(10)]),
+        Duration::from_secs(10)
+    );
+
+    // Invalid Expires is already expired
+    let mut headers = Headers::new();
+    headers.set_raw("Expires", "0");
+    assert_eq!(freshness_lifetime(&headers, now), Duration::from_secs(0));
+
+    let mut headers = Headers::new();
+    headers.set(header::Date(now.into()));
+    headers.set(header::Expires((now + Duration::from_secs(30)).into()));
+    assert_eq!(freshness_lifetime(&headers, now), Duration::from_secs(30));
+}
+
+#[test]
+fn test_is_fresh() {
+    let secs = Duration::from_secs;
+    let cc = |xs: Vec<CacheDirective>| {
+        let mut headers = Headers::new();
+        headers.set(header::CacheControl(xs));
+        headers
+    };
+    let none = Headers::new();
+
+    assert!(is_fresh(&none, &none, secs(5), secs(10)));
+    assert!(!is_fresh(&none, &none, secs(10), secs(10)));
+    assert!(!is_fresh(&none, &cc(vec![CacheDirective::NoCache]), secs(0), secs(10)));
+    assert!(!is_fresh(&cc(vec![CacheDirective::NoCache]), &none, secs(0), secs(10)));
+    assert!(!is_fresh(&cc(vec![CacheDirective::MaxAge(2)]), &none, secs(5), secs(10)));
+    assert!(!is_fresh(&cc(vec![CacheDirective::MinFresh(6)]), &none, secs(5), secs(10)));
+    assert!(is_fresh(&cc(vec![CacheDirective::MaxStale(10)]), &none, secs(15), secs(10)));
+    assert!(!is_fresh(
+        &cc(vec![CacheDirective::MaxStale(10)]),
+        &cc(vec![CacheDirective::MustRevalidate]),
+        secs(15),
+        secs(10)
+    ));
+}
+
+#[test]
+fn test_is_storable() {
+    let get = Method::Get;
+    let none = Headers::new();
+    let mut private = Headers::new();
+    private.set(header::CacheControl(vec![CacheDirective::Private]));
+    let mut vary_any = Headers::new();
+    vary_any.set(header::Vary::Any);
+
+    assert!(is_storable(&get, &none, StatusCode::Ok, &none));
+    assert!(!is_storable(&Method::Post, &none, StatusCode::Ok, &none));
+    assert!(!is_storable(&get, &none, StatusCode::InternalServerError, &none));
+    assert!(!is_storable(&get, &none, StatusCode::Ok, &private));
+    assert!(!is_storable(&get, &none, StatusCode::Ok, &vary_any));
+
+    // Partial and not modified responses only make sense to the client that asked.
+    let mut max_age = Headers::new();
+    max_age.set(header::CacheControl(vec![CacheDirective::MaxAge(60)]));
+    assert!(is_storable(&get, &none, StatusCode::Ok, &max_age));
+    assert!(!is_storable(&get, &none, StatusCode::PartialContent, &max_age));
+    assert!(!is_storable(&get, &none, StatusCode::NotModified, &max_age));
+}
+
+#[test]
+fn test_stale_extensions() {
+    use hyper::header::{Header, Raw};
+    let parse = |s: &[u8]| header::CacheControl::parse_header(&Raw::from(s)).unwrap();
+
+    let mut res_headers = Headers::new();
+    res_headers.set(parse(b"max-age=1, stale-while-revalidate=30, stale-if-error=600"));
+    let mut req_headers = Headers::new();
+
+    assert_eq!(stale_while_revalidate(&res_headers), Some(Duration::from_secs(30)));
+    assert_eq!(stale_if_error(&req_headers, &res_headers), Some(Duration::from_secs(600)));
+
+    req_headers.set(parse(b"stale-if-error=5"));
+    assert_eq!(stale_if_error(&req_headers, &res_headers), Some(Duration::from_secs(5)));
+
+    assert!(may_serve_stale(&res_headers));
+    res_headers.set(parse(b"max-age=1, must-revalidate, stale-if-error=600"));
+    assert!(!may_serve_stale(&res_headers));
+}
@@ -0,0 +1,536 @@
+//! Running CGI/1.1 scripts, and the parts that FastCGI shares with it: meta-variables and
+//! the script's response.
+//!
+//! <https://tools.ietf.org/html/rfc3875>
+
+use std::env;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self as std_mpsc, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use futures::{Future, Sink};
+use futures::sync::{mpsc, oneshot};
+use hyper::{self, header, Body, Chunk, Request, Response, StatusCode};
+use libc;
+
+use balance::ChosenTarget;
+use hop;
+use path;
+use response;
+use service::errors::FromUpstream;
+use split::ChosenVariant;
+
+/// A script's response head can't be larger than this.
+const MAX_HEAD_SIZE: usize = 65_536;
+
+/// How often to check whether a script that's done writing has exited.
+const WAIT_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The script a request resolved to.
+#[derive(Debug, PartialEq)]
+pub struct Script {
+    /// The url path of the script, e.g. `/blog/index.php`.
+    pub name: String,
+    /// Where the script is on disk.
+    pub filename: PathBuf,
+    /// Whatever the url path has after the script's, e.g. `/posts/42`.
+    pub path_info: String,
+}
+
+/// Finds the script in the cgi folder that a request path runs.
+///
+/// The first file along the path is the script and the rest of the path is its `PATH_INFO`.
+pub fn resolve(root: &Path, dir: &str, req_path: &str) -> Option<Script> {
+    let prefix = format!("/{}/", dir.trim_matches('/'));
+    if !req_path.starts_with(&prefix) {
+        return None;
+    }
+
+    let mut end = prefix.len();
+    loop {
+        let next = req_path[end..]
+            .find('/')
+            .map(|i| end + i)
+            .unwrap_or_else(|| req_path.len());
+        let filename = path::get_entity_path(root, &req_path[..next])?;
+
+        if filename.is_file() {
+            return Some(Script {
+                name: path::percent_decode_str(&req_path[..next]).ok()?,
+                filename,
+                path_info: path::percent_decode_str(&req_path[next..]).ok()?,
+            });
+        }
+        if next == req_path.len() || !filename.is_dir() {
+            return None;
+        }
+
+        end = next + 1;
+    }
+}
+
+pub fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .map(|meta| meta.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+/// Runs a script with the request body on its stdin and streams its stdout to the client.
+///
+/// The script is killed if it's still running after `timeout`. Its stderr is logged.
+pub fn run<I, B>(
+    script: &Script,
+    vars: &[(String, String)],
+    body: I,
+    timeout: Duration,
+    mut output: Output,
+) -> io::Result<()>
+where
+    I: Iterator<Item = io::Result<B>> + Send + 'static,
+    B: AsRef<[u8]>,
+{
+    let mut command = Command::new(&script.filename);
+    command
+        .env_clear()
+        .envs(vars.iter().map(|(name, value)| (name, value)))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if let Some(path) = env::var_os("PATH") {
+        command.env("PATH", path);
+    }
+    if let Some(dir) = script.filename.parent() {
+        command.current_dir(dir);
+    }
+    // Anything the script starts can be killed along with it
+    command.process_group(0);
+
+    let mut child = command.spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+
+    // Fed on its own thread since the script may write before it reads everything
+    thread::spawn(move || {
+        for chunk in body {
+            match chunk {
+                Ok(chunk) => if stdin.write_all(chunk.as_ref()).is_err() {
+                    break;
+                },
+                Err(_) => break,
+            }
+        }
+    });
+
+    let name = script.name.clone();
+    thread::spawn(move || {
+        for line in BufReader::new(stderr).lines() {
+            match line {
+                Ok(line) => warn!("[cgi] {}: {}", name, line),
+                Err(_) => break,
+            }
+        }
+    });
+
+    let child = Arc::new(Mutex::new(child));
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let (done, wait_done) = std_mpsc::channel::<()>();
+    {
+        let child = Arc::clone(&child);
+        let timed_out = Arc::clone(&timed_out);
+        thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = wait_done.recv_timeout(timeout) {
+                timed_out.store(true, Ordering::SeqCst);
+                kill(&child.lock().unwrap());
+            }
+        });
+    }
+
+    let mut buf = vec![0; 65_536];
+    let result = loop {
+        match stdout.read(&mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(n) => if let Err(e) = output.write(&buf[..n]) {
+                // No one is left to read the rest
+                kill(&child.lock().unwrap());
+                break Err(e);
+            },
+            Err(e) => break Err(e),
+        }
+    };
+
+    // Polled rather than waited on so the timeout can still take the lock and kill a
+    // script that closed its stdout but kept running
+    let status = loop {
+        if let Some(status) = child.lock().unwrap().try_wait()? {
+            break status;
+        }
+        thread::sleep(WAIT_INTERVAL);
+    };
+    drop(done);
+
+    if timed_out.load(Ordering::SeqCst) {
+        output.respond(response::gateway_timeout());
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "script timed out"));
+    }
+
+    result?;
+
+    if !status.success() {
+        warn!("[cgi] {} exited with {}", script.name, status);
+    }
+
+    Ok(())
+}
+
+/// Kills the script's whole process group.
+///
+/// A process the script started could still have its stdout open, so killing only the
+/// script wouldn't end the response.
+fn kill(child: &Child) {
+    unsafe {
+        libc::killpg(child.id() as libc::pid_t, libc::SIGKILL);
+    }
+}
+
+/// The meta-variables that describe a request to a script.
+pub fn meta_variables(
+    req: &Request,
+    remote_ip: IpAddr,
+    server_port: u16,
+    root: &Path,
+    script: &Script,
+) -> Vec<(String, String)> {
+    let mut vars = Vec::new();
+    {
+        let mut set = |name: &str, value: String| vars.push((name.to_string(), value));
+
+        set("GATEWAY_INTERFACE", "CGI/1.1".to_string());
+        set("SERVER_SOFTWARE", format!("prox/{}", env!("CARGO_PKG_VERSION")));
+        set("SERVER_PROTOCOL", req.version().to_string());
+        set("SERVER_PORT", server_port.to_string());
+        if let Some(host) = req.headers().get::<header::Host>() {
+            set("SERVER_NAME", host.hostname().to_string());
+        }
+        set("REQUEST_METHOD", req.method().to_string());
+        set(
+            "REQUEST_URI",
+            match req.query() {
+                None => req.path().to_string(),
+                Some(query) => format!("{}?{}", req.path(), query),
+            },
+        );
+        set("QUERY_STRING", req.query().unwrap_or("").to_string());
+        set("DOCUMENT_ROOT", root.to_string_lossy().into_owned());
+        set("SCRIPT_NAME", script.name.clone());
+        set("SCRIPT_FILENAME", script.filename.to_string_lossy().into_owned());
+        set("PATH_INFO", script.path_info.clone());
+        set("REMOTE_ADDR", remote_ip.to_string());
+
+        if let Some(header::ContentType(mime)) = req.headers().get() {
+            set("CONTENT_TYPE", mime.to_string());
+        }
+        if let Some(&header::ContentLength(len)) = req.headers().get() {
+            set("CONTENT_LENGTH", len.to_string());
+        }
+    }
+
+    for view in req.headers().iter() {
+        if view.is::<header::ContentType>() || view.is::<header::ContentLength>()
+            || view.is::<ChosenVariant>() || view.is::<ChosenTarget>()
+            || hop::is_hop_header(view.name())
+            // A client could point the script's HTTP_PROXY at a server of its choosing (httpoxy)
+            || view.name().eq_ignore_ascii_case("Proxy")
+        {
+            continue;
+        }
+        let name = format!("HTTP_{}", view.name().to_uppercase().replace('-', "_"));
+        vars.push((name, view.value_string()));
+    }
+
+    vars
+}
+
+/// Where a script's header section ends and its body starts.
+fn find_head_end(buf: &[u8]) -> Option<(usize, usize)> {
+    for i in 0..buf.len() {
+        if buf[i..].starts_with(b"\r\n\r\n") {
+            return Some((i, i + 4));
+        }
+        if buf[i..].starts_with(b"\n\n") {
+            return Some((i, i + 2));
+        }
+    }
+    None
+}
+
+/// Turns a script's header section into a response.
+///
+/// The status comes from the `Status` header. A bare `Location` is a redirect.
+pub fn parse_head(head: &[u8]) -> Result<Response, String> {
+    let mut res = Response::new();
+    let mut status = None;
+
+    for line in head.split(|&b| b == b'\n') {
+        let line = match line.last() {
+            Some(&b'\r') => &line[..line.len() - 1],
+            _ => line,
+        };
+        if line.is_empty() {
+            continue;
+        }
+
+        let colon = line.iter()
+            .position(|&b| b == b':')
+            .ok_or_else(|| format!("malformed header line: {:?}", String::from_utf8_lossy(line)))?;
+        let name = String::from_utf8_lossy(&line[..colon]).trim().to_string();
+        let value = trim(&line[colon + 1..]);
+
+        if name.eq_ignore_ascii_case("Status") {
+            let code = String::from_utf8_lossy(value)
+                .split_whitespace()
+                .next()
+                .and_then(|code| code.parse::<u16>().ok())
+                .and_then(|code| StatusCode::try_from(code).ok())
+                .ok_or_else(|| format!("invalid status: {:?}", String::from_utf8_lossy(value)))?;
+            status = Some(code);
+        } else if !hop::is_hop_header(&name) {
+            res.headers_mut().append_raw(name, value.to_vec());
+        }
+    }
+
+    let status = status.unwrap_or_else(|| {
+        if res.headers().has::<header::Location>() {
+            StatusCode::Found
+        } else {
+            StatusCode::Ok
+        }
+    });
+
+    Ok(res.with_status(status))
+}
+
+fn trim(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(bytes.len());
+    let end = bytes.iter().rposition(|b| !b.is_ascii_whitespace()).map(|i| i + 1).unwrap_or(start);
+    &bytes[start..end]
+}
+
+/// Streams a script's output to the client as it's written.
+///
+/// Meant to be written to from a pool thread since writes block until the client
+/// has room for more of the body. If it's dropped before the header section was
+/// complete, then the client gets a 502.
+///
+/// Script responses count as upstream ones for `intercept_errors`.
+pub struct Output {
+    head: Vec<u8>,
+    on_head: Option<oneshot::Sender<Response>>,
+    body: Option<mpsc::Sender<Result<Chunk, hyper::Error>>>,
+}
+
+impl Output {
+    pub fn new() -> (Self, oneshot::Receiver<Response>) {
+        let (tx, rx) = oneshot::channel();
+        let output = Output {
+            head: Vec::new(),
+            on_head: Some(tx),
+            body: None,
+        };
+        (output, rx)
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.on_head.is_none() {
+            return self.send(data);
+        }
+
+        self.head.extend_from_slice(data);
+
+        let (end, start) = match find_head_end(&self.head) {
+            Some(x) => x,
+            None if self.head.len() > MAX_HEAD_SIZE => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "response head too large"))
+            }
+            None => return Ok(()),
+        };
+
+        let mut res = parse_head(&self.head[..end])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        res.headers_mut().set(FromUpstream(true));
+        let rest = self.head.split_off(start);
+        self.head = Vec::new();
+
+        let (tx, body) = Body::pair();
+        self.body = Some(tx);
+        if let Some(on_head) = self.on_head.take() {
+            let _ = on_head.send(res.with_body(body));
+        }
+
+        if rest.is_empty() {
+            Ok(())
+        } else {
+            self.send(&rest)
+        }
+    }
+
+    /// Answers without running the script, e.g. when there's no such script.
+    pub fn respond(mut self, res: Response) {
+        if let Some(on_head) = self.on_head.take() {
+            let _ = on_head.send(res);
+        }
+    }
+
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        let sent = self.body
+            .take()
+            .map(|tx| tx.send(Ok(Chunk::from(data.to_vec()))).wait());
+        match sent {
+            Some(Ok(tx)) => {
+                self.body = Some(tx);
+                Ok(())
+            }
+            _ => Err(io::Error::new(io::ErrorKind::BrokenPipe, "client went away")),
+        }
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        if let Some(on_head) = self.on_head.take() {
+            let _ = on_head.send(response::bad_gateway());
+        }
+    }
+}
+
+#[test]
+fn test_parse_head() {
+    let res = parse_head(b"Status: 404 Not Found\r\nContent-Type: text/html\r\nX-Powered-By:  PHP \r\n").unwrap();
+    assert_eq!(res.status(), StatusCode::NotFound);
+    assert_eq!(
+        res.headers().get::<header::ContentType>(),
+        Some(&header::ContentType::html())
+    );
+    assert_eq!(
+        res.headers().get_raw("X-Powered-By").and_then(|raw| raw.one()),
+        Some(&b"PHP"[..])
+    );
+    assert!(res.headers().get_raw("Status").is_none());
+
+    let res = parse_head(b"Location: /login\n").unwrap();
+    assert_eq!(res.status(), StatusCode::Found);
+
+    assert_eq!(parse_head(b"").unwrap().status(), StatusCode::Ok);
+    assert!(parse_head(b"Status: nope").is_err());
+    assert!(parse_head(b"Content-Type text/html").is_err());
+
+    assert_eq!(find_head_end(b"A: b\r\n\r\nbody"), Some((4, 8)));
+    assert_eq!(find_head_end(b"A: b\n\nbody"), Some((4, 6)));
+    assert_eq!(find_head_end(b"A: b\r\n"), None);
+}
+
+#[test]
+fn test_run() {
+    use std::fs;
+    use std::iter;
+    use std::time::Instant;
+
+    use futures::Stream;
+    use futures_cpupool::CpuPool;
+
+    use util::TempDir;
+
+    fn script(path: &Path, source: &str) {
+        fs::write(path, source).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    let root = TempDir::new("cgi_run");
+    fs::create_dir_all(root.join("cgi/tools")).unwrap();
+    script(
+        &root.join("cgi/tools/echo.sh"),
+        "#!/bin/sh\necho 'Status: 201 Created'\necho 'Content-Type: text/plain'\necho\n\
+         echo \"$REQUEST_METHOD $PATH_INFO $QUERY_STRING\"\ncat\n",
+    );
+    script(&root.join("cgi/slow.sh"), "#!/bin/sh\nexec sleep 5\n");
+    script(
+        &root.join("cgi/detached.sh"),
+        "#!/bin/sh\necho 'Content-Type: text/plain'\necho\necho done\nexec >&-\nexec sleep 5\n",
+    );
+    script(
+        &root.join("cgi/forked.sh"),
+        "#!/bin/sh\necho 'Content-Type: text/plain'\necho\necho started\nsleep 5 &\n",
+    );
+    fs::write(root.join("cgi/data.txt"), "").unwrap();
+
+    // Resolving
+    let echo = resolve(&root, "cgi", "/cgi/tools/echo.sh/a/b").unwrap();
+    assert_eq!(echo.name, "/cgi/tools/echo.sh");
+    assert_eq!(echo.filename, root.join("cgi/tools/echo.sh"));
+    assert_eq!(echo.path_info, "/a/b");
+    assert!(is_executable(&echo.filename));
+    assert!(!is_executable(&resolve(&root, "cgi", "/cgi/data.txt").unwrap().filename));
+    assert_eq!(resolve(&root, "cgi", "/cgi/tools/"), None);
+    assert_eq!(resolve(&root, "cgi", "/cgi/missing.sh"), None);
+    assert_eq!(resolve(&root, "cgi", "/tools/echo.sh"), None);
+
+    // Running
+    let vars = vec![
+        ("REQUEST_METHOD".to_string(), "POST".to_string()),
+        ("PATH_INFO".to_string(), echo.path_info.clone()),
+        ("QUERY_STRING".to_string(), "x=1".to_string()),
+    ];
+    let (output, head) = Output::new();
+    let body = vec![Ok(b"hello".to_vec())].into_iter();
+    let runner = thread::spawn(move || run(&echo, &vars, body, Duration::from_secs(5), output));
+    let res = head.wait().unwrap();
+    assert_eq!(res.status(), StatusCode::Created);
+    let body = res.body().concat2().wait().unwrap();
+    assert_eq!(&body[..], &b"POST /a/b x=1\nhello"[..]);
+    runner.join().unwrap().unwrap();
+
+    // Timing out
+    let slow = resolve(&root, "cgi", "/cgi/slow.sh").unwrap();
+    let (output, head) = Output::new();
+    let body = iter::empty::<io::Result<Vec<u8>>>();
+    let runner = thread::spawn(move || run(&slow, &[], body, Duration::from_millis(100), output));
+    assert_eq!(head.wait().unwrap().status(), StatusCode::GatewayTimeout);
+    assert!(runner.join().unwrap().is_err());
+
+    // A script that closed its stdout but keeps running still times out, which frees
+    // its thread for the next script
+    let pool = CpuPool::new(1);
+    let run_on_pool = |script: Script, timeout| {
+        let (output, head) = Output::new();
+        let body = iter::empty::<io::Result<Vec<u8>>>();
+        let ran = pool.spawn_fn(move || Ok::<_, ()>(run(&script, &[], body, timeout, output)));
+        (head, ran)
+    };
+    let detached = resolve(&root, "cgi", "/cgi/detached.sh").unwrap();
+    let (head, ran) = run_on_pool(detached, Duration::from_millis(100));
+    let body = head.wait().unwrap().body().concat2().wait().unwrap();
+    assert_eq!(&body[..], &b"done\n"[..]);
+    let echo = resolve(&root, "cgi", "/cgi/tools/echo.sh").unwrap();
+    let (head, _ran_next) = run_on_pool(echo, Duration::from_secs(5));
+    assert_eq!(head.wait().unwrap().status(), StatusCode::Created);
+    assert!(ran.wait().unwrap().is_err());
+
+    // A process the script left behind with its stdout is killed along with it
+    let forked = resolve(&root, "cgi", "/cgi/forked.sh").unwrap();
+    let started = Instant::now();
+    let (output, head) = Output::new();
+    let body = iter::empty::<io::Result<Vec<u8>>>();
+    let runner = thread::spawn(move || run(&forked, &[], body, Duration::from_millis(100), output));
+    let body = head.wait().unwrap().body().concat2().wait().unwrap();
+    assert_eq!(&body[..], &b"started\n"[..]);
+    assert!(runner.join().unwrap().is_err());
+    assert!(started.elapsed() < Duration::from_secs(2));
+}
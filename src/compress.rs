@@ -0,0 +1,394 @@
+//! The content codings that responses can be compressed with.
+
+use std::io::{self, Write};
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use brotli;
+use flate2;
+use futures::{Async, Poll, Stream};
+use hyper::{self, header, Chunk};
+use serde;
+use zstd;
+
+use config;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coding {
+    Brotli,
+    Zstd,
+    Gzip,
+    Deflate,
+}
+
+impl Coding {
+    pub fn from_token(token: &str) -> Option<Coding> {
+        match token {
+            "br" => Some(Coding::Brotli),
+            "zstd" => Some(Coding::Zstd),
+            "gzip" => Some(Coding::Gzip),
+            "deflate" => Some(Coding::Deflate),
+            _ => None,
+        }
+    }
+
+    pub fn token(self) -> &'static str {
+        match self {
+            Coding::Brotli => "br",
+            Coding::Zstd => "zstd",
+            Coding::Gzip => "gzip",
+            Coding::Deflate => "deflate",
+        }
+    }
+
+    /// The file extension of a precompressed copy, e.g. `app.js.br`.
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            Coding::Brotli => Some("br"),
+            Coding::Zstd => Some("zst"),
+            Coding::Gzip => Some("gz"),
+            Coding::Deflate => None,
+        }
+    }
+
+    pub fn from_encoding(encoding: &header::Encoding) -> Option<Coding> {
+        match *encoding {
+            header::Encoding::Brotli => Some(Coding::Brotli),
+            header::Encoding::Gzip => Some(Coding::Gzip),
+            header::Encoding::Deflate => Some(Coding::Deflate),
+            header::Encoding::EncodingExt(ref token) => Coding::from_token(token),
+            _ => None,
+        }
+    }
+
+    /// The value for `Accept-Encoding` and `Content-Encoding`.
+    pub fn encoding(self) -> header::Encoding {
+        match self {
+            Coding::Brotli => header::Encoding::Brotli,
+            Coding::Zstd => header::Encoding::EncodingExt("zstd".to_string()),
+            Coding::Gzip => header::Encoding::Gzip,
+            Coding::Deflate => header::Encoding::Deflate,
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Coding {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let token = String::deserialize(deserializer)?;
+        Coding::from_token(&token).ok_or_else(|| {
+            D::Error::invalid_value(
+                serde::de::Unexpected::Str(&token),
+                &"one of \"br\", \"zstd\", \"gzip\" or \"deflate\"",
+            )
+        })
+    }
+}
+
+/// Collects what an encoder writes so that it can be sent along as chunks.
+#[derive(Clone, Default)]
+struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Output {
+    fn take(&self) -> Vec<u8> {
+        mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Brotli's state is several KiB, so it's boxed to keep the others small
+enum Codec {
+    Brotli(Box<brotli::CompressorWriter<Output>>),
+    Zstd(zstd::stream::write::Encoder<'static, Output>),
+    Gzip(flate2::write::GzEncoder<Output>),
+    Deflate(flate2::write::ZlibEncoder<Output>),
+    BrotliDecoder(Box<brotli::DecompressorWriter<Output>>),
+    ZstdDecoder(zstd::stream::write::Decoder<'static, Output>),
+    GzipDecoder(flate2::write::GzDecoder<Output>),
+    DeflateDecoder(flate2::write::ZlibDecoder<Output>),
+}
+
+impl Codec {
+    fn encoder(coding: Coding, opts: &config::Gzip, output: Output) -> io::Result<Codec> {
+        Ok(match coding {
+            Coding::Brotli => {
+                // 4 KiB buffer and a 4 MiB window like the brotli command line tool
+                Codec::Brotli(Box::new(brotli::CompressorWriter::new(
+                    output,
+                    4096,
+                    opts.br_level.min(11),
+                    22,
+                )))
+            }
+            Coding::Zstd => Codec::Zstd(zstd::stream::write::Encoder::new(output, opts.zstd_level)?),
+            Coding::Gzip => Codec::Gzip(flate2::write::GzEncoder::new(
+                output,
+                flate2::Compression::new(opts.level.min(9)),
+            )),
+            Coding::Deflate => Codec::Deflate(flate2::write::ZlibEncoder::new(
+                output,
+                flate2::Compression::new(opts.deflate_level.min(9)),
+            )),
+        })
+    }
+
+    fn decoder(coding: Coding, output: Output) -> io::Result<Codec> {
+        Ok(match coding {
+            Coding::Brotli => Codec::BrotliDecoder(Box::new(brotli::DecompressorWriter::new(output, 4096))),
+            Coding::Zstd => Codec::ZstdDecoder(zstd::stream::write::Decoder::new(output)?),
+            Coding::Gzip => Codec::GzipDecoder(flate2::write::GzDecoder::new(output)),
+            Coding::Deflate => Codec::DeflateDecoder(flate2::write::ZlibDecoder::new(output)),
+        })
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match *self {
+            Codec::Brotli(ref mut w) => w,
+            Codec::Zstd(ref mut w) => w,
+            Codec::Gzip(ref mut w) => w,
+            Codec::Deflate(ref mut w) => w,
+            Codec::BrotliDecoder(ref mut w) => w,
+            Codec::ZstdDecoder(ref mut w) => w,
+            Codec::GzipDecoder(ref mut w) => w,
+            Codec::DeflateDecoder(ref mut w) => w,
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Codec::Brotli(w) => {
+                w.into_inner();
+            }
+            Codec::Zstd(w) => {
+                w.finish()?;
+            }
+            Codec::Gzip(w) => {
+                w.finish()?;
+            }
+            Codec::Deflate(w) => {
+                w.finish()?;
+            }
+            // These write out the rest when they're dropped
+            Codec::BrotliDecoder(mut w) => w.flush()?,
+            Codec::ZstdDecoder(mut w) => w.flush()?,
+            Codec::GzipDecoder(w) => {
+                w.finish()?;
+            }
+            Codec::DeflateDecoder(w) => {
+                w.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Output is sent on once there's this much of it
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Compresses or decompresses a body as one stream.
+///
+/// The codec is only flushed when the upstream has nothing more for us yet, so the
+/// client never waits on data that the upstream already sent, while a body that arrives
+/// all at once compresses as well as it would in one piece.
+pub struct Transcode<S> {
+    stream: S,
+    codec: Option<Codec>,
+    output: Output,
+    // Written to the codec since it was last flushed
+    unflushed: bool,
+}
+
+impl<S> Transcode<S> {
+    pub fn encode(stream: S, coding: Coding, opts: &config::Gzip) -> io::Result<Self> {
+        let output = Output::default();
+        let codec = Codec::encoder(coding, opts, output.clone())?;
+        Ok(Transcode::new(stream, codec, output))
+    }
+
+    pub fn decode(stream: S, coding: Coding) -> io::Result<Self> {
+        let output = Output::default();
+        let codec = Codec::decoder(coding, output.clone())?;
+        Ok(Transcode::new(stream, codec, output))
+    }
+
+    fn new(stream: S, codec: Codec, output: Output) -> Self {
+        Transcode {
+            stream,
+            codec: Some(codec),
+            output,
+            unflushed: false,
+        }
+    }
+}
+
+impl<S> Stream for Transcode<S>
+where
+    S: Stream<Item = Chunk, Error = hyper::Error>,
+{
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        loop {
+            if self.codec.is_none() {
+                let buf = self.output.take();
+                if buf.is_empty() {
+                    return Ok(Async::Ready(None));
+                }
+                return Ok(Async::Ready(Some(buf.into())));
+            }
+
+            match self.stream.poll()? {
+                Async::Ready(Some(chunk)) => {
+                    if let Some(ref mut codec) = self.codec {
+                        codec.writer().write_all(&chunk)?;
+                        self.unflushed = true;
+                    }
+                    if self.output.0.lock().unwrap().len() >= CHUNK_SIZE {
+                        return Ok(Async::Ready(Some(self.output.take().into())));
+                    }
+                }
+                Async::Ready(None) => {
+                    if let Some(codec) = self.codec.take() {
+                        codec.finish()?;
+                    }
+                }
+                Async::NotReady => {
+                    if !self.unflushed {
+                        return Ok(Async::NotReady);
+                    }
+                    self.unflushed = false;
+                    if let Some(ref mut codec) = self.codec {
+                        codec.writer().flush()?;
+                    }
+                    let buf = self.output.take();
+                    if buf.is_empty() {
+                        return Ok(Async::NotReady);
+                    }
+                    return Ok(Async::Ready(Some(buf.into())));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_encode() {
+    use std::io::Read;
+
+    use futures::{stream, Future};
+
+    let opts = config::Gzip::default();
+    let text = "Hello, world! ".repeat(1000);
+    let chunks = [text[..5000].to_string(), text[5000..].to_string()];
+
+    let encode = |coding| {
+        let stream = stream::iter_ok::<_, hyper::Error>(chunks.iter().cloned().map(Chunk::from));
+        let out = Transcode::encode(stream, coding, &opts).unwrap().collect().wait().unwrap();
+        out.iter().flat_map(|chunk| chunk.to_vec()).collect::<Vec<u8>>()
+    };
+
+    let mut decoded = String::new();
+    brotli::Decompressor::new(&encode(Coding::Brotli)[..], 4096)
+        .read_to_string(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, text);
+
+    let decoded = zstd::stream::decode_all(&encode(Coding::Zstd)[..]).unwrap();
+    assert_eq!(decoded, text.as_bytes());
+
+    let mut decoded = String::new();
+    flate2::read::GzDecoder::new(&encode(Coding::Gzip)[..])
+        .read_to_string(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, text);
+
+    let mut decoded = String::new();
+    flate2::read::ZlibDecoder::new(&encode(Coding::Deflate)[..])
+        .read_to_string(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, text);
+}
+
+#[test]
+fn test_encode_flush() {
+    use std::io::Read;
+
+    use futures::executor::{self, Notify, NotifyHandle};
+    use futures::sync::mpsc;
+
+    struct Noop;
+    impl Notify for Noop {
+        fn notify(&self, _id: usize) {}
+    }
+    let noop = NotifyHandle::from(Arc::new(Noop));
+
+    let (tx, rx) = mpsc::unbounded();
+    let stream = rx.map_err(|()| -> hyper::Error { unreachable!() });
+    let mut encode = executor::spawn(Transcode::encode(stream, Coding::Gzip, &config::Gzip::default()).unwrap());
+
+    // Nothing to flush yet
+    match encode.poll_stream_notify(&noop, 0).unwrap() {
+        Async::NotReady => {}
+        _ => panic!("expected NotReady"),
+    }
+
+    // What the upstream sent so far is flushed while it's waiting on more
+    tx.unbounded_send(Chunk::from("hello ")).unwrap();
+    let first = match encode.poll_stream_notify(&noop, 0).unwrap() {
+        Async::Ready(Some(chunk)) => chunk.to_vec(),
+        _ => panic!("expected a chunk"),
+    };
+    let mut decoded = vec![0; 6];
+    flate2::read::GzDecoder::new(&first[..]).read_exact(&mut decoded).unwrap();
+    assert_eq!(decoded, b"hello ");
+
+    // One gzip member for the whole body
+    tx.unbounded_send(Chunk::from("world")).unwrap();
+    drop(tx);
+    let mut out = first;
+    loop {
+        match encode.poll_stream_notify(&noop, 0).unwrap() {
+            Async::Ready(Some(chunk)) => out.extend_from_slice(&chunk),
+            Async::Ready(None) => break,
+            Async::NotReady => panic!("expected the stream to end"),
+        }
+    }
+    let mut decoded = String::new();
+    flate2::read::GzDecoder::new(&out[..]).read_to_string(&mut decoded).unwrap();
+    assert_eq!(decoded, "hello world");
+}
+
+#[test]
+fn test_decode() {
+    use futures::{stream, Future};
+
+    let opts = config::Gzip::default();
+    let text = "Hello, world! ".repeat(1000);
+
+    for &coding in &[Coding::Brotli, Coding::Zstd, Coding::Gzip, Coding::Deflate] {
+        let stream = stream::iter_ok::<_, hyper::Error>(vec![Chunk::from(text.clone())]);
+        let encoded = Transcode::encode(stream, coding, &opts).unwrap().collect().wait().unwrap();
+        let decoded = Transcode::decode(stream::iter_ok(encoded), coding)
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        let decoded = decoded.iter().flat_map(|chunk| chunk.to_vec()).collect::<Vec<u8>>();
+        assert_eq!(decoded, text.as_bytes(), "{}", coding.token());
+    }
+}
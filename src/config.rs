@@ -1,8 +1,7 @@
-use std::collections::HashSet;
-use std::error::Error;
+use std::collections::{BTreeMap, HashSet};
 use std::iter::FromIterator;
-use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use hyper::{header, Method};
@@ -10,7 +9,11 @@ use serde;
 use unicase::Ascii;
 use url::{self, Url};
 
+use compress::Coding;
 use host::Host;
+use path::PathPattern;
+use regex::Regex;
+use rewrite;
 
 /// Configures the proxy server.
 #[derive(Deserialize, Debug, Clone, Default)]
@@ -30,6 +33,8 @@ pub struct Server {
     /// Bind the prox server to this address.
     pub bind: SocketAddr,
     pub timeouts: Timeouts,
+    /// The default maximum byte size of request bodies for every site.
+    pub max_body_size: Option<u64>,
 }
 
 #[derive(Debug, Clone)]
@@ -51,6 +56,7 @@ impl Default for Server {
         Server {
             bind: default_bind().parse().unwrap(),
             timeouts: Timeouts::default(),
+            max_body_size: None,
         }
     }
 }
@@ -70,8 +76,16 @@ pub struct Site {
     pub host: Vec<Host>,
 
     /// Proxy requests to this url. Example: `http://localhost:3001`.
+    ///
+    /// If several urls were given, then this is the first one.
     pub url: Option<Url>,
 
+    /// Every upstream target. Requests are balanced between them.
+    pub targets: Vec<Url>,
+
+    /// Keep sending a client to the same target.
+    pub sticky: Option<Sticky>,
+
     /// Configure static-file serving.
     pub serve: Option<Serve>,
 
@@ -83,6 +97,158 @@ pub struct Site {
 
     /// Configure CORS.
     pub cors: Option<Cors>,
+
+    /// Configure caching of proxied responses.
+    pub cache: Option<Cache>,
+
+    /// Configure find-and-replace on proxied response bodies.
+    pub sub_filter: Option<SubFilter>,
+
+    /// Configure how upstream urls and cookies in response headers are rewritten.
+    pub rewrite_headers: RewriteHeaders,
+
+    /// Serve these files as the bodies of error responses, by status code.
+    pub error_pages: BTreeMap<u16, PathBuf>,
+
+    /// Also replace the bodies of error responses from the upstream. Default: `false`.
+    pub intercept_errors: bool,
+
+    /// Send JSON error bodies to clients that prefer `application/json`. Default: `false`.
+    pub json_errors: bool,
+
+    /// The maximum byte size of request bodies. Overrides `server.max_body_size`.
+    pub max_body_size: Option<u64>,
+
+    /// Copy proxied requests to a secondary upstream.
+    pub mirror: Option<Mirror>,
+
+    /// Send some of the proxied traffic to canary upstreams instead of `url`.
+    pub split: Option<Split>,
+
+    /// Limit concurrent requests to each upstream url.
+    pub upstream: Option<Upstream>,
+
+    /// Send requests to a FastCGI responder, e.g. php-fpm, instead of `url`.
+    pub fastcgi: Option<FastCgi>,
+
+    /// Configure how streaming responses like Server-Sent Events are passed through.
+    pub streaming: Streaming,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Streaming {
+    /// End a streaming response once the upstream sent nothing for this long.
+    /// Default: 5 minutes.
+    #[serde(default = "default_streaming_idle_timeout")]
+    #[serde(deserialize_with = "deserialize_millis")]
+    pub idle_timeout: Duration,
+
+    /// Send an event stream a comment when it's been quiet for this long. `0` turns it off.
+    /// Default: 15 seconds.
+    #[serde(default = "default_streaming_sse_keepalive")]
+    #[serde(deserialize_with = "deserialize_millis")]
+    pub sse_keepalive: Duration,
+}
+
+impl Default for Streaming {
+    fn default() -> Self {
+        Streaming {
+            idle_timeout: default_streaming_idle_timeout(),
+            sse_keepalive: default_streaming_sse_keepalive(),
+        }
+    }
+}
+
+fn default_streaming_idle_timeout() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_streaming_sse_keepalive() -> Duration {
+    Duration::from_secs(15)
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct FastCgi {
+    /// Where the responder listens.
+    pub address: FastCgiAddress,
+
+    /// The script that handles requests for a directory. Default: `"index.php"`.
+    ///
+    /// Only files with the same extension are run by the responder. `serve` won't send them
+    /// to clients as static files.
+    #[serde(default = "default_fastcgi_index")]
+    pub index: String,
+
+    /// Extra params to send along with the request's, e.g. `{ APP_ENV = "production" }`.
+    #[serde(default)]
+    pub params: BTreeMap<String, String>,
+
+    /// The most requests that may be talking to the responder at once. Default: `16`.
+    #[serde(default = "default_fastcgi_max_connections")]
+    pub max_connections: usize,
+
+    /// Give up on a responder that reads or sends nothing for this long. Default: 30 seconds.
+    #[serde(default = "default_fastcgi_timeout")]
+    #[serde(deserialize_with = "deserialize_millis")]
+    pub timeout: Duration,
+}
+
+impl FastCgi {
+    /// The extension of the scripts that the responder runs, e.g. `"php"`.
+    pub fn extension(&self) -> Option<&str> {
+        Path::new(&self.index).extension().and_then(|x| x.to_str())
+    }
+}
+
+fn default_fastcgi_index() -> String {
+    "index.php".to_string()
+}
+
+fn default_fastcgi_max_connections() -> usize {
+    16
+}
+
+fn default_fastcgi_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FastCgiAddress {
+    /// e.g. `"127.0.0.1:9000"`
+    Tcp(String),
+    /// e.g. `"unix:/run/php-fpm.sock"`
+    Unix(PathBuf),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Upstream {
+    /// The most requests that may be in flight to each upstream url at once.
+    pub max_connections: usize,
+
+    /// The most requests that may wait for a connection to each upstream url.
+    /// Any more get a 503 response. Default: `100`.
+    #[serde(default = "default_upstream_max_pending")]
+    pub max_pending: usize,
+
+    /// How long a request waits for a connection before it gets a 503 response.
+    /// Default: 5 seconds.
+    #[serde(default = "default_upstream_queue_timeout")]
+    #[serde(deserialize_with = "deserialize_millis")]
+    pub queue_timeout: Duration,
+}
+
+fn default_upstream_max_pending() -> usize {
+    100
+}
+
+fn default_upstream_queue_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Sticky {
+    /// The name of the cookie that names the client's target.
+    pub cookie: String,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -102,22 +268,196 @@ pub struct Serve {
     /// Show and serve files that start with dot ("."). Default: `false`.
     #[serde(default)]
     pub dotfiles: bool,
+
+    /// Serve the first of these files that a folder has when it's requested.
+    /// Default: `["index.html", "index.htm"]`.
+    #[serde(default = "default_serve_index")]
+    pub index: Vec<String>,
+
+    /// Run executable files in a folder of the root as CGI scripts.
+    pub cgi: Option<Cgi>,
+
+    /// Cache-Control values for the files and folder listings whose path matches.
+    /// The first matching rule wins. Default: `[]`.
+    #[serde(default)]
+    pub cache_control: Vec<CacheControlRule>,
+
+    /// Serve `about.html` for `/about`, and redirect `/about.html` to `/about` and
+    /// `/docs/index.html` to `/docs/`. Default: `false`.
+    #[serde(default)]
+    pub clean_urls: bool,
+
+    /// Serve the first of these paths that exists before passing a request down the chain,
+    /// where `{path}` is the request path. e.g. `["{path}", "{path}.html", "/index.html"]`
+    /// for a single-page app. Default: `[]`.
+    #[serde(default)]
+    pub try_files: Vec<String>,
+
+    /// Request paths that skip `try_files`, where `*` matches anything. e.g. `["/api/*"]`.
+    /// Default: `[]`.
+    #[serde(default)]
+    pub try_files_exclude: Vec<String>,
+
+    /// Serve `app.js.br`, `app.js.gz` or `app.js.zst` for `app.js` when the client accepts
+    /// that coding, most preferred first. Default: `[]`.
+    #[serde(default)]
+    pub precompressed: Vec<Coding>,
 }
 
-#[derive(Deserialize, Debug, Clone, Default)]
+#[derive(Deserialize, Debug, Clone)]
+pub struct Cgi {
+    /// The folder of the root that has the scripts. Default: `"cgi"`.
+    #[serde(default = "default_cgi_dir")]
+    pub dir: String,
+
+    /// Kill a script that is still running after this long. Default: 30 seconds.
+    #[serde(default = "default_cgi_timeout")]
+    #[serde(deserialize_with = "deserialize_millis")]
+    pub timeout: Duration,
+
+    /// The most scripts of the site that may run at once. The rest wait their turn. Default: `4`.
+    #[serde(default = "default_cgi_max_processes")]
+    pub max_processes: usize,
+}
+
+/// Sends `value` as the Cache-Control of what's served from a matching path, e.g. `/js/app.js`
+/// or `/docs/index.html`, along with an `Expires` if it has a `max-age`.
+///
+/// An empty value sends no Cache-Control at all.
+#[derive(Debug, Clone)]
+pub struct CacheControlRule {
+    pub path: PathPattern,
+    pub value: String,
+}
+
+impl<'de> serde::Deserialize<'de> for CacheControlRule {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use hyper::header::{Header, Raw};
+        use serde::de::Error;
+
+        #[derive(Deserialize, Debug)]
+        struct CacheControlRule_ {
+            path: Option<String>,
+            regex: Option<String>,
+            value: String,
+        }
+
+        let CacheControlRule_ { path, regex, value } =
+            CacheControlRule_::deserialize(deserializer)?;
+
+        let path = match (path, regex) {
+            (Some(glob), None) => PathPattern::Glob(glob),
+            (None, Some(regex)) => {
+                PathPattern::Regex(Regex::new(&regex).map_err(|e| D::Error::custom(e.to_string()))?)
+            }
+            _ => {
+                return Err(D::Error::custom(
+                    "cache_control rule needs exactly one of `path` or `regex`",
+                ))
+            }
+        };
+
+        if !value.is_empty() {
+            header::CacheControl::parse_header(&Raw::from(value.as_str())).map_err(|_| {
+                D::Error::invalid_value(
+                    serde::de::Unexpected::Str(&value),
+                    &"a Cache-Control header value",
+                )
+            })?;
+        }
+
+        Ok(CacheControlRule { path, value })
+    }
+}
+
+fn default_serve_index() -> Vec<String> {
+    vec!["index.html".to_string(), "index.htm".to_string()]
+}
+
+fn default_cgi_dir() -> String {
+    "cgi".to_string()
+}
+
+fn default_cgi_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_cgi_max_processes() -> usize {
+    4
+}
+
+#[derive(Deserialize, Debug, Clone)]
 pub struct Gzip {
-    // Gzip: 0-9
-    // We default 1 because it has the maximum compression to cpu ratio.
-    // pub level: _,
-    /// The minimum file size that will be gzipped. Default: `1400`.
+    /// The minimum file size that will be compressed. Default: `1400`.
     #[serde(default = "default_gzip_threshold")]
     pub threshold: u64,
+
+    /// The content codings to offer, most preferred first. A client gets the one it gives
+    /// the highest q-value, and the most preferred one of those.
+    /// Default: `["br", "zstd", "gzip", "deflate"]`.
+    #[serde(default = "default_gzip_encodings")]
+    pub encodings: Vec<Coding>,
+
+    /// Gzip level from 0 to 9. Default: `1`.
+    ///
+    /// We default 1 because it has the maximum compression to cpu ratio.
+    #[serde(default = "default_gzip_level")]
+    pub level: u32,
+
+    /// Deflate level from 0 to 9. Default: `1`.
+    #[serde(default = "default_gzip_level")]
+    pub deflate_level: u32,
+
+    /// Brotli quality from 0 to 11. Default: `4`.
+    #[serde(default = "default_gzip_br_level")]
+    pub br_level: u32,
+
+    /// Zstandard level from 1 to 22. Default: `3`.
+    #[serde(default = "default_gzip_zstd_level")]
+    pub zstd_level: i32,
+
+    /// Decompress upstream responses for clients that don't accept their coding. Default: `false`.
+    #[serde(default)]
+    pub decompress: bool,
+}
+
+impl Default for Gzip {
+    fn default() -> Self {
+        Gzip {
+            threshold: default_gzip_threshold(),
+            encodings: default_gzip_encodings(),
+            level: default_gzip_level(),
+            deflate_level: default_gzip_level(),
+            br_level: default_gzip_br_level(),
+            zstd_level: default_gzip_zstd_level(),
+            decompress: false,
+        }
+    }
 }
 
 fn default_gzip_threshold() -> u64 {
     1400
 }
 
+fn default_gzip_encodings() -> Vec<Coding> {
+    vec![Coding::Brotli, Coding::Zstd, Coding::Gzip, Coding::Deflate]
+}
+
+fn default_gzip_level() -> u32 {
+    1
+}
+
+fn default_gzip_br_level() -> u32 {
+    4
+}
+
+fn default_gzip_zstd_level() -> i32 {
+    3
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Log {
     #[serde(default = "default_log_format")]
@@ -128,6 +468,362 @@ fn default_log_format() -> String {
     super::service::log::COMMON_LOG_FORMAT.to_string()
 }
 
+#[derive(Deserialize, Debug, Clone)]
+pub struct Cache {
+    /// Store cached bodies in this folder instead of in memory.
+    pub path: Option<PathBuf>,
+
+    /// The maximum total byte size of cached bodies. Default: `67108864` (64 MiB).
+    #[serde(default = "default_cache_max_size")]
+    pub max_size: u64,
+
+    /// The maximum byte size of a single cached body. Default: `1048576` (1 MiB).
+    #[serde(default = "default_cache_max_entry_size")]
+    pub max_entry_size: u64,
+
+    /// How long a request waits on an identical in-flight request before
+    /// going to the upstream itself. Default: 5 seconds.
+    #[serde(default = "default_cache_lock_timeout")]
+    #[serde(deserialize_with = "deserialize_millis")]
+    pub lock_timeout: Duration,
+
+    /// Serve a stale response for up to this many seconds while it's refreshed
+    /// in the background. Overrides the upstream's `stale-while-revalidate`.
+    pub stale_while_revalidate: Option<u64>,
+
+    /// Serve a stale response for up to this many seconds when the upstream fails.
+    /// Overrides the upstream's `stale-if-error`.
+    pub stale_if_error: Option<u64>,
+
+    /// Configure the purge and inspection API.
+    pub purge: Option<Purge>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Purge {
+    /// Client IPs that are allowed to purge. Default: `[]`.
+    #[serde(default)]
+    pub allow: Vec<IpAddr>,
+
+    /// Clients that send `Authorization: Bearer <token>` are allowed to purge.
+    pub token: Option<String>,
+
+    /// Where the admin endpoint is mounted on the site. Default: `"/.prox/cache"`.
+    #[serde(default = "default_purge_path")]
+    pub path: String,
+}
+
+fn default_purge_path() -> String {
+    "/.prox/cache".to_string()
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Cache {
+            path: None,
+            max_size: default_cache_max_size(),
+            max_entry_size: default_cache_max_entry_size(),
+            lock_timeout: default_cache_lock_timeout(),
+            stale_while_revalidate: None,
+            stale_if_error: None,
+            purge: None,
+        }
+    }
+}
+
+fn default_cache_max_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_cache_max_entry_size() -> u64 {
+    1024 * 1024
+}
+
+fn default_cache_lock_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+#[derive(Debug, Clone)]
+pub struct Mirror {
+    /// Send copies of requests to this url. Its responses are ignored.
+    pub url: Url,
+
+    /// The share of requests to copy, from 0 to 100. Default: `100`.
+    pub percent: f64,
+
+    /// Requests with larger bodies aren't copied. Default: `65536` (64 KiB).
+    pub max_body_size: u64,
+}
+
+impl<'de> serde::Deserialize<'de> for Mirror {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        #[derive(Deserialize, Debug)]
+        struct Mirror_ {
+            url: String,
+            #[serde(default = "default_mirror_percent")]
+            percent: f64,
+            #[serde(default = "default_mirror_max_body_size")]
+            max_body_size: u64,
+        }
+
+        fn default_mirror_percent() -> f64 {
+            100.0
+        }
+
+        fn default_mirror_max_body_size() -> u64 {
+            64 * 1024
+        }
+
+        let input = Mirror_::deserialize(deserializer)?;
+
+        let url = input.url.parse::<Url>().map_err(|e| {
+            D::Error::invalid_value(serde::de::Unexpected::Str(&input.url), &e.to_string().as_str())
+        })?;
+
+        if input.percent < 0.0 || input.percent > 100.0 {
+            return Err(D::Error::invalid_value(
+                serde::de::Unexpected::Float(input.percent),
+                &"a percent from 0 to 100",
+            ));
+        }
+
+        Ok(Mirror {
+            url,
+            percent: input.percent,
+            max_body_size: input.max_body_size,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Split {
+    /// Pin clients to their variant with a cookie of this name.
+    /// Without it, clients are assigned by a hash of their IP address.
+    pub cookie: Option<String>,
+
+    /// Tell clients which variant served them in a response header of this name.
+    pub header: Option<String>,
+
+    pub variants: Vec<Variant>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Variant {
+    /// Shows up in logs, the `header` and the `cookie`.
+    pub name: String,
+
+    pub url: Url,
+
+    /// The share of traffic this variant gets, from 0 to 100.
+    /// Whatever the variants don't get goes to the site's `url`.
+    pub percent: f64,
+
+    /// Requests with this header (and value, if given) always go to this variant.
+    pub match_header: Option<(String, Option<String>)>,
+
+    /// Requests with this cookie (and value, if given) always go to this variant.
+    pub match_cookie: Option<(String, Option<String>)>,
+}
+
+impl<'de> serde::Deserialize<'de> for Split {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        #[derive(Deserialize, Debug)]
+        struct Split_ {
+            cookie: Option<String>,
+            header: Option<String>,
+            #[serde(rename = "variant")]
+            #[serde(default)]
+            variants: Vec<Variant_>,
+        }
+
+        #[derive(Deserialize, Debug)]
+        struct Variant_ {
+            name: String,
+            url: String,
+            #[serde(default)]
+            percent: f64,
+            // "X-Canary" or "X-Canary: 1"
+            match_header: Option<String>,
+            // "canary" or "canary=1"
+            match_cookie: Option<String>,
+        }
+
+        fn split_pair(s: &str, sep: char) -> (String, Option<String>) {
+            let mut parts = s.splitn(2, sep);
+            let name = parts.next().unwrap_or("").trim().to_string();
+            let value = parts.next().map(|x| x.trim().to_string());
+            (name, value)
+        }
+
+        let input = Split_::deserialize(deserializer)?;
+
+        let mut variants = Vec::with_capacity(input.variants.len());
+        let mut total = 0.0;
+
+        for variant in input.variants {
+            // Names end up in cookies and cache keys
+            let is_valid_name = !variant.name.is_empty()
+                && variant.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                && variant.name != ::split::PRIMARY
+                && !variants.iter().any(|v: &Variant| v.name == variant.name);
+
+            if !is_valid_name {
+                return Err(D::Error::invalid_value(
+                    serde::de::Unexpected::Str(&variant.name),
+                    &"a unique variant name of letters, digits, \"-\" and \"_\" other than \"primary\"",
+                ));
+            }
+
+            let url = variant.url.parse::<Url>().map_err(|e| {
+                D::Error::invalid_value(serde::de::Unexpected::Str(&variant.url), &e.to_string().as_str())
+            })?;
+
+            if variant.percent < 0.0 {
+                return Err(D::Error::invalid_value(
+                    serde::de::Unexpected::Float(variant.percent),
+                    &"a percent from 0 to 100",
+                ));
+            }
+            total += variant.percent;
+
+            variants.push(Variant {
+                name: variant.name,
+                url,
+                percent: variant.percent,
+                match_header: variant.match_header.map(|s| split_pair(&s, ':')),
+                match_cookie: variant.match_cookie.map(|s| split_pair(&s, '=')),
+            });
+        }
+
+        if total > 100.0 {
+            return Err(D::Error::custom("split variant percents add up to more than 100"));
+        }
+
+        Ok(Split {
+            cookie: input.cookie,
+            header: input.header,
+            variants,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RewriteHeaders {
+    /// Point `Location`, `Content-Location` and `Refresh` urls on the upstream back at
+    /// the host and scheme the client used. Default: `true`.
+    #[serde(default = "default_rewrite_location")]
+    pub location: bool,
+
+    /// Replace these url prefixes in `Location`, `Content-Location` and `Refresh`.
+    /// The longest matching prefix wins.
+    #[serde(default)]
+    pub redirect: BTreeMap<String, String>,
+
+    /// Replace the `Domain` of upstream cookies. An empty replacement removes it.
+    #[serde(default)]
+    pub cookie_domain: BTreeMap<String, String>,
+
+    /// Replace `Path` prefixes of upstream cookies. The longest matching prefix wins.
+    #[serde(default)]
+    pub cookie_path: BTreeMap<String, String>,
+}
+
+fn default_rewrite_location() -> bool {
+    true
+}
+
+impl Default for RewriteHeaders {
+    fn default() -> Self {
+        RewriteHeaders {
+            location: default_rewrite_location(),
+            redirect: BTreeMap::new(),
+            cookie_domain: BTreeMap::new(),
+            cookie_path: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SubFilter {
+    /// Only rewrite responses with one of these content types. Default: `["text/html"]`.
+    pub types: Vec<String>,
+
+    /// Applied in order of where they match in the body.
+    pub rules: Vec<rewrite::Rule>,
+}
+
+impl<'de> serde::Deserialize<'de> for SubFilter {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        #[derive(Deserialize, Debug)]
+        struct SubFilter_ {
+            #[serde(default = "default_sub_filter_types")]
+            types: Vec<String>,
+            #[serde(rename = "rule")]
+            #[serde(default)]
+            rules: Vec<Rule_>,
+        }
+
+        #[derive(Deserialize, Debug)]
+        struct Rule_ {
+            from: Option<String>,
+            regex: Option<String>,
+            to: String,
+            #[serde(default = "default_sub_filter_max_len")]
+            max_len: usize,
+        }
+
+        fn default_sub_filter_types() -> Vec<String> {
+            vec!["text/html".to_string()]
+        }
+
+        fn default_sub_filter_max_len() -> usize {
+            1024
+        }
+
+        let input = SubFilter_::deserialize(deserializer)?;
+
+        let rules = input
+            .rules
+            .into_iter()
+            .map(|rule| match (rule.from, rule.regex) {
+                (Some(from), None) => rewrite::Rule::literal(&from, &rule.to)
+                    .map_err(|e| D::Error::custom(e.to_string())),
+                (None, Some(regex)) => rewrite::Rule::regex(&regex, &rule.to, rule.max_len)
+                    .map_err(|e| D::Error::custom(e.to_string())),
+                _ => Err(D::Error::custom(
+                    "sub_filter rule needs exactly one of `from` or `regex`",
+                )),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let types = input.types.into_iter().map(|t| t.to_lowercase()).collect();
+
+        Ok(SubFilter { types, rules })
+    }
+}
+
+fn deserialize_millis<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    <u64 as serde::Deserialize>::deserialize(deserializer).map(Duration::from_millis)
+}
+
 impl<'de> serde::Deserialize<'de> for Site {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -142,35 +838,97 @@ impl<'de> serde::Deserialize<'de> for Site {
             Arr(Vec<Host>),
         }
 
+        #[derive(Deserialize, Debug, Clone)]
+        #[serde(untagged)]
+        enum Urls_ {
+            Str(String),
+            Arr(Vec<String>),
+        }
+
         #[derive(Deserialize, Debug, Clone)]
         struct Site_ {
             host: Hosts_,
-            url: Option<String>,
+            url: Option<Urls_>,
+            sticky: Option<Sticky>,
             serve: Option<Serve>,
             gzip: Option<Gzip>,
             log: Option<Log>,
             cors: Option<Cors>,
+            cache: Option<Cache>,
+            sub_filter: Option<SubFilter>,
+            #[serde(default)]
+            rewrite_headers: RewriteHeaders,
+            #[serde(default)]
+            error_pages: BTreeMap<String, PathBuf>,
+            #[serde(default)]
+            intercept_errors: bool,
+            #[serde(default)]
+            json_errors: bool,
+            max_body_size: Option<u64>,
+            mirror: Option<Mirror>,
+            split: Option<Split>,
+            upstream: Option<Upstream>,
+            fastcgi: Option<FastCgi>,
+            #[serde(default)]
+            streaming: Streaming,
         }
 
         let mut input: Site_ = Site_::deserialize(deserializer)?;
 
         // FIXME: This file is a mess.
-        let url = match input.clone().url.map(|url| url.parse::<Url>()) {
-            None => None,
-            Some(Ok(ref x)) => Some(x.clone()),
-            Some(Err(e)) => {
-                return Err(D::Error::invalid_value(
-                    serde::de::Unexpected::Str(&input.clone().url.unwrap()),
-                    &e.description(),
-                ))
-            }
+        let urls = match input.url.clone() {
+            None => Vec::new(),
+            Some(Urls_::Str(x)) => vec![x],
+            Some(Urls_::Arr(xs)) => xs,
         };
 
+        let targets = urls.iter()
+            .map(|url| {
+                url.parse::<Url>().map_err(|e| {
+                    D::Error::invalid_value(serde::de::Unexpected::Str(url), &e.to_string().as_str())
+                })
+            })
+            .collect::<Result<Vec<Url>, _>>()?;
+
+        let url = targets.first().cloned();
+
+        if input.fastcgi.is_some() {
+            if url.is_some() {
+                return Err(D::Error::custom("site can't have both `url` and `fastcgi`"));
+            }
+            if input.serve.is_none() {
+                return Err(D::Error::custom("site with `fastcgi` needs a `serve.root` for its scripts"));
+            }
+        }
+
+        if let Some(ref serve) = input.serve {
+            if let Some(coding) = serve.precompressed.iter().find(|x| x.extension().is_none()) {
+                return Err(D::Error::custom(format!(
+                    "`serve.precompressed` doesn't support \"{}\"",
+                    coding.token()
+                )));
+            }
+        }
+
         let host = match input.host {
             Hosts_::Str(x) => vec![x],
             Hosts_::Arr(xs) => xs,
         };
 
+        // TOML keys are always strings
+        let error_pages = input
+            .error_pages
+            .clone()
+            .into_iter()
+            .map(|(status, path)| match status.parse::<u16>() {
+                Ok(code) if (400..600).contains(&code) => Ok((code, path)),
+                _ => Err(D::Error::invalid_value(
+                    serde::de::Unexpected::Str(&status),
+                    &"an error status code from 400 to 599",
+                )),
+            })
+            .collect::<Result<BTreeMap<_, _>, _>>()?;
+
         // Canonicalize the root just so it's more helpful to see in the boot message.
         // We don't care if canonicalize fails because we check the folder every request so
         // even if the folder doesn't exist now, it can exist in the future.
@@ -183,14 +941,50 @@ impl<'de> serde::Deserialize<'de> for Site {
         Ok(Site {
             host,
             url,
+            targets,
+            sticky: input.sticky,
             serve: input.serve,
             gzip: input.gzip,
             log: input.log,
             cors: input.cors,
+            cache: input.cache,
+            sub_filter: input.sub_filter,
+            rewrite_headers: input.rewrite_headers,
+            error_pages,
+            intercept_errors: input.intercept_errors,
+            json_errors: input.json_errors,
+            max_body_size: input.max_body_size,
+            mirror: input.mirror,
+            split: input.split,
+            upstream: input.upstream,
+            fastcgi: input.fastcgi,
+            streaming: input.streaming,
         })
     }
 }
 
+impl<'de> serde::Deserialize<'de> for FastCgiAddress {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let s = String::deserialize(deserializer)?;
+
+        if let Some(path) = s.strip_prefix("unix:") {
+            Ok(FastCgiAddress::Unix(PathBuf::from(path)))
+        } else if s.contains(':') {
+            Ok(FastCgiAddress::Tcp(s))
+        } else {
+            Err(D::Error::invalid_value(
+                serde::de::Unexpected::Str(&s),
+                &"\"host:port\" or \"unix:/path/to.sock\"",
+            ))
+        }
+    }
+}
+
 impl<'de> serde::Deserialize<'de> for Host {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -203,7 +997,7 @@ impl<'de> serde::Deserialize<'de> for Host {
         match input.parse::<Host>() {
             Err(e) => Err(D::Error::invalid_value(
                 serde::de::Unexpected::Str(&input),
-                &e.description(),
+                &e.to_string().as_str(),
             )),
             Ok(host) => Ok(host),
         }
@@ -222,6 +1016,7 @@ impl<'de> serde::Deserialize<'de> for Server {
             #[serde(default = "default_bind")]
             bind: String,
             timeouts: Option<Timeouts>,
+            max_body_size: Option<u64>,
         }
 
         let input = Http_::deserialize(deserializer)?;
@@ -233,7 +1028,7 @@ impl<'de> serde::Deserialize<'de> for Server {
             Err(e) => {
                 return Err(D::Error::invalid_value(
                     serde::de::Unexpected::Str(&input.bind),
-                    &e.description(),
+                    &e.to_string().as_str(),
                 ))
             }
             Ok(bind) => bind,
@@ -242,6 +1037,7 @@ impl<'de> serde::Deserialize<'de> for Server {
         Ok(Server {
             bind,
             timeouts: input.timeouts.unwrap_or_else(Timeouts::default),
+            max_body_size: input.max_body_size,
         })
     }
 }
@@ -302,7 +1098,7 @@ impl<'de> serde::Deserialize<'de> for CorsOrigin {
             )),
             Ok(CorsOrigin_::Arr(xs)) => {
                 let origins = xs.into_iter()
-                    .map(|x| url_to_origin(&x).map_err(|e| D::Error::custom(e.description())))
+                    .map(|x| url_to_origin(&x).map_err(|e| D::Error::custom(e.to_string())))
                     .collect::<Result<Vec<header::Origin>, _>>()?;
                 Ok(CorsOrigin::Few(origins))
             }
@@ -347,7 +1143,7 @@ impl<'de> serde::Deserialize<'de> for Cors {
             .into_iter()
             .map(|s| {
                 s.parse::<Method>()
-                    .map_err(|e| D::Error::custom(e.description()))
+                    .map_err(|e| D::Error::custom(e.to_string()))
             })
             .collect::<Result<Vec<Method>, _>>()?;
 
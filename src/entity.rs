@@ -97,8 +97,7 @@ impl Entity {
                 let chunk_size = cmp::min(max_chunk_size, remaining.end - remaining.start) as usize;
 
                 // Read chunk from file
-                let mut chunk = Vec::with_capacity(chunk_size);
-                unsafe { chunk.set_len(chunk_size) };
+                let mut chunk = vec![0; chunk_size];
                 let bytes_read = match inner.file.read_at(&mut chunk, remaining.start) {
                     Err(e) => return Some(err(hyper::Error::from(e))),
                     Ok(n) => n,
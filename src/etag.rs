@@ -14,7 +14,7 @@ pub fn encode(mut n: u64) -> String {
         n /= BASE
     }
     buf[i] = ALPHABET[n as usize];
-    buf[(MAX_CAP - cap)..].into_iter().collect()
+    buf[(MAX_CAP - cap)..].iter().collect()
 }
 
 // PRIVATE
@@ -54,6 +54,6 @@ fn test_constants() {
     let base = ALPHABET.len() as u64;
     assert_eq!(base, BASE);
 
-    let max_cap = (::std::u64::MAX as f64).log(base as f64).ceil() as usize;
+    let max_cap = (u64::MAX as f64).log(base as f64).ceil() as usize;
     assert_eq!(max_cap, MAX_CAP);
 }
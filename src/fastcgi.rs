@@ -0,0 +1,373 @@
+//! A FastCGI client for talking to responders like php-fpm.
+//!
+//! Each request gets its own connection and the responder closes it when it's done.
+//!
+//! <https://fast-cgi.github.io/spec>
+
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use cgi::Script;
+use config::{FastCgi, FastCgiAddress};
+use path;
+
+const VERSION: u8 = 1;
+
+// Record types
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+
+const RESPONDER: u16 = 1;
+const REQUEST_COMPLETE: u8 = 0;
+
+// Only one request is sent per connection
+const REQUEST_ID: u16 = 1;
+
+const MAX_CONTENT_LEN: usize = 65_535;
+
+pub enum Conn {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Read for Conn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Conn::Tcp(ref mut stream) => stream.read(buf),
+            Conn::Unix(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Conn::Tcp(ref mut stream) => stream.write(buf),
+            Conn::Unix(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Conn::Tcp(ref mut stream) => stream.flush(),
+            Conn::Unix(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+/// Connects to the responder within `connect_timeout`.
+///
+/// Reads and writes on the connection fail once the responder has been quiet for
+/// `io_timeout`, so a hung responder can't hold a thread forever.
+pub fn connect(address: &FastCgiAddress, connect_timeout: Duration, io_timeout: Duration) -> io::Result<Conn> {
+    match *address {
+        FastCgiAddress::Unix(ref path) => {
+            // There's no connect_timeout for Unix sockets, and connecting blocks while the
+            // responder's backlog is full.
+            let (tx, rx) = mpsc::channel();
+            let path = path.clone();
+            thread::spawn(move || {
+                let _ = tx.send(UnixStream::connect(path));
+            });
+            let stream = match rx.recv_timeout(connect_timeout) {
+                Ok(stream) => stream?,
+                Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out connecting")),
+            };
+            stream.set_read_timeout(Some(io_timeout))?;
+            stream.set_write_timeout(Some(io_timeout))?;
+            Ok(Conn::Unix(stream))
+        }
+        FastCgiAddress::Tcp(ref address) => {
+            let mut last_error = None;
+            for addr in address.to_socket_addrs()? {
+                match TcpStream::connect_timeout(&addr, connect_timeout) {
+                    Ok(stream) => {
+                        stream.set_read_timeout(Some(io_timeout))?;
+                        stream.set_write_timeout(Some(io_timeout))?;
+                        return Ok(Conn::Tcp(stream));
+                    }
+                    Err(e) => last_error = Some(e),
+                }
+            }
+            Err(last_error.unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "address resolved to nothing")
+            }))
+        }
+    }
+}
+
+/// Determines if an error means the responder took too long, rather than that it failed.
+///
+/// Socket timeouts surface as `WouldBlock` on some platforms.
+pub fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock)
+}
+
+/// Finds the script that a request path runs.
+///
+/// A directory runs its `index`. Otherwise the first path segment with the index's extension
+/// is the script and the rest of the path is passed along as `PATH_INFO`.
+pub fn resolve(root: &Path, req_path: &str, opts: &FastCgi) -> Option<Script> {
+    let (name, path_info) = if req_path.ends_with('/') {
+        (format!("{}{}", req_path, opts.index), "")
+    } else {
+        let suffix = format!(".{}", opts.extension()?);
+        let end = req_path
+            .match_indices(suffix.as_str())
+            .map(|(i, _)| i + suffix.len())
+            .find(|&end| end == req_path.len() || req_path[end..].starts_with('/'))?;
+        (req_path[..end].to_string(), &req_path[end..])
+    };
+
+    let filename = path::get_entity_path(root, &name)?;
+    if !filename.is_file() {
+        return None;
+    }
+
+    Some(Script {
+        name: path::percent_decode_str(&name).ok()?,
+        filename,
+        path_info: path::percent_decode_str(path_info).ok()?,
+    })
+}
+
+/// Sends a request to the responder and hands its stdout to `on_stdout` as it arrives.
+pub fn run<S, I, B, F>(conn: &mut S, params: &[(String, String)], body: I, mut on_stdout: F) -> io::Result<()>
+where
+    S: Read + Write,
+    I: IntoIterator<Item = io::Result<B>>,
+    B: AsRef<[u8]>,
+    F: FnMut(&[u8]) -> io::Result<()>,
+{
+    let role = [(RESPONDER >> 8) as u8, RESPONDER as u8];
+    write_record(conn, BEGIN_REQUEST, &[role[0], role[1], 0, 0, 0, 0, 0, 0])?;
+
+    write_record(conn, PARAMS, &encode_params(params))?;
+    write_record(conn, PARAMS, &[])?;
+
+    for chunk in body {
+        let chunk = chunk?;
+        if !chunk.as_ref().is_empty() {
+            write_record(conn, STDIN, chunk.as_ref())?;
+        }
+    }
+    write_record(conn, STDIN, &[])?;
+    conn.flush()?;
+
+    loop {
+        let (kind, content) = read_record(conn)?;
+        match kind {
+            STDOUT => on_stdout(&content)?,
+            STDERR if !content.is_empty() => {
+                warn!("[fastcgi] {}", String::from_utf8_lossy(&content).trim())
+            }
+            END_REQUEST => {
+                return match content.get(4) {
+                    Some(&REQUEST_COMPLETE) => Ok(()),
+                    status => Err(io::Error::other(
+                        format!("responder rejected the request (protocol status {:?})", status),
+                    )),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Writes the content as one or more records. Empty content ends a stream.
+fn write_record<W: Write>(w: &mut W, kind: u8, content: &[u8]) -> io::Result<()> {
+    let mut chunks = content.chunks(MAX_CONTENT_LEN).peekable();
+    if chunks.peek().is_none() {
+        return w.write_all(&header(kind, 0));
+    }
+    for chunk in chunks {
+        let mut record = header(kind, chunk.len());
+        record.extend_from_slice(chunk);
+        w.write_all(&record)?;
+    }
+    Ok(())
+}
+
+fn header(kind: u8, len: usize) -> Vec<u8> {
+    let id = [(REQUEST_ID >> 8) as u8, REQUEST_ID as u8];
+    vec![VERSION, kind, id[0], id[1], (len >> 8) as u8, len as u8, 0, 0]
+}
+
+fn read_record<R: Read>(r: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0; 8];
+    r.read_exact(&mut header)?;
+    let len = (header[4] as usize) << 8 | header[5] as usize;
+    let padding = header[6] as usize;
+    let mut content = vec![0; len + padding];
+    r.read_exact(&mut content)?;
+    content.truncate(len);
+    Ok((header[1], content))
+}
+
+fn encode_params(params: &[(String, String)]) -> Vec<u8> {
+    fn encode_len(buf: &mut Vec<u8>, len: usize) {
+        if len < 128 {
+            buf.push(len as u8);
+        } else {
+            buf.extend_from_slice(&[(len >> 24) as u8 | 0x80, (len >> 16) as u8, (len >> 8) as u8, len as u8]);
+        }
+    }
+
+    let mut buf = Vec::new();
+    for (name, value) in params {
+        encode_len(&mut buf, name.len());
+        encode_len(&mut buf, value.len());
+        buf.extend_from_slice(name.as_bytes());
+        buf.extend_from_slice(value.as_bytes());
+    }
+    buf
+}
+
+#[test]
+fn test_run() {
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn decode_params(mut buf: &[u8]) -> HashMap<String, String> {
+        fn decode_len(buf: &mut &[u8]) -> usize {
+            if buf[0] < 128 {
+                let len = buf[0] as usize;
+                *buf = &buf[1..];
+                len
+            } else {
+                let len = ((buf[0] & 0x7f) as usize) << 24 | (buf[1] as usize) << 16
+                    | (buf[2] as usize) << 8 | buf[3] as usize;
+                *buf = &buf[4..];
+                len
+            }
+        }
+
+        let mut params = HashMap::new();
+        while !buf.is_empty() {
+            let name_len = decode_len(&mut buf);
+            let value_len = decode_len(&mut buf);
+            let name = String::from_utf8(buf[..name_len].to_vec()).unwrap();
+            let value = String::from_utf8(buf[name_len..name_len + value_len].to_vec()).unwrap();
+            params.insert(name, value);
+            buf = &buf[name_len + value_len..];
+        }
+        params
+    }
+
+    // A responder that echoes the script filename and the request body
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let responder = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut params = Vec::new();
+        let mut stdin = Vec::new();
+        loop {
+            match read_record(&mut stream).unwrap() {
+                (BEGIN_REQUEST, content) => assert_eq!(&content[..2], &[0, 1]),
+                (PARAMS, content) => params.extend(content),
+                (STDIN, ref content) if content.is_empty() => break,
+                (STDIN, content) => stdin.extend(content),
+                (kind, _) => panic!("unexpected record type {}", kind),
+            }
+        }
+        let params = decode_params(&params);
+        write_record(&mut stream, STDOUT, b"Status: 201 Created\r\nContent-Type: text/plain\r\n\r\n").unwrap();
+        write_record(&mut stream, STDERR, b"PHP Notice: hi\n").unwrap();
+        let body = format!("{} {}", params["SCRIPT_FILENAME"], String::from_utf8(stdin).unwrap());
+        write_record(&mut stream, STDOUT, body.as_bytes()).unwrap();
+        write_record(&mut stream, STDOUT, &[]).unwrap();
+        write_record(&mut stream, END_REQUEST, &[0, 0, 0, 0, REQUEST_COMPLETE, 0, 0, 0]).unwrap();
+    });
+
+    let mut conn = connect(&FastCgiAddress::Tcp(address), Duration::from_secs(1), Duration::from_secs(5)).unwrap();
+    let params = vec![
+        ("SCRIPT_FILENAME".to_string(), "/srv/index.php".to_string()),
+        ("LONG".to_string(), "x".repeat(300)),
+    ];
+    let body = vec![Ok(b"hello ".to_vec()), Ok(Vec::new()), Ok(b"world".to_vec())];
+    let mut stdout = Vec::new();
+    run(&mut conn, &params, body, |data| {
+        stdout.extend_from_slice(data);
+        Ok(())
+    }).unwrap();
+    responder.join().unwrap();
+
+    assert_eq!(
+        String::from_utf8(stdout).unwrap(),
+        "Status: 201 Created\r\nContent-Type: text/plain\r\n\r\n/srv/index.php hello world"
+    );
+}
+
+#[test]
+fn test_run_timeout() {
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixListener;
+
+    use util::TempDir;
+
+    let params = vec![("SCRIPT_FILENAME".to_string(), "/srv/index.php".to_string())];
+    let timeout = Duration::from_millis(100);
+
+    // Responders that take the request and then never answer
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = FastCgiAddress::Tcp(listener.local_addr().unwrap().to_string());
+    let mut conn = connect(&address, timeout, timeout).unwrap();
+    let e = run(&mut conn, &params, Vec::<io::Result<Vec<u8>>>::new(), |_| Ok(())).unwrap_err();
+    assert!(is_timeout(&e), "{:?}", e);
+
+    let dir = TempDir::new("fastcgi_timeout");
+    let path = dir.join("fpm.sock");
+    let _listener = UnixListener::bind(&path).unwrap();
+    let mut conn = connect(&FastCgiAddress::Unix(path), timeout, timeout).unwrap();
+    let e = run(&mut conn, &params, Vec::<io::Result<Vec<u8>>>::new(), |_| Ok(())).unwrap_err();
+    assert!(is_timeout(&e), "{:?}", e);
+
+    // Refused connections are failures, not timeouts
+    drop(listener);
+    let e = connect(&address, timeout, timeout).err().unwrap();
+    assert!(!is_timeout(&e), "{:?}", e);
+}
+
+#[test]
+fn test_resolve() {
+    use std::fs;
+
+    use util::TempDir;
+
+    let root = TempDir::new("fastcgi_resolve");
+    fs::create_dir_all(root.join("blog")).unwrap();
+    fs::write(root.join("index.php"), "").unwrap();
+    fs::write(root.join("blog/post.php"), "").unwrap();
+
+    let opts = FastCgi {
+        address: FastCgiAddress::Tcp("127.0.0.1:9000".to_string()),
+        index: "index.php".to_string(),
+        params: Default::default(),
+        max_connections: 1,
+        timeout: Duration::from_secs(1),
+    };
+
+    let script = resolve(&root, "/", &opts).unwrap();
+    assert_eq!(script.name, "/index.php");
+    assert_eq!(script.filename, root.join("index.php"));
+    assert_eq!(script.path_info, "");
+
+    let script = resolve(&root, "/blog/post.php/2018/hello%20world", &opts).unwrap();
+    assert_eq!(script.name, "/blog/post.php");
+    assert_eq!(script.path_info, "/2018/hello world");
+
+    assert_eq!(resolve(&root, "/blog/", &opts), None);
+    assert_eq!(resolve(&root, "/blog/post.phpx", &opts), None);
+    assert_eq!(resolve(&root, "/style.css", &opts), None);
+    assert_eq!(resolve(&root, "/../index.php", &opts), None);
+}
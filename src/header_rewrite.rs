@@ -0,0 +1,221 @@
+//! Rewriting upstream urls and cookie attributes in proxied response headers.
+//!
+//! An upstream only knows its own address, so its redirects point at e.g.
+//! `http://127.0.0.1:4001/login`. These get mapped back to the host and scheme
+//! the client actually used.
+
+use std::collections::BTreeMap;
+
+use hyper::header::{self, Headers};
+use url::Url;
+
+use config::RewriteHeaders;
+
+/// Rewrites `Location`, `Content-Location`, `Refresh` and `Set-Cookie`.
+///
+/// `public` is the origin the client sent the request to, if known.
+pub fn rewrite(opts: &RewriteHeaders, upstream: &Url, public: Option<&Url>, headers: &mut Headers) {
+    let location = headers
+        .get::<header::Location>()
+        .and_then(|value| rewrite_url(opts, upstream, public, value));
+    if let Some(value) = location {
+        headers.set(header::Location::new(value));
+    }
+
+    let content_location = headers
+        .get::<header::ContentLocation>()
+        .and_then(|value| rewrite_url(opts, upstream, public, value));
+    if let Some(value) = content_location {
+        headers.set(header::ContentLocation(value));
+    }
+
+    let refresh = headers
+        .get_raw("Refresh")
+        .and_then(|raw| raw.one())
+        .and_then(|bytes| String::from_utf8(bytes.to_vec()).ok())
+        .and_then(|value| rewrite_refresh(opts, upstream, public, &value));
+    if let Some(value) = refresh {
+        headers.set_raw("Refresh", value);
+    }
+
+    if opts.cookie_domain.is_empty() && opts.cookie_path.is_empty() {
+        return;
+    }
+
+    if let Some(&mut header::SetCookie(ref mut cookies)) = headers.get_mut::<header::SetCookie>() {
+        for cookie in cookies.iter_mut() {
+            *cookie = rewrite_cookie(opts, cookie);
+        }
+    }
+}
+
+/// Returns the new url, or None if it should be left alone.
+fn rewrite_url(
+    opts: &RewriteHeaders,
+    upstream: &Url,
+    public: Option<&Url>,
+    value: &str,
+) -> Option<String> {
+    if let Some((from, to)) = longest_prefix(&opts.redirect, value) {
+        return Some(format!("{}{}", to, &value[from.len()..]));
+    }
+
+    if !opts.location {
+        return None;
+    }
+
+    let public = public?;
+    let mut url = Url::parse(value).ok()?;
+
+    if url.origin() != upstream.origin() {
+        return None;
+    }
+
+    url.set_scheme(public.scheme()).ok()?;
+    url.set_host(public.host_str()).ok()?;
+    url.set_port(public.port()).ok()?;
+
+    Some(url.into_string())
+}
+
+// e.g. "5; url=http://127.0.0.1:4001/"
+fn rewrite_refresh(
+    opts: &RewriteHeaders,
+    upstream: &Url,
+    public: Option<&Url>,
+    value: &str,
+) -> Option<String> {
+    let start = value.to_ascii_lowercase().find("url=")? + "url=".len();
+    let quoted = value[start..].starts_with('"') || value[start..].starts_with('\'');
+    let (start, end) = if quoted {
+        let quote = &value[start..=start];
+        let end = value[start + 1..].find(quote).map(|i| start + 1 + i)?;
+        (start + 1, end)
+    } else {
+        (start, value.len())
+    };
+
+    let url = rewrite_url(opts, upstream, public, value[start..end].trim())?;
+    Some(format!("{}{}{}", &value[..start], url, &value[end..]))
+}
+
+fn rewrite_cookie(opts: &RewriteHeaders, cookie: &str) -> String {
+    cookie
+        .split(';')
+        .enumerate()
+        .filter_map(|(i, attr)| {
+            // The first pair is the cookie itself
+            if i == 0 {
+                return Some(attr.to_string());
+            }
+
+            let mut parts = attr.trim().splitn(2, '=');
+            let name = parts.next().unwrap_or("");
+            let value = parts.next().unwrap_or("");
+
+            if name.eq_ignore_ascii_case("domain") {
+                let domain = value.trim_start_matches('.');
+                let to = opts.cookie_domain
+                    .iter()
+                    .find(|(from, _)| from.trim_start_matches('.').eq_ignore_ascii_case(domain))
+                    .map(|(_, to)| to);
+                return match to {
+                    None => Some(attr.to_string()),
+                    // An empty replacement makes it a host-only cookie
+                    Some(to) if to.is_empty() => None,
+                    Some(to) => Some(format!(" Domain={}", to)),
+                };
+            }
+
+            if name.eq_ignore_ascii_case("path") {
+                return match longest_prefix(&opts.cookie_path, value) {
+                    None => Some(attr.to_string()),
+                    Some((from, to)) => Some(format!(" Path={}{}", to, &value[from.len()..])),
+                };
+            }
+
+            Some(attr.to_string())
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+fn longest_prefix<'a>(map: &'a BTreeMap<String, String>, value: &str) -> Option<(&'a str, &'a str)> {
+    map.iter()
+        .filter(|(from, _)| value.starts_with(from.as_str()))
+        .max_by_key(|(from, _)| from.len())
+        .map(|(from, to)| (from.as_str(), to.as_str()))
+}
+
+#[test]
+fn test_rewrite_location() {
+    let opts = RewriteHeaders::default();
+    let upstream = Url::parse("http://127.0.0.1:4001").unwrap();
+    let public = Url::parse("https://example.com").unwrap();
+
+    let mut headers = Headers::new();
+    headers.set(header::Location::new("http://127.0.0.1:4001/login?next=/"));
+    headers.set(header::ContentLocation("http://other.com/".to_string()));
+    headers.set_raw("Refresh", "0; url=http://127.0.0.1:4001/home");
+    rewrite(&opts, &upstream, Some(&public), &mut headers);
+
+    assert_eq!(
+        headers.get::<header::Location>(),
+        Some(&header::Location::new("https://example.com/login?next=/"))
+    );
+    assert_eq!(
+        headers.get::<header::ContentLocation>(),
+        Some(&header::ContentLocation("http://other.com/".to_string()))
+    );
+    assert_eq!(
+        headers.get_raw("Refresh").and_then(|raw| raw.one()),
+        Some(&b"0; url=https://example.com/home"[..])
+    );
+}
+
+#[test]
+fn test_rewrite_redirect_prefix() {
+    let opts = RewriteHeaders {
+        redirect: vec![
+            ("http://127.0.0.1:4001/".to_string(), "/legacy/".to_string()),
+            ("http://127.0.0.1:4001/admin/".to_string(), "/admin/".to_string()),
+        ].into_iter()
+            .collect(),
+        ..RewriteHeaders::default()
+    };
+    let upstream = Url::parse("http://127.0.0.1:4001").unwrap();
+
+    assert_eq!(
+        rewrite_url(&opts, &upstream, None, "http://127.0.0.1:4001/login"),
+        Some("/legacy/login".to_string())
+    );
+    assert_eq!(
+        rewrite_url(&opts, &upstream, None, "http://127.0.0.1:4001/admin/users"),
+        Some("/admin/users".to_string())
+    );
+    assert_eq!(rewrite_url(&opts, &upstream, None, "/relative"), None);
+}
+
+#[test]
+fn test_rewrite_cookie() {
+    let opts = RewriteHeaders {
+        cookie_domain: vec![
+            ("internal.local".to_string(), "example.com".to_string()),
+            ("127.0.0.1".to_string(), "".to_string()),
+        ].into_iter()
+            .collect(),
+        cookie_path: vec![("/".to_string(), "/legacy/".to_string())]
+            .into_iter()
+            .collect(),
+        ..RewriteHeaders::default()
+    };
+
+    assert_eq!(
+        rewrite_cookie(&opts, "sid=abc; Domain=.Internal.local; Path=/app; HttpOnly"),
+        "sid=abc; Domain=example.com; Path=/legacy/app; HttpOnly"
+    );
+    assert_eq!(
+        rewrite_cookie(&opts, "sid=abc; domain=127.0.0.1; Secure"),
+        "sid=abc; Secure"
+    );
+}
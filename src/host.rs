@@ -37,9 +37,11 @@ impl Host {
     pub fn port(&self) -> u16 {
         self.port
     }
+}
 
-    pub fn to_string(&self) -> String {
-        format!("{}:{}", self.hostname(), self.port())
+impl fmt::Display for Host {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}:{}", self.hostname(), self.port())
     }
 }
 
@@ -49,7 +51,7 @@ impl FromStr for Host {
 
     // FIXME: Lazy impl
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let uri = match format!("{}", s).parse::<Uri>() {
+        let uri = match s.to_string().parse::<Uri>() {
             Ok(uri) => uri,
             Err(_) => return Err(HostParseError(())),
         };
@@ -67,12 +69,8 @@ pub struct HostParseError(());
 
 impl fmt::Display for HostParseError {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        fmt.write_str(self.description())
+        fmt.write_str("host must be \"ipaddress[:port]\" or \"domain[:port]\"")
     }
 }
 
-impl Error for HostParseError {
-    fn description(&self) -> &str {
-        "host must be \"ipaddress[:port]\" or \"domain[:port]\""
-    }
-}
+impl Error for HostParseError {}
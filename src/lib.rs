@@ -1,7 +1,4 @@
 #![allow(unused_macros)]
-#![feature(macro_at_most_once_rep)]
-#![feature(option_filter)]
-#![feature(nll)]
 
 //! Prox is a lightweight reverse proxy and asset server.
 
@@ -9,13 +6,17 @@ extern crate tokio;
 #[macro_use]
 extern crate hyper;
 extern crate atty;
+extern crate brotli;
 extern crate chrono;
 extern crate flate2;
 extern crate futures;
 extern crate leak;
+extern crate libc;
 extern crate percent_encoding;
+extern crate regex;
 extern crate tokio_core;
 extern crate url;
+extern crate zstd;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
@@ -27,24 +28,38 @@ extern crate serde;
 extern crate unicase;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 extern crate toml;
 
 #[macro_use]
 mod util;
+mod balance;
+mod body;
 mod boot_message;
+mod cache;
+mod cgi;
+mod compress;
 mod config;
 mod entity;
 mod etag;
+mod fastcgi;
+mod header_rewrite;
 mod hop;
 mod host;
 mod mime;
+mod mirror;
 mod negotiate;
 mod path;
+mod queue;
 mod range;
 mod response;
+mod rewrite;
 mod server;
 mod service;
+mod split;
+mod state;
+mod streaming;
 
-pub use config::{Config, Gzip, Log, Server, Site, Timeouts, Serve};
+pub use config::{Cache, Config, Gzip, Log, Server, Site, SubFilter, Timeouts, Serve};
 
 pub use server::serve;
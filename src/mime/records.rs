@@ -30,7 +30,7 @@ lazy_static! {
     pub static ref COMPRESSIBLE_BY_MIME: HashSet<Mime> = {
         let mut xs = HashSet::new();
 
-        for (_, v) in self::BY_EXTENSION.iter() {
+        for v in self::BY_EXTENSION.values() {
             if v.compressible {
                 xs.insert(v.mime.clone());
             }
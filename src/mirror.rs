@@ -0,0 +1,225 @@
+//! Copying live traffic to a secondary upstream whose responses are thrown away.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use futures::{Future, future::{ok, Either}};
+use futures::sync::oneshot;
+use futures_cpupool::CpuPool;
+use hyper::{header, Client, Request, Uri, client::HttpConnector};
+use tokio_core::reactor::{Handle, Timeout};
+
+use body;
+use config;
+use hop;
+use util;
+
+/// What's known about the mirror's responses. Only ever logged.
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// Requests that got a response.
+    pub responses: u64,
+    /// Requests that failed or timed out.
+    pub errors: u64,
+    /// Requests that weren't sent because their body was too large.
+    pub skipped: u64,
+    /// Response count by status code.
+    pub statuses: BTreeMap<u16, u64>,
+    pub total_latency: Duration,
+    pub max_latency: Duration,
+}
+
+/// A site's mirroring state.
+#[derive(Default)]
+pub struct Mirror {
+    seen: AtomicUsize,
+    stats: Mutex<Stats>,
+}
+
+impl Mirror {
+    // Spreads the mirrored requests evenly, e.g. every 4th request at 25%.
+    fn sample(&self, percent: f64) -> bool {
+        let n = self.seen.fetch_add(1, Ordering::SeqCst) as f64;
+        ((n + 1.0) * percent / 100.0).floor() > (n * percent / 100.0).floor()
+    }
+
+    fn record(&self, req: &str, status: Option<u16>, latency: Duration) {
+        let mut stats = self.stats.lock().unwrap();
+        match status {
+            Some(status) => {
+                stats.responses += 1;
+                *stats.statuses.entry(status).or_insert(0) += 1;
+            }
+            None => stats.errors += 1,
+        }
+        stats.total_latency += latency;
+        if latency > stats.max_latency {
+            stats.max_latency = latency;
+        }
+
+        let count = stats.responses + stats.errors;
+        debug!(
+            "[mirror] {} -> {} in {}ms (count={} errors={} skipped={} avg={}ms max={}ms statuses={:?})",
+            req,
+            status.map(|x| x.to_string()).unwrap_or_else(|| "error".to_string()),
+            util::duration_as_millis(latency),
+            count,
+            stats.errors,
+            stats.skipped,
+            util::duration_as_millis(stats.total_latency) / count,
+            util::duration_as_millis(stats.max_latency),
+            stats.statuses,
+        );
+    }
+
+    fn record_skipped(&self) {
+        self.stats.lock().unwrap().skipped += 1;
+    }
+}
+
+/// Sends a copy of the proxied request to the mirror in the background.
+///
+/// The request body is streamed to the upstream as usual while a copy of it is kept.
+/// The mirror only gets the request once the whole body has arrived.
+pub fn spawn(
+    opts: &'static config::Mirror,
+    mirror: &'static Mirror,
+    client: &'static Client<HttpConnector>,
+    handle: &Handle,
+    pool: &CpuPool,
+    timeout: Duration,
+    req: &mut Request,
+) {
+    if !mirror.sample(opts.percent) {
+        return;
+    }
+
+    let mut copy = match copy_request(opts, req) {
+        None => return,
+        Some(copy) => copy,
+    };
+
+    let (tx, rx) = oneshot::channel();
+
+    match req.body_mut().take() {
+        None => {
+            let _ = tx.send(Vec::new());
+        }
+        Some(stream) => {
+            // The sender is dropped without sending if the body grows too large
+            let tee = body::Tee::new(stream, opts.max_body_size, move |buf| {
+                let _ = tx.send(buf);
+            });
+            req.set_body(body::spawn(pool, tee));
+        }
+    }
+
+    let timeout = match Timeout::new(timeout, handle) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("error creating timeout: {}", e);
+            return;
+        }
+    };
+
+    let summary = format!("{} {}", copy.method(), copy.path());
+
+    let future = rx.then(move |buf| match buf {
+        Err(_) => {
+            mirror.record_skipped();
+            Either::A(ok::<(), ()>(()))
+        }
+        Ok(buf) => {
+            if !buf.is_empty() {
+                copy.set_body(buf);
+            }
+
+            let start = Instant::now();
+
+            // The mirror's body is dropped unread
+            let future = client
+                .request(copy)
+                .map(|res| res.status().as_u16())
+                .select2(timeout)
+                .then(move |result| {
+                    let status = match result {
+                        Ok(Either::A((status, _))) => Some(status),
+                        _ => None,
+                    };
+                    mirror.record(&summary, status, start.elapsed());
+                    Ok::<(), ()>(())
+                });
+
+            Either::B(future)
+        }
+    });
+
+    handle.spawn(future);
+}
+
+// Same method, target and end-to-end headers, but addressed to the mirror.
+fn copy_request(opts: &config::Mirror, req: &Request) -> Option<Request> {
+    let uri = opts.url
+        .join(&util::path_and_query(req.uri()))
+        .ok()
+        .and_then(|url| url.to_string().parse::<Uri>().ok())?;
+
+    let mut copy = Request::new(req.method().clone(), uri);
+    copy.set_version(req.version());
+    copy.headers_mut()
+        .extend(req.headers().iter().filter(|h| !hop::is_hop_header(h.name())));
+    copy.headers_mut().remove::<header::Host>();
+    if let Some(host) = opts.url.host_str() {
+        copy.headers_mut().set(header::Host::new(host.to_string(), opts.url.port()));
+    }
+
+    Some(copy)
+}
+
+#[test]
+fn test_copy_request() {
+    use hyper::Method;
+
+    let opts = config::Mirror {
+        url: "http://127.0.0.1:4002/".parse().unwrap(),
+        percent: 100.0,
+        max_body_size: 0,
+    };
+    let mut req = Request::new(Method::Get, "/search?q=prox".parse().unwrap());
+    req.headers_mut().set(header::Host::new("example.com", None));
+    req.headers_mut().set(header::Connection::close());
+    req.headers_mut().set_raw("Proxy-Authorization", "Basic Zm9vOmJhcg==");
+    req.headers_mut().set(header::UserAgent::new("curl"));
+
+    let copy = copy_request(&opts, &req).unwrap();
+    assert_eq!(copy.uri().to_string(), "http://127.0.0.1:4002/search?q=prox");
+    assert_eq!(
+        copy.headers().get::<header::Host>(),
+        Some(&header::Host::new("127.0.0.1", Some(4002)))
+    );
+    assert!(!copy.headers().has::<header::Connection>());
+    assert!(copy.headers().get_raw("Proxy-Authorization").is_none());
+    assert!(copy.headers().has::<header::UserAgent>());
+}
+
+#[test]
+fn test_sample() {
+    let count = |percent| {
+        let mirror = Mirror::default();
+        (0..100).filter(|_| mirror.sample(percent)).count()
+    };
+
+    assert_eq!(count(0.0), 0);
+    assert_eq!(count(25.0), 25);
+    assert_eq!(count(12.5), 12);
+    assert_eq!(count(100.0), 100);
+
+    let mirror = Mirror::default();
+    let picks = (0..8).map(|_| mirror.sample(25.0)).collect::<Vec<_>>();
+    assert_eq!(
+        picks,
+        vec![false, false, false, true, false, false, false, true]
+    );
+}
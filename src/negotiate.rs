@@ -1,10 +1,12 @@
 use hyper::header;
 
+use compress::Coding;
+
 // If returns false, then there was an etag match so we should respond with not-modified.
 pub fn none_match(header_value: Option<&header::IfNoneMatch>, etag: &header::EntityTag) -> bool {
     match header_value {
         Some(&header::IfNoneMatch::Any) => false,
-        Some(&header::IfNoneMatch::Items(ref candidates)) => {
+        Some(header::IfNoneMatch::Items(candidates)) => {
             for candidate in candidates {
                 if candidate.weak_eq(etag) {
                     return false;
@@ -20,7 +22,7 @@ pub fn none_match(header_value: Option<&header::IfNoneMatch>, etag: &header::Ent
 pub fn any_match(header_value: Option<&header::IfMatch>, etag: &header::EntityTag) -> bool {
     match header_value {
         None | Some(&header::IfMatch::Any) => true,
-        Some(&header::IfMatch::Items(ref candidates)) => {
+        Some(header::IfMatch::Items(candidates)) => {
             for candidate in candidates {
                 if candidate.strong_eq(etag) {
                     return true;
@@ -31,51 +33,87 @@ pub fn any_match(header_value: Option<&header::IfMatch>, etag: &header::EntityTa
     }
 }
 
-// Returns Some(Gzip) if we should compress.
+// Returns the coding we should compress with, if any.
+//
+// The client's q-values come first. Ties go to the coding that comes first in `available`.
 //
 // https://tools.ietf.org/html/rfc7231#section-5.3.4
-pub fn encoding(header_value: Option<&header::AcceptEncoding>) -> Option<header::Encoding> {
+pub fn encoding(header_value: Option<&header::AcceptEncoding>, available: &[Coding]) -> Option<Coding> {
     let qis = match header_value {
         None => return None,
-        Some(&header::AcceptEncoding(ref qis)) => qis,
+        Some(header::AcceptEncoding(qis)) => qis,
     };
 
-    let (mut gzip_q, mut identity_q, mut star_q) = (None, None, None);
-
-    for qi in qis {
-        match qi.item {
-            header::Encoding::Gzip => {
-                gzip_q = Some(qi.quality);
-            }
-            header::Encoding::Identity => {
-                identity_q = Some(qi.quality);
-            }
-            header::Encoding::EncodingExt(ref e) if e == "*" => {
-                star_q = Some(qi.quality);
-            }
-            _ => {}
-        };
-    }
+    let q_of = |encoding: &header::Encoding| {
+        qis.iter()
+            .find(|qi| qi.item == *encoding)
+            .map(|qi| qi.quality)
+    };
 
-    let gzip_q = gzip_q.or(star_q).unwrap_or_else(|| header::q(0));
+    let star_q = q_of(&header::Encoding::EncodingExt("*".to_string()));
 
     // If the representation has no content-coding, then it is
     // acceptable by default unless specifically excluded by the
     // Accept-Encoding field stating either "identity;q=0" or "*;q=0"
     // without a more specific entry for "identity".
-    let identity_q = identity_q.or(star_q).unwrap_or_else(|| header::q(1));
+    let identity_q = q_of(&header::Encoding::Identity)
+        .or(star_q)
+        .unwrap_or_else(|| header::q(1));
 
-    if gzip_q > header::q(0) && gzip_q >= identity_q {
-        Some(header::Encoding::Gzip)
-    } else {
-        None
+    let mut best: Option<(Coding, header::Quality)> = None;
+
+    for &coding in available {
+        let q = q_of(&coding.encoding()).or(star_q).unwrap_or_else(|| header::q(0));
+        let is_better = best.map(|(_, best_q)| q > best_q).unwrap_or(true);
+        if q > header::q(0) && q >= identity_q && is_better {
+            best = Some((coding, q));
+        }
     }
+
+    best.map(|(coding, _)| coding)
+}
+
+// Returns true if the client can decode a body in this coding.
+//
+// Clients that send no Accept-Encoding at all are taken to only want identity, like in `encoding`.
+pub fn accepts(header_value: Option<&header::AcceptEncoding>, coding: Coding) -> bool {
+    let qis = match header_value {
+        None => return false,
+        Some(header::AcceptEncoding(qis)) => qis,
+    };
+
+    let q_of = |encoding: &header::Encoding| {
+        qis.iter()
+            .find(|qi| qi.item == *encoding)
+            .map(|qi| qi.quality)
+    };
+
+    q_of(&coding.encoding())
+        .or_else(|| q_of(&header::Encoding::EncodingExt("*".to_string())))
+        .map(|q| q > header::q(0))
+        .unwrap_or(false)
+}
+
+#[test]
+fn test_negotiate_accepts() {
+    use hyper::header::{AcceptEncoding, Header, Raw};
+    use compress::Coding::{Brotli, Gzip};
+    let parse = |s: &[u8]| AcceptEncoding::parse_header(&Raw::from(s)).unwrap();
+    assert!(accepts(Some(&parse(b"gzip, deflate")), Gzip));
+    assert!(accepts(Some(&parse(b"gzip;q=0.1, identity")), Gzip));
+    assert!(!accepts(Some(&parse(b"gzip, deflate")), Brotli));
+    assert!(accepts(Some(&parse(b"*")), Brotli));
+    assert!(!accepts(Some(&parse(b"br;q=0, *")), Brotli));
+    assert!(!accepts(Some(&parse(b"identity")), Gzip));
+    assert!(!accepts(None, Gzip));
 }
 
 #[test]
 fn test_negotiate_encoding() {
-    use hyper::header::{AcceptEncoding, Encoding::Gzip, Header, Raw};
-    let parse = |s: &[u8]| AcceptEncoding::parse_header(&Raw::from(&s[..])).unwrap();
+    use hyper::header::{AcceptEncoding, Header, Raw};
+    use compress::Coding::Gzip;
+    let parse = |s: &[u8]| AcceptEncoding::parse_header(&Raw::from(s)).unwrap();
+    let encoding = |value: Option<&AcceptEncoding>| encoding(value, &[Gzip]);
     assert_eq!(encoding(Some(&parse(b"compress, gzip"))), Some(Gzip));
     assert_eq!(
         encoding(Some(&parse(b"compress;q=0.5, gzip;q=1.0"))),
@@ -99,3 +137,69 @@ fn test_negotiate_encoding() {
     assert_eq!(encoding(Some(&parse(b"identity;q=1.0, gzip;q=0.5"))), None);
     assert_eq!(encoding(Some(&parse(b"*;q=0"))), None);
 }
+
+#[test]
+fn test_negotiate_encoding_preference() {
+    use hyper::header::{AcceptEncoding, Header, Raw};
+    use compress::Coding::{Brotli, Deflate, Gzip, Zstd};
+    let parse = |s: &[u8]| AcceptEncoding::parse_header(&Raw::from(s)).unwrap();
+    let all = [Brotli, Zstd, Gzip, Deflate];
+
+    // Ties go to the server's preference
+    assert_eq!(encoding(Some(&parse(b"gzip, deflate, br")), &all), Some(Brotli));
+    assert_eq!(encoding(Some(&parse(b"gzip, deflate, br")), &[Gzip, Brotli]), Some(Gzip));
+    assert_eq!(encoding(Some(&parse(b"*")), &all), Some(Brotli));
+
+    // The client's q-values come first
+    assert_eq!(encoding(Some(&parse(b"br;q=0.5, gzip")), &all), Some(Gzip));
+    assert_eq!(encoding(Some(&parse(b"zstd, br;q=0.9")), &all), Some(Zstd));
+    assert_eq!(encoding(Some(&parse(b"deflate, *;q=0.1")), &all), Some(Deflate));
+    assert_eq!(encoding(Some(&parse(b"br, *;q=0")), &[Gzip, Deflate]), None);
+    assert_eq!(encoding(Some(&parse(b"gzip")), &[]), None);
+}
+
+// Returns true if the client asked for JSON over HTML, e.g. for error bodies.
+//
+// A bare `*/*` counts for neither so that browsers and curl get HTML.
+pub fn prefers_json(header_value: Option<&header::Accept>) -> bool {
+    use hyper::mime;
+
+    let qis = match header_value {
+        None => return false,
+        Some(header::Accept(qis)) => qis,
+    };
+
+    let (mut json_q, mut html_q, mut text_q) = (None, None, None);
+
+    for qi in qis {
+        let (type_, subtype) = (qi.item.type_(), qi.item.subtype());
+        if type_ == mime::APPLICATION && subtype == mime::JSON {
+            json_q = Some(qi.quality);
+        } else if type_ == mime::TEXT && subtype == mime::HTML {
+            html_q = Some(qi.quality);
+        } else if type_ == mime::TEXT && subtype == mime::STAR {
+            text_q = Some(qi.quality);
+        }
+    }
+
+    let json_q = json_q.unwrap_or_else(|| header::q(0));
+    let html_q = html_q.or(text_q).unwrap_or_else(|| header::q(0));
+
+    json_q > header::q(0) && json_q > html_q
+}
+
+#[test]
+fn test_prefers_json() {
+    use hyper::header::{Accept, Header, Raw};
+    let parse = |s: &[u8]| Accept::parse_header(&Raw::from(s)).unwrap();
+    assert!(!prefers_json(None));
+    assert!(!prefers_json(Some(&parse(b"*/*"))));
+    assert!(!prefers_json(Some(&parse(
+        b"text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"
+    ))));
+    assert!(prefers_json(Some(&parse(b"application/json"))));
+    assert!(prefers_json(Some(&parse(b"application/json, text/plain, */*"))));
+    assert!(prefers_json(Some(&parse(b"text/html;q=0.5, application/json"))));
+    assert!(!prefers_json(Some(&parse(b"text/*, application/json;q=0.9"))));
+    assert!(!prefers_json(Some(&parse(b"application/json;q=0"))));
+}
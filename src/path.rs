@@ -2,8 +2,9 @@ use std::path::{Path, PathBuf};
 use std::str::Utf8Error;
 
 use percent_encoding as pe;
+use regex::Regex;
 
-fn percent_decode_str(s: &str) -> Result<String, Utf8Error> {
+pub fn percent_decode_str(s: &str) -> Result<String, Utf8Error> {
     pe::percent_decode(s.as_bytes())
         .decode_utf8()
         .map(|x| x.to_string())
@@ -13,10 +14,7 @@ fn percent_decode_str(s: &str) -> Result<String, Utf8Error> {
 fn is_safe_path(path: &Path) -> bool {
     use std::path::Component::{Normal, RootDir};
 
-    path.components().all(|c| match c {
-        RootDir | Normal(_) => true,
-        _ => false,
-    })
+    path.components().all(|c| matches!(c, RootDir | Normal(_)))
 }
 
 #[test]
@@ -74,3 +72,61 @@ fn test_get_entity_path() {
         Some(PathBuf::from("./中文.txt"))
     );
 }
+
+#[derive(Debug, Clone)]
+pub enum PathPattern {
+    Glob(String),
+    Regex(Regex),
+}
+
+impl PathPattern {
+    pub fn is_match(&self, path: &str) -> bool {
+        match *self {
+            PathPattern::Glob(ref pattern) => glob_match(pattern, path),
+            PathPattern::Regex(ref regex) => regex.is_match(path),
+        }
+    }
+}
+
+// Matches a request path against a pattern where `*` matches anything, e.g. "/api/*".
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    if !path.starts_with(first) {
+        return false;
+    }
+    let mut rest = &path[first.len()..];
+
+    let parts = parts.collect::<Vec<_>>();
+    let (last, middle) = match parts.split_last() {
+        None => return rest.is_empty(),
+        Some(x) => x,
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            None => return false,
+            Some(i) => rest = &rest[i + part.len()..],
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+#[test]
+fn test_glob_match() {
+    assert!(glob_match("/api/*", "/api/"));
+    assert!(glob_match("/api/*", "/api/users/1"));
+    assert!(!glob_match("/api/*", "/api"));
+    assert!(!glob_match("/api/*", "/apis/users"));
+    assert!(glob_match("/api*", "/api"));
+    assert!(glob_match("*.map", "/js/app.js.map"));
+    assert!(glob_match("/static/*/*.js", "/static/v1/app.js"));
+    assert!(!glob_match("/static/*/*.js", "/static/v1/app.css"));
+    assert!(glob_match("/health", "/health"));
+    assert!(!glob_match("/health", "/healthz"));
+    assert!(glob_match("*", "/anything"));
+    assert!(glob_match("/a*ba", "/aba"));
+    // The suffix can't reuse bytes the prefix already matched
+    assert!(!glob_match("/ab*ba", "/aba"));
+}
@@ -0,0 +1,244 @@
+//! Limiting concurrent requests to an upstream url and queueing the rest.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use futures::{Async, Future, Poll, Stream, future::{err, ok, Either}};
+use futures::sync::oneshot;
+use hyper::{self, Chunk};
+use tokio_core::reactor::{Handle, Timeout};
+
+use config;
+
+/// Why a request didn't get to go upstream.
+#[derive(Debug, PartialEq)]
+pub enum Rejected {
+    /// Too many requests were already waiting.
+    Full,
+    /// It waited longer than `queue_timeout`.
+    TimedOut,
+}
+
+pub struct Limiter {
+    // For logging
+    url: String,
+    max_connections: usize,
+    max_pending: usize,
+    inner: Mutex<Inner>,
+    // Totals since startup
+    rejected: AtomicUsize,
+    timed_out: AtomicUsize,
+}
+
+/// A snapshot of a limiter for the admin listing.
+#[derive(Debug, PartialEq)]
+pub struct Stats {
+    /// Requests holding a connection slot.
+    pub active: usize,
+    /// Requests waiting for a slot.
+    pub depth: usize,
+    /// Requests turned away since startup because the queue was full.
+    pub rejected: usize,
+    /// Requests that gave up waiting since startup.
+    pub timed_out: usize,
+}
+
+struct Inner {
+    active: usize,
+    queue: VecDeque<oneshot::Sender<Permit>>,
+}
+
+/// Holds one of the upstream's connection slots until it's dropped.
+pub struct Permit {
+    // None once the permit was handed to a waiter that went away
+    limiter: Option<&'static Limiter>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(limiter) = self.limiter.take() {
+            limiter.release();
+        }
+    }
+}
+
+impl Limiter {
+    pub fn new(url: &str, opts: &config::Upstream) -> Self {
+        Limiter {
+            url: url.to_string(),
+            max_connections: opts.max_connections,
+            max_pending: opts.max_pending,
+            inner: Mutex::new(Inner {
+                active: 0,
+                queue: VecDeque::new(),
+            }),
+            rejected: AtomicUsize::new(0),
+            timed_out: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        let inner = self.inner.lock().unwrap();
+        Stats {
+            active: inner.active,
+            depth: inner.queue.iter().filter(|tx| !tx.is_canceled()).count(),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            timed_out: self.timed_out.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Resolves once the request may go upstream.
+    pub fn acquire(
+        &'static self,
+        timeout: Duration,
+        handle: &Handle,
+    ) -> impl Future<Item = Permit, Error = Rejected> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.active < self.max_connections {
+            inner.active += 1;
+            return Either::A(ok(Permit {
+                limiter: Some(self),
+            }));
+        }
+
+        // Forget the waiters that already gave up
+        inner.queue.retain(|tx| !tx.is_canceled());
+
+        if inner.queue.len() >= self.max_pending {
+            warn!(
+                "[queue] {} rejected a request (active={} depth={})",
+                self.url,
+                inner.active,
+                inner.queue.len()
+            );
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return Either::A(err(Rejected::Full));
+        }
+
+        let timeout = match Timeout::new(timeout, handle) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("error creating timeout: {}", e);
+                return Either::A(err(Rejected::TimedOut));
+            }
+        };
+
+        let (tx, rx) = oneshot::channel();
+        inner.queue.push_back(tx);
+
+        info!(
+            "[queue] {} queued a request (active={} depth={})",
+            self.url,
+            inner.active,
+            inner.queue.len()
+        );
+
+        Either::B(rx.select2(timeout).then(move |result| match result {
+            Ok(Either::A((permit, _))) => Ok(permit),
+            _ => {
+                warn!("[queue] {} timed out a queued request", self.url);
+                self.timed_out.fetch_add(1, Ordering::Relaxed);
+                Err(Rejected::TimedOut)
+            }
+        }))
+    }
+
+    /// Hands the slot to the next waiter, or frees it.
+    fn release(&'static self) {
+        let mut inner = self.inner.lock().unwrap();
+
+        while let Some(tx) = inner.queue.pop_front() {
+            let permit = Permit {
+                limiter: Some(self),
+            };
+            match tx.send(permit) {
+                Ok(()) => return,
+                // The waiter went away, so the permit must not release the slot again
+                Err(mut permit) => permit.limiter = None,
+            }
+        }
+
+        inner.active -= 1;
+    }
+}
+
+/// Keeps a permit until the body has been streamed.
+pub struct Hold<S> {
+    stream: S,
+    permit: Option<Permit>,
+}
+
+impl<S> Hold<S> {
+    pub fn new(stream: S, permit: Permit) -> Self {
+        Hold {
+            stream,
+            permit: Some(permit),
+        }
+    }
+}
+
+impl<S> Stream for Hold<S>
+where
+    S: Stream<Item = Chunk, Error = hyper::Error>,
+{
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        let result = self.stream.poll();
+        match result {
+            Ok(Async::NotReady) | Ok(Async::Ready(Some(_))) => {}
+            _ => self.permit = None,
+        }
+        result
+    }
+}
+
+#[test]
+fn test_limiter() {
+    use leak::Leak;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let opts = config::Upstream {
+        max_connections: 1,
+        max_pending: 1,
+        queue_timeout: Duration::from_secs(5),
+    };
+    let limiter = Box::new(Limiter::new("http://localhost:4001", &opts)).leak();
+    let timeout = opts.queue_timeout;
+
+    let first = core.run(limiter.acquire(timeout, &handle)).unwrap();
+    let second = limiter.acquire(timeout, &handle);
+
+    // Nowhere left to wait
+    assert_eq!(
+        core.run(limiter.acquire(timeout, &handle)).err(),
+        Some(Rejected::Full)
+    );
+    assert_eq!(
+        limiter.stats(),
+        Stats { active: 1, depth: 1, rejected: 1, timed_out: 0 }
+    );
+
+    // The slot goes to the waiter
+    drop(first);
+    let second = core.run(second).unwrap();
+    assert_eq!(limiter.inner.lock().unwrap().active, 1);
+
+    drop(second);
+    assert_eq!(limiter.inner.lock().unwrap().active, 0);
+
+    // Waiters give up eventually
+    let _third = core.run(limiter.acquire(timeout, &handle)).unwrap();
+    let fourth = limiter.acquire(Duration::from_millis(1), &handle);
+    assert_eq!(core.run(fourth).err(), Some(Rejected::TimedOut));
+    assert_eq!(
+        limiter.stats(),
+        Stats { active: 1, depth: 0, rejected: 1, timed_out: 1 }
+    );
+}
@@ -1,8 +1,13 @@
 use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use hyper::header::{self, ByteRangeSpec};
 use std::cmp;
 
+// More ranges than this, even after coalescing, and we send the whole entity instead.
+pub const MAX_RANGES: usize = 32;
+
 #[derive(Debug, PartialEq)]
 pub enum RequestedRange {
     // Client did not provide a range
@@ -11,8 +16,9 @@ pub enum RequestedRange {
     // Client provided a range but it was invalid
     NotSatisfiable,
 
-    // We can serve the client's requested range
-    Satisfiable(Range<u64>),
+    // We can serve the client's requested ranges. They're end-inclusive, sorted,
+    // and none of them overlap.
+    Satisfiable(Vec<Range<u64>>),
 }
 
 pub fn parse_range_header(
@@ -21,13 +27,7 @@ pub fn parse_range_header(
     file_len: u64,
 ) -> RequestedRange {
     match header_value {
-        Some(&header::Range::Bytes(ref byte_ranges)) => {
-            // Get the first byte range or short-circuit if none given.
-            let byte_range = match byte_ranges.first() {
-                Some(range) => range,
-                None => return RequestedRange::NotSatisfiable,
-            };
-
+        Some(header::Range::Bytes(byte_ranges)) => {
             // Avoid overflow on zero-length file by short-circuiting if client tries
             // to define a range at all since even 0-0 is impossible.
             if file_len == 0 {
@@ -36,37 +36,57 @@ pub fn parse_range_header(
 
             let max_end = file_len - 1;
 
-            let range = match *byte_range {
-                ByteRangeSpec::FromTo(start, end) => start..(cmp::min(max_end, end)),
-                ByteRangeSpec::AllFrom(start) => start..max_end,
-                ByteRangeSpec::Last(suffix_len) => {
-                    if suffix_len == 0 {
-                        return RequestedRange::NotSatisfiable;
+            let ranges = byte_ranges
+                .iter()
+                .filter_map(|byte_range| {
+                    let range = match *byte_range {
+                        ByteRangeSpec::FromTo(start, end) => start..(cmp::min(max_end, end)),
+                        ByteRangeSpec::AllFrom(start) => start..max_end,
+                        ByteRangeSpec::Last(suffix_len) => {
+                            if suffix_len == 0 {
+                                return None;
+                            }
+                            // Ensure start cannot be negative
+                            let start = file_len - cmp::min(file_len, suffix_len);
+                            start..max_end
+                        }
+                    };
+
+                    // VALIDATION
+
+                    // Bad range: start > end
+                    // FIXME: This doesn't actually check anything because header_value goes to None branch if start > end.
+                    // I'd prefer to respond NotSatisfiable.
+                    if range.start > range.end {
+                        return None;
+                    }
+
+                    // Bad range: start >= resource length
+                    // BAD: fileLength=10 and range is "10-"
+                    // BAD: fileLength=10 and range is "10-10"
+                    // GOOD: fileLength=10 and range is "9-"
+                    if range.start > max_end {
+                        return None;
                     }
-                    // Ensure start cannot be negative
-                    let start = max_end - cmp::min(max_end, suffix_len + 1);
-                    start..max_end
-                }
-            };
 
-            // VALIDATION
+                    Some(range)
+                })
+                .collect::<Vec<_>>();
 
-            // Bad range: start > end
-            // FIXME: This doesn't actually check anything because header_value goes to None branch if start > end.
-            // I'd prefer to respond NotSatisfiable.
-            if range.start > range.end {
+            // Only unsatisfiable if none of the ranges are
+            if ranges.is_empty() {
                 return RequestedRange::NotSatisfiable;
             }
 
-            // Bad range: start >= resource length
-            // BAD: fileLength=10 and range is "10-"
-            // BAD: fileLength=10 and range is "10-10"
-            // GOOD: fileLength=10 and range is "9-"
-            if range.start > max_end {
-                return RequestedRange::NotSatisfiable;
+            let ranges = coalesce(ranges);
+
+            // Too many ranges is more likely abuse than a real client, so ignore the header.
+            // <https://tools.ietf.org/html/rfc7233#section-6.1>
+            if ranges.len() > MAX_RANGES {
+                return RequestedRange::None;
             }
 
-            RequestedRange::Satisfiable(range)
+            RequestedRange::Satisfiable(ranges)
         }
         // We only support byte ranges.
         Some(_) => RequestedRange::NotSatisfiable,
@@ -83,7 +103,90 @@ pub fn parse_range_header(
     }
 }
 
+// Returns false if the client's If-Range validator no longer matches the entity, in which
+// case the Range header is ignored and the whole entity is sent.
+//
+// ETags must match strongly, and dates exactly, to the second that HTTP dates have.
+// <https://tools.ietf.org/html/rfc7233#section-3.2>
+pub fn if_range_matches(
+    has_header: bool,
+    header_value: Option<&header::IfRange>,
+    etag: &header::EntityTag,
+    last_modified: header::HttpDate,
+) -> bool {
+    match header_value {
+        Some(header::IfRange::EntityTag(tag)) => tag.strong_eq(etag),
+        Some(&header::IfRange::Date(date)) => {
+            let secs = |date: header::HttpDate| {
+                SystemTime::from(date)
+                    .duration_since(UNIX_EPOCH)
+                    .map(|x| x.as_secs())
+                    .unwrap_or(0)
+            };
+            secs(date) == secs(last_modified)
+        }
+        // Can't tell what the client has, so it gets everything
+        None => !has_header,
+    }
+}
+
+// Merges end-inclusive ranges that overlap or touch, so that no byte is sent twice.
+fn coalesce(mut ranges: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    ranges.sort_by_key(|range| range.start);
+
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(ref mut last) if range.start <= last.end.saturating_add(1) => {
+                last.end = cmp::max(last.end, range.end);
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+// A `multipart/byteranges` boundary that won't show up in the parts.
+pub fn boundary() -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.subsec_nanos())
+        .unwrap_or(0);
+    format!(
+        "prox-byteranges-{:08x}{:08x}",
+        nanos,
+        COUNTER.fetch_add(1, Ordering::Relaxed) as u32
+    )
+}
+
+// The headers before each part of a `multipart/byteranges` body.
+//
+// <https://tools.ietf.org/html/rfc7233#appendix-A>
+pub fn part_head(boundary: &str, content_type: &str, range: &Range<u64>, entity_len: u64) -> String {
+    format!(
+        "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+        boundary, content_type, range.start, range.end, entity_len
+    )
+}
+
+pub fn multipart_tail(boundary: &str) -> String {
+    format!("\r\n--{}--\r\n", boundary)
+}
+
+// The byte length of a `multipart/byteranges` body, so that it can have a Content-Length.
+pub fn multipart_len(ranges: &[Range<u64>], boundary: &str, content_type: &str, entity_len: u64) -> u64 {
+    let parts: u64 = ranges
+        .iter()
+        .map(|range| {
+            part_head(boundary, content_type, range, entity_len).len() as u64 + range.end - range.start + 1
+        })
+        .sum();
+    parts + multipart_tail(boundary).len() as u64
+}
+
 #[test]
+#[allow(clippy::single_range_in_vec_init)]
 // TODO: Add more tests. Test failures. Consider using RangeInclusive.
 fn test_parse_range_header() {
     // Range given but hyper can't parse it.
@@ -107,7 +210,7 @@ fn test_parse_range_header() {
     );
 
     assert_eq!(
-        RequestedRange::Satisfiable(0..0),
+        RequestedRange::Satisfiable(vec![0..0]),
         parse_range_header(
             true,
             Some(&header::Range::Bytes(vec![
@@ -117,3 +220,94 @@ fn test_parse_range_header() {
         )
     );
 }
+
+#[test]
+#[allow(clippy::single_range_in_vec_init)]
+fn test_parse_multiple_ranges() {
+    use hyper::header::ByteRangeSpec::{AllFrom, FromTo, Last};
+
+    let parse = |specs: Vec<ByteRangeSpec>| {
+        parse_range_header(true, Some(&header::Range::Bytes(specs)), 100)
+    };
+
+    assert_eq!(
+        parse(vec![FromTo(0, 9), FromTo(50, 59), Last(10)]),
+        RequestedRange::Satisfiable(vec![0..9, 50..59, 90..99])
+    );
+
+    // Suffixes longer than the entity are the whole entity
+    assert_eq!(parse(vec![Last(500)]), RequestedRange::Satisfiable(vec![0..99]));
+
+    // Overlapping and adjacent ranges are coalesced
+    assert_eq!(
+        parse(vec![FromTo(50, 59), FromTo(0, 9), FromTo(5, 20), FromTo(21, 30), AllFrom(55)]),
+        RequestedRange::Satisfiable(vec![0..30, 50..99])
+    );
+
+    // Unsatisfiable ranges are dropped unless that's all of them
+    assert_eq!(
+        parse(vec![FromTo(0, 9), AllFrom(100)]),
+        RequestedRange::Satisfiable(vec![0..9])
+    );
+    assert_eq!(parse(vec![AllFrom(100), Last(0)]), RequestedRange::NotSatisfiable);
+
+    // Too many ranges and the whole entity is sent instead
+    let many = (0..MAX_RANGES as u64 + 1).map(|i| FromTo(i * 2, i * 2)).collect();
+    assert_eq!(parse(many), RequestedRange::None);
+    let overlapping = (0..100).map(|_| FromTo(0, 9)).collect();
+    assert_eq!(parse(overlapping), RequestedRange::Satisfiable(vec![0..9]));
+}
+
+#[test]
+fn test_multipart_len() {
+    let ranges = vec![0..9, 50..59];
+    let boundary = boundary();
+    let body = format!(
+        "{}{}{}{}{}",
+        part_head(&boundary, "text/plain", &ranges[0], 100),
+        "x".repeat(10),
+        part_head(&boundary, "text/plain", &ranges[1], 100),
+        "x".repeat(10),
+        multipart_tail(&boundary)
+    );
+    assert!(body.contains("\r\nContent-Range: bytes 50-59/100\r\n"));
+    assert_eq!(multipart_len(&ranges, &boundary, "text/plain", 100), body.len() as u64);
+    assert_ne!(boundary, self::boundary());
+}
+
+#[test]
+fn test_if_range_matches() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let etag = header::EntityTag::strong("abc".to_string());
+    let modified = header::HttpDate::from(UNIX_EPOCH + Duration::from_secs(1_500_000_000));
+    let matches = |value: Option<header::IfRange>| if_range_matches(true, value.as_ref(), &etag, modified);
+
+    // No If-Range
+    assert!(if_range_matches(false, None, &etag, modified));
+    // If-Range that hyper couldn't parse
+    assert!(!matches(None));
+
+    // ETags
+    assert!(matches(Some(header::IfRange::EntityTag(header::EntityTag::strong("abc".to_string())))));
+    assert!(!matches(Some(header::IfRange::EntityTag(header::EntityTag::strong("xyz".to_string())))));
+    // Weak validators never match
+    assert!(!matches(Some(header::IfRange::EntityTag(header::EntityTag::weak("abc".to_string())))));
+    assert!(!if_range_matches(
+        true,
+        Some(&header::IfRange::EntityTag(header::EntityTag::weak("abc".to_string()))),
+        &header::EntityTag::weak("abc".to_string()),
+        modified
+    ));
+
+    // Dates
+    assert!(matches(Some(header::IfRange::Date(modified))));
+    let earlier = header::HttpDate::from(UNIX_EPOCH + Duration::from_secs(1_400_000_000));
+    assert!(!matches(Some(header::IfRange::Date(earlier))));
+    let later = header::HttpDate::from(UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+    assert!(!matches(Some(header::IfRange::Date(later))));
+
+    // Files have finer mtimes than HTTP dates
+    let modified_nanos = header::HttpDate::from(UNIX_EPOCH + Duration::new(1_500_000_000, 250_000_000));
+    assert!(if_range_matches(true, Some(&header::IfRange::Date(modified)), &etag, modified_nanos));
+}
@@ -1,5 +1,7 @@
 //! Canned responses.
 
+use std::time::Duration;
+
 use hyper::{header, Response, StatusCode};
 
 pub fn bad_request(msg: &'static str) -> Response {
@@ -29,6 +31,34 @@ pub fn bad_gateway() -> Response {
         .with_body(TEXT)
 }
 
+pub fn gateway_timeout() -> Response {
+    const TEXT: &str = "Gateway timeout";
+    Response::new()
+        .with_status(StatusCode::GatewayTimeout)
+        .with_header(header::ContentLength(TEXT.len() as u64))
+        .with_header(header::ContentType::plaintext())
+        .with_body(TEXT)
+}
+
+pub fn moved_permanently(location: String) -> Response {
+    const TEXT: &str = "Moved permanently";
+    Response::new()
+        .with_status(StatusCode::MovedPermanently)
+        .with_header(header::Location::new(location))
+        .with_header(header::ContentLength(TEXT.len() as u64))
+        .with_header(header::ContentType::plaintext())
+        .with_body(TEXT)
+}
+
+pub fn forbidden() -> Response {
+    const TEXT: &str = "Forbidden";
+    Response::new()
+        .with_status(StatusCode::Forbidden)
+        .with_header(header::ContentLength(TEXT.len() as u64))
+        .with_header(header::ContentType::plaintext())
+        .with_body(TEXT)
+}
+
 pub fn not_found() -> Response {
     const TEXT: &str = "Not found";
     Response::new()
@@ -38,6 +68,27 @@ pub fn not_found() -> Response {
         .with_body(TEXT)
 }
 
+/// Prox stops reading the request body, so the connection can't be reused.
+pub fn payload_too_large() -> Response {
+    const TEXT: &str = "Payload too large";
+    Response::new()
+        .with_status(StatusCode::PayloadTooLarge)
+        .with_header(header::Connection::close())
+        .with_header(header::ContentType::plaintext())
+        .with_header(header::ContentLength(TEXT.len() as u64))
+        .with_body(TEXT)
+}
+
+pub fn service_unavailable(retry_after: Duration) -> Response {
+    const TEXT: &str = "Service unavailable";
+    Response::new()
+        .with_status(StatusCode::ServiceUnavailable)
+        .with_header(header::RetryAfter::Delay(retry_after))
+        .with_header(header::ContentType::plaintext())
+        .with_header(header::ContentLength(TEXT.len() as u64))
+        .with_body(TEXT)
+}
+
 pub fn internal_server_error() -> Response {
     const TEXT: &str = "Internal server error";
     Response::new()
@@ -74,3 +125,10 @@ pub fn invalid_range(entity_len: u64) -> Response {
         .with_header(header::ContentLength(TEXT.len() as u64))
         .with_body(TEXT)
 }
+
+pub fn json(body: String) -> Response {
+    Response::new()
+        .with_header(header::ContentType::json())
+        .with_header(header::ContentLength(body.len() as u64))
+        .with_body(body)
+}
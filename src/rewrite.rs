@@ -0,0 +1,302 @@
+//! Streaming find-and-replace over response bodies.
+
+use std::cmp;
+use std::io::{self, Write};
+use std::mem;
+
+use flate2::{self, write::{GzDecoder, GzEncoder}};
+use futures::{Async, Poll, Stream};
+use hyper::{self, Chunk};
+use regex::{self, bytes::Regex};
+
+/// A single substitution.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    re: Regex,
+    to: Vec<u8>,
+    // Literal rules don't expand "$1"-style references
+    literal: bool,
+    // The longest match that's guaranteed to be found across chunk boundaries
+    max_len: usize,
+}
+
+impl Rule {
+    pub fn literal(from: &str, to: &str) -> Result<Rule, regex::Error> {
+        Ok(Rule {
+            re: Regex::new(&regex::escape(from))?,
+            to: to.as_bytes().to_vec(),
+            literal: true,
+            max_len: from.len(),
+        })
+    }
+
+    /// A regex rule can reference capture groups in its replacement, e.g. "$1".
+    pub fn regex(pattern: &str, to: &str, max_len: usize) -> Result<Rule, regex::Error> {
+        Ok(Rule {
+            re: Regex::new(pattern)?,
+            to: to.as_bytes().to_vec(),
+            literal: false,
+            max_len,
+        })
+    }
+
+    // Writes the replacement for the match that begins at start. The regex is
+    // re-run on the whole buffer so "^" and "\b" see what comes before it.
+    fn replace(&self, haystack: &[u8], start: usize, out: &mut Vec<u8>) {
+        if self.literal {
+            out.extend_from_slice(&self.to);
+        } else if let Some(caps) = self.re.captures_at(haystack, start) {
+            caps.expand(&self.to, out);
+        }
+    }
+}
+
+/// Applies rules to a body that arrives in chunks.
+///
+/// Bytes that could still be the start of a match are held back until the next chunk.
+pub struct Rewriter<'a> {
+    rules: &'a [Rule],
+    pending: Vec<u8>,
+    // How many leading bytes of pending were already sent and are only kept
+    // so "^" and "\b" can look behind the next match
+    context: usize,
+    // How many trailing bytes might be the start of an incomplete match
+    hold: usize,
+}
+
+impl<'a> Rewriter<'a> {
+    pub fn new(rules: &'a [Rule]) -> Self {
+        let hold = rules
+            .iter()
+            .map(|rule| rule.max_len.saturating_sub(1))
+            .max()
+            .unwrap_or(0);
+
+        Rewriter {
+            rules,
+            pending: Vec::new(),
+            context: 0,
+            hold,
+        }
+    }
+
+    /// Feeds a chunk and returns the bytes that are ready to send.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<u8> {
+        self.pending.extend_from_slice(chunk);
+        self.process(false)
+    }
+
+    /// Returns whatever is left once the body has ended.
+    pub fn finish(&mut self) -> Vec<u8> {
+        self.process(true)
+    }
+
+    fn process(&mut self, last: bool) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.pending.len());
+        let mut pos = self.context;
+
+        // Bytes before this point can't be the start of an unseen match
+        let mut safe = if last {
+            self.pending.len()
+        } else {
+            self.pending.len().saturating_sub(self.hold)
+        };
+
+        while let Some((start, end, rule)) = self.next_match(pos) {
+            if start >= safe {
+                break;
+            }
+            // Match might keep going in the next chunk
+            if !last && end > safe && end == self.pending.len() {
+                safe = start;
+                break;
+            }
+            out.extend_from_slice(&self.pending[pos..start]);
+            rule.replace(&self.pending, start, &mut out);
+            pos = end;
+        }
+
+        let safe = cmp::max(pos, safe);
+        out.extend_from_slice(&self.pending[pos..safe]);
+
+        // A UTF-8 character is at most 4 bytes
+        self.context = cmp::min(safe, 4);
+        self.pending.drain(..safe - self.context);
+        out
+    }
+
+    // The leftmost non-empty match at or after pos. Ties go to the earlier rule.
+    fn next_match(&self, pos: usize) -> Option<(usize, usize, &'a Rule)> {
+        let mut best: Option<(usize, usize, &'a Rule)> = None;
+
+        for rule in self.rules {
+            let mut at = pos;
+            while at <= self.pending.len() {
+                match rule.re.find_at(&self.pending, at) {
+                    Some(m) if m.start() == m.end() => at = m.end() + 1,
+                    Some(m) => {
+                        if best.map(|(start, _, _)| m.start() < start).unwrap_or(true) {
+                            best = Some((m.start(), m.end(), rule));
+                        }
+                        break;
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        best
+    }
+}
+
+/// Rewrites a streamed body, gunzipping and re-gzipping it if needed.
+pub struct RewriteBody<S> {
+    stream: S,
+    rewriter: Rewriter<'static>,
+    decoder: Option<GzDecoder<Vec<u8>>>,
+    encoder: Option<GzEncoder<Vec<u8>>>,
+    done: bool,
+}
+
+impl<S> RewriteBody<S> {
+    pub fn new(stream: S, rules: &'static [Rule], gzipped: bool) -> Self {
+        RewriteBody {
+            stream,
+            rewriter: Rewriter::new(rules),
+            decoder: if gzipped {
+                Some(GzDecoder::new(Vec::new()))
+            } else {
+                None
+            },
+            encoder: if gzipped {
+                Some(GzEncoder::new(Vec::new(), flate2::Compression::new(1)))
+            } else {
+                None
+            },
+            done: false,
+        }
+    }
+
+    fn transform(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        let plain = match self.decoder {
+            None => chunk.to_vec(),
+            Some(ref mut decoder) => {
+                decoder.write_all(chunk)?;
+                mem::take(decoder.get_mut())
+            }
+        };
+        let rewritten = self.rewriter.push(&plain);
+        self.encode(rewritten)
+    }
+
+    fn finish(&mut self) -> io::Result<Vec<u8>> {
+        let plain = match self.decoder {
+            None => Vec::new(),
+            Some(ref mut decoder) => {
+                decoder.try_finish()?;
+                mem::take(decoder.get_mut())
+            }
+        };
+        let mut rewritten = self.rewriter.push(&plain);
+        rewritten.extend(self.rewriter.finish());
+        let mut out = self.encode(rewritten)?;
+        if let Some(encoder) = self.encoder.take() {
+            out.extend(encoder.finish()?);
+        }
+        Ok(out)
+    }
+
+    fn encode(&mut self, bytes: Vec<u8>) -> io::Result<Vec<u8>> {
+        match self.encoder {
+            None => Ok(bytes),
+            Some(ref mut encoder) => {
+                encoder.write_all(&bytes)?;
+                Ok(mem::take(encoder.get_mut()))
+            }
+        }
+    }
+}
+
+impl<S> Stream for RewriteBody<S>
+where
+    S: Stream<Item = Chunk, Error = hyper::Error>,
+{
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        loop {
+            if self.done {
+                return Ok(Async::Ready(None));
+            }
+
+            let out = match self.stream.poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(Some(chunk)) => self.transform(&chunk)?,
+                Async::Ready(None) => {
+                    self.done = true;
+                    self.finish()?
+                }
+            };
+
+            // Everything might have been held back
+            if !out.is_empty() {
+                return Ok(Async::Ready(Some(Chunk::from(out))));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_rewriter_across_chunks() {
+    let rules = vec![Rule::literal("http://127.0.0.1:4001/", "/legacy/").unwrap()];
+    let mut rewriter = Rewriter::new(&rules);
+
+    let mut out = Vec::new();
+    out.extend(rewriter.push(b"<a href=\"http://127.0"));
+    out.extend(rewriter.push(b".0.1:4001/login\">http://127.0.0.1:4001/</a>"));
+    out.extend(rewriter.finish());
+
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "<a href=\"/legacy/login\">/legacy/</a>"
+    );
+}
+
+#[test]
+fn test_rewriter_regex() {
+    let rules = vec![
+        Rule::regex(r"https?://old\.example\.com(/\w*)", "https://example.com$1", 64).unwrap(),
+    ];
+    let mut rewriter = Rewriter::new(&rules);
+
+    let mut out = Vec::new();
+    for chunk in &[&b"a http://old.exa"[..], b"mple.com/foo b", b" https://old.example.com/"] {
+        out.extend(rewriter.push(chunk));
+    }
+    out.extend(rewriter.finish());
+
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "a https://example.com/foo b https://example.com/"
+    );
+}
+
+#[test]
+fn test_rewriter_regex_context() {
+    // "\B" only holds inside a word, so the match must keep its left context
+    let rules = vec![Rule::regex(r"\B(o+)", "[$1]", 8).unwrap()];
+    let mut rewriter = Rewriter::new(&rules);
+
+    let mut out = rewriter.push(b"foo oops");
+    out.extend(rewriter.finish());
+
+    assert_eq!(String::from_utf8(out).unwrap(), "f[oo] o[o]ps");
+}
+
+#[test]
+fn test_rewriter_no_rules() {
+    let mut rewriter = Rewriter::new(&[]);
+    assert_eq!(rewriter.push(b"abc"), b"abc".to_vec());
+    assert_eq!(rewriter.finish(), Vec::<u8>::new());
+}
@@ -13,6 +13,7 @@ use hyper;
 use boot_message;
 use config::Config;
 use service;
+use state::State;
 
 /// Start server with given configuration.
 ///
@@ -37,6 +38,7 @@ pub fn serve(config: &Config) {
         }
         Box::new(map).leak()
     };
+    let state = Box::new(State::new(config)).leak();
 
     let mut http: Http<Chunk> = Http::new();
     http.sleep_on_errors(true);
@@ -49,6 +51,7 @@ pub fn serve(config: &Config) {
         remote_ip,
         pool,
         handle,
+        state,
     };
 
     let future = listener.incoming().for_each(move |socket| {
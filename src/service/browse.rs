@@ -15,6 +15,7 @@ use path;
 use mime;
 use response;
 use service;
+use state::State;
 use util;
 
 // TODO: Generate ETag, Content-Length
@@ -29,13 +30,14 @@ pub struct Browse {
     pub client: &'static Client<HttpConnector>,
     pub remote_ip: IpAddr,
     pub handle: &'static ::tokio_core::reactor::Handle,
+    pub state: &'static State,
 }
 
 impl Service for Browse {
     type Request = (&'static Site, Request);
     type Response = Response;
     type Error = hyper::Error;
-    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, (site, req): Self::Request) -> Self::Future {
         trace!("[browse] request {} entered", req.uri());
@@ -44,6 +46,7 @@ impl Service for Browse {
         let client = self.client;
         let remote_ip = self.remote_ip;
         let handle = self.handle;
+        let state = self.state;
 
         let next = move || service::serve::Serve {
             config,
@@ -51,16 +54,20 @@ impl Service for Browse {
             client,
             remote_ip,
             handle,
+            state,
         };
 
         // Short-circuit if root or browse opts are not set
-        let (root, dotfiles) = match &site.serve {
-            None => return next().call((site, req)),
+        let (root, dotfiles, index, cache_control) = match &site.serve {
             Some(config::Serve {
                 ref root,
                 ref dotfiles,
+                ref index,
+                ref cache_control,
+                browse: true,
                 ..
-            }) => (root, dotfiles),
+            }) => (root, dotfiles, index, cache_control),
+            _ => return next().call((site, req)),
         };
 
         // Only handle GET, OPTIONS, HEAD
@@ -70,19 +77,35 @@ impl Service for Browse {
             return Box::new(next().call((site, req)));
         }
 
-        let entity_path = match path::get_entity_path(&root, req.path()) {
+        let entity_path = match path::get_entity_path(root, req.path()) {
             None => return Box::new(ok(response::not_found())),
             Some(path) => path,
         };
 
-        let future = Box::new(pool.spawn_fn(move || {
-            handle_folder(pool, &root, entity_path.as_path(), &dotfiles)
+        let slashed = req.path().ends_with('/');
+
+        let future = Box::new(pool.spawn_fn(move || -> io::Result<Option<Response>> {
+            // Serve redirects to the slashed url and sends the index file if there is one
+            if entity_path.is_dir() && (!slashed || service::serve::find_index(&entity_path, index).is_some()) {
+                return Ok(None);
+            }
+            let mut res = handle_folder(pool, root, entity_path.as_path(), dotfiles)?;
+
+            // Listings match like their folder's path, e.g. "/docs/"
+            let mut url_path = service::serve::url_path(root, &entity_path);
+            if !url_path.ends_with('/') {
+                url_path.push('/');
+            }
+            service::serve::set_cache_control(res.headers_mut(), cache_control, &url_path);
+
+            Ok(Some(res))
         }));
 
         Box::new(future.then(move |res| {
             match res {
                 // Our handler succeeded, so return its response
-                Ok(res) => Box::new(ok(res)),
+                Ok(Some(res)) => Box::new(ok(res)),
+                Ok(None) => next().call((site, req)),
                 // If not a directory or file not found, then continue to next handler
                 Err(ref e) if e.raw_os_error() == Some(20) => next().call((site, req)),
                 Err(ref e) if e.kind() == io::ErrorKind::NotFound => next().call((site, req)),
@@ -128,12 +151,12 @@ fn handle_folder(
                     .path()
                     .extension()
                     .and_then(|x| x.to_str())
-                    .map(|x| mime::is_image_ext(x))
+                    .map(mime::is_image_ext)
                     .unwrap_or(false);
 
                 let href = format!(
                     "/{}",
-                    entry.path().strip_prefix(&root).unwrap().to_string_lossy()
+                    entry.path().strip_prefix(root).unwrap().to_string_lossy()
                 );
 
                 Some(FolderItem {
@@ -145,7 +168,7 @@ fn handle_folder(
             })
         })
         .filter_map(Result::ok)
-        .filter_map(|item| item)
+        .flatten()
         .collect();
 
     // Sort folders first, and the sort by filename a-z
@@ -163,7 +186,6 @@ fn handle_folder(
         path.strip_prefix(root)
             .unwrap()
             .to_string_lossy()
-            .to_string()
     );
 
     let stream = stream::iter_ok(vec![
@@ -204,8 +226,7 @@ fn handle_folder(
                 .created()
                 .map_err(|_| ())
                 .and_then(|time| time.duration_since(UNIX_EPOCH).map_err(|_| ()))
-                .map(|dur| util::duration_as_millis(dur).to_string())
-                .map_err(|()| ());
+                .map(|dur| util::duration_as_millis(dur).to_string());
             duration.unwrap_or_else(|()| "—".to_string())
         };
 
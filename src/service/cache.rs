@@ -0,0 +1,431 @@
+use std::io;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+use futures::{Future, Stream, future::{err, ok, Either}, sync::oneshot};
+use futures_cpupool::CpuPool;
+use hyper::{self, header, Client, Method, Request, Response, StatusCode, client::HttpConnector,
+            server::Service};
+use tokio_core::reactor::Timeout;
+
+use body;
+use cache::{self, policy, Entry, Role, Store};
+use config::{Config, Site};
+use negotiate;
+use response;
+use service;
+use state::State;
+use streaming::Streaming;
+
+header! {
+    (XCache, "X-Cache") => [String]
+}
+
+type ResponseFuture = Box<dyn Future<Item = Response, Error = hyper::Error>>;
+
+#[derive(Clone, Copy)]
+pub struct Cache {
+    pub config: &'static Config,
+    pub pool: &'static CpuPool,
+    // For downstream,
+    pub client: &'static Client<HttpConnector>,
+    pub remote_ip: IpAddr,
+    pub handle: &'static ::tokio_core::reactor::Handle,
+    pub state: &'static State,
+}
+
+impl Service for Cache {
+    type Request = (&'static Site, Request);
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, (site, req): Self::Request) -> Self::Future {
+        trace!("[cache] request {} entered", req.uri());
+
+        // Short-circuit if caching is disabled
+        let store: &'static Store = match self.state.site(site).cache {
+            None => return self.next().call((site, req)),
+            Some(ref store) => store,
+        };
+
+        let limiters = &self.state.site(site).limiters;
+        if let Some(res) = cache::admin::handle(store, limiters, self.remote_ip, &req) {
+            return Box::new(ok(res));
+        }
+
+        let key = cache::key(&req);
+
+        match *req.method() {
+            Method::Get | Method::Head => {}
+            // Unsafe methods invalidate what we have stored for the uri.
+            // <https://tools.ietf.org/html/rfc7234#section-4.4>
+            Method::Post | Method::Put | Method::Delete | Method::Patch => {
+                store.remove(&key);
+                return self.next().call((site, req));
+            }
+            _ => return self.next().call((site, req)),
+        }
+
+        if policy::is_no_store(req.headers()) {
+            return self.next().call((site, req));
+        }
+
+        self.lookup(site, store, key, req, true)
+    }
+}
+
+impl Cache {
+    fn next(&self) -> service::proxy::Proxy {
+        service::proxy::Proxy {
+            pool: self.pool,
+            client: self.client,
+            remote_ip: self.remote_ip,
+            config: self.config,
+            handle: self.handle,
+            state: self.state,
+        }
+    }
+
+    /// Serves a fresh stored response or goes upstream.
+    ///
+    /// If `coalesce` is set, then concurrent misses for the same key share one upstream fetch.
+    fn lookup(
+        self,
+        site: &'static Site,
+        store: &'static Store,
+        key: String,
+        req: Request,
+        coalesce: bool,
+    ) -> ResponseFuture {
+        let entry = match store.lookup(&key, req.headers()) {
+            None => return self.fetch(site, store, key, req, None, coalesce),
+            Some(entry) => entry,
+        };
+
+        let now = SystemTime::now();
+        let is_stale = !entry.is_fresh(req.headers(), now);
+
+        // A stale entry is either revalidated now, or served as-is while it's
+        // refreshed in the background.
+        if is_stale {
+            let window = store
+                .stale_while_revalidate()
+                .or_else(|| policy::stale_while_revalidate(&entry.headers));
+
+            if !policy::request_allows_stale(req.headers()) || !is_within(window, &entry, now) {
+                return self.fetch(site, store, key, req, Some(entry), coalesce);
+            }
+
+            self.refresh(site, store, key.clone(), &req, entry.clone());
+        }
+
+        let hit = serve_hit(
+            self.pool,
+            store,
+            &key,
+            entry,
+            req.headers().clone(),
+            *req.method() == Method::Head,
+        );
+
+        Box::new(hit.then(move |result| -> ResponseFuture {
+            match result {
+                Ok(res) => Box::new(ok(if is_stale {
+                    with_warning(res, 110, "Response is Stale")
+                } else {
+                    res
+                })),
+                // Stored body went missing, so go get a new one
+                Err(e) => {
+                    error!("failed to read cached body for {}: {}", key, e);
+                    store.remove(&key);
+                    self.fetch(site, store, key, req, None, coalesce)
+                }
+            }
+        }))
+    }
+
+    /// Forwards the request upstream and stores the response if we're allowed to.
+    ///
+    /// If we have a stale entry, then the request is made conditional so that the upstream
+    /// can tell us our copy is still good with a 304.
+    fn fetch(
+        self,
+        site: &'static Site,
+        store: &'static Store,
+        key: String,
+        mut req: Request,
+        stale: Option<Entry>,
+        coalesce: bool,
+    ) -> ResponseFuture {
+        let pool = self.pool;
+        let req_headers = req.headers().clone();
+        let method = req.method().clone();
+
+        // Only GETs are worth sharing since HEAD responses have no body to store.
+        let lock = if coalesce && method == Method::Get {
+            match store.join(&key, &req_headers) {
+                Role::Waiter(rx) => return self.wait(site, store, key, req, rx),
+                Role::Leader(lock) => Some(lock),
+            }
+        } else {
+            None
+        };
+
+        let can_validate = stale.as_ref().map(Entry::has_validator).unwrap_or(false);
+
+        if let Some(entry) = stale.as_ref().filter(|_| can_validate) {
+            if let Some(header::ETag(etag)) = entry.headers.get() {
+                req.headers_mut()
+                    .set(header::IfNoneMatch::Items(vec![etag.clone()]));
+            }
+            if let Some(&header::LastModified(date)) = entry.headers.get() {
+                req.headers_mut().set(header::IfModifiedSince(date));
+            }
+        }
+
+        let stale_if_error = stale.as_ref().and_then(|entry| {
+            store
+                .stale_if_error()
+                .or_else(|| policy::stale_if_error(&req_headers, &entry.headers))
+        });
+
+        let is_head = method == Method::Head;
+        let request_time = SystemTime::now();
+
+        Box::new(self.next().call((site, req)).then(move |result| -> ResponseFuture {
+            let response_time = SystemTime::now();
+
+            // Fall back to our stale copy if the upstream is having trouble.
+            let failed = match result {
+                Ok(ref res) => policy::is_error_status(res.status()),
+                Err(_) => true,
+            };
+
+            if failed {
+                if let Some(entry) = stale
+                    .as_ref()
+                    .filter(|entry| is_within(stale_if_error, entry, response_time))
+                {
+                    warn!("[cache] upstream failed, serving stale {}", key);
+                    let hit = serve_hit(pool, store, &key, entry.clone(), req_headers, is_head)
+                        .map(|res| with_warning(res, 111, "Revalidation Failed"));
+                    return or_internal_server_error(store, key, hit);
+                }
+            }
+
+            let res = match result {
+                Err(e) => return Box::new(err(e)),
+                Ok(res) => res,
+            };
+
+            if can_validate && res.status() == StatusCode::NotModified {
+                if let Some(entry) = stale {
+                    let entry = store
+                        .freshen(&key, &req_headers, res.headers(), request_time, response_time)
+                        .unwrap_or(entry);
+                    // Waiters will find the freshened entry on their own.
+                    drop(lock);
+                    let hit = serve_hit(pool, store, &key, entry, req_headers, is_head);
+                    return or_internal_server_error(store, key, hit);
+                }
+            }
+
+            let status = res.status();
+
+            // A stream may never end, so there'd be nothing to store
+            if !policy::is_storable(&method, &req_headers, status, res.headers())
+                || res.headers().has::<Streaming>()
+            {
+                return Box::new(ok(with_x_cache(res, "MISS")));
+            }
+
+            let headers = res.headers().clone();
+
+            // Hand the response to every waiter that would select the same variant.
+            // The rest are dropped which sends them upstream on their own.
+            let waiters = lock.map(cache::Lock::release)
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|waiter| {
+                    cache::same_variant(&headers, &req_headers, &waiter.req_headers)
+                        && policy::is_storable(&method, &waiter.req_headers, status, &headers)
+                })
+                .collect::<Vec<_>>();
+
+            let stored_headers = headers.clone();
+
+            let tee = body::Tee::new(res.body(), store.max_entry_size(), move |buf| {
+                trace!("[cache] storing {}", key);
+                let fetched = cache::Fetched {
+                    status,
+                    headers: stored_headers,
+                    request_time,
+                    response_time,
+                    body: buf,
+                };
+                store.insert(key, &req_headers, fetched)
+            });
+
+            let mut bodies = body::fanout(pool, tee, waiters.len() + 1);
+
+            for (waiter, body) in waiters.into_iter().zip(bodies.drain(1..)) {
+                let res = Response::new()
+                    .with_status(status)
+                    .with_headers(headers.clone())
+                    .with_body(body);
+                // Waiter may have given up already
+                let _ = waiter.tx.send(with_x_cache(res, "HIT"));
+            }
+
+            let res = Response::new()
+                .with_status(status)
+                .with_headers(headers)
+                .with_body(bodies.remove(0));
+
+            Box::new(ok(with_x_cache(res, "MISS")))
+        }))
+    }
+
+    /// Refreshes a stale entry in the background.
+    fn refresh(
+        self,
+        site: &'static Site,
+        store: &'static Store,
+        key: String,
+        req: &Request,
+        entry: Entry,
+    ) {
+        // Somebody is already on it
+        if store.is_in_flight(&key) {
+            return;
+        }
+
+        trace!("[cache] refreshing {} in the background", key);
+
+        let mut bg_req = Request::new(Method::Get, req.uri().clone());
+        bg_req.headers_mut().extend(req.headers().iter().filter(|h| {
+            !h.is::<header::IfNoneMatch>() && !h.is::<header::IfModifiedSince>()
+                && !h.is::<header::Range>()
+        }));
+
+        // The response is only fetched to be stored, so drain its body.
+        let future = self.fetch(site, store, key, bg_req, Some(entry), true)
+            .and_then(|res| res.body().for_each(|_| Ok(())))
+            .map_err(|e| error!("[cache] background refresh failed: {}", e));
+
+        self.handle.spawn(future);
+    }
+
+    /// Waits for another request's fetch of the same key.
+    ///
+    /// If the other request can't share its response or takes longer than the lock
+    /// timeout, then this request starts over without coalescing.
+    fn wait(
+        self,
+        site: &'static Site,
+        store: &'static Store,
+        key: String,
+        req: Request,
+        rx: oneshot::Receiver<Response>,
+    ) -> ResponseFuture {
+        trace!("[cache] waiting on in-flight request for {}", key);
+
+        let timeout = match Timeout::new(store.lock_timeout(), self.handle) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("error creating timeout: {}", e);
+                return self.lookup(site, store, key, req, false);
+            }
+        };
+
+        Box::new(rx.select2(timeout).then(move |result| -> ResponseFuture {
+            match result {
+                Ok(Either::A((res, _timeout))) => Box::new(ok(res)),
+                Ok(Either::B(_)) => {
+                    trace!("[cache] lock timed out for {}", key);
+                    self.lookup(site, store, key, req, false)
+                }
+                Err(_) => self.lookup(site, store, key, req, false),
+            }
+        }))
+    }
+}
+
+/// Builds a response from a stored entry.
+fn serve_hit(
+    pool: &CpuPool,
+    store: &Store,
+    key: &str,
+    entry: Entry,
+    req_headers: header::Headers,
+    is_head: bool,
+) -> Box<dyn Future<Item = Response, Error = io::Error>> {
+    store.record_hit(key, &req_headers);
+
+    Box::new(pool.spawn_fn(move || -> io::Result<Response> {
+        let body = entry.read_body()?;
+        let now = SystemTime::now();
+
+        // The client might already have our copy
+        if let Some(header::ETag(etag)) = entry.headers.get() {
+            if !negotiate::none_match(req_headers.get::<header::IfNoneMatch>(), etag) {
+                return Ok(with_x_cache(response::not_modified(etag.clone()), "HIT"));
+            }
+        }
+
+        let mut res = Response::new()
+            .with_status(entry.status)
+            .with_headers(entry.headers.clone());
+
+        res.headers_mut()
+            .set(policy::Age(entry.age(now).as_secs() as u32));
+        res.headers_mut()
+            .set(header::ContentLength(body.len() as u64));
+
+        let res = with_x_cache(res, "HIT");
+
+        if is_head {
+            Ok(res)
+        } else {
+            Ok(res.with_body(body))
+        }
+    }))
+}
+
+/// Determines if a stale entry is still within an allowed staleness window.
+fn is_within(window: Option<Duration>, entry: &Entry, now: SystemTime) -> bool {
+    match window {
+        None => false,
+        Some(window) => policy::may_serve_stale(&entry.headers) && entry.staleness(now) < window,
+    }
+}
+
+/// Responds with a 500 if the stored body can't be read.
+fn or_internal_server_error<F>(store: &'static Store, key: String, hit: F) -> ResponseFuture
+where
+    F: Future<Item = Response, Error = io::Error> + 'static,
+{
+    Box::new(hit.or_else(move |e| -> Result<Response, hyper::Error> {
+        error!("failed to read cached body for {}: {}", key, e);
+        store.remove(&key);
+        Ok(response::internal_server_error())
+    }))
+}
+
+/// <https://tools.ietf.org/html/rfc7234#section-5.5>
+fn with_warning(mut res: Response, code: u16, text: &str) -> Response {
+    res.headers_mut().set(header::Warning {
+        code,
+        agent: "prox".to_string(),
+        text: text.to_string(),
+        date: None,
+    });
+    res
+}
+
+fn with_x_cache(mut res: Response, value: &str) -> Response {
+    res.headers_mut().set(XCache(value.to_string()));
+    res
+}
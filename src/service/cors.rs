@@ -8,6 +8,7 @@ use unicase::Ascii;
 
 use config::{self, Config, CorsOrigin, Site};
 use service;
+use state::State;
 use util;
 
 pub struct Cors {
@@ -17,13 +18,14 @@ pub struct Cors {
     pub client: &'static Client<HttpConnector>,
     pub remote_ip: IpAddr,
     pub handle: &'static ::tokio_core::reactor::Handle,
+    pub state: &'static State,
 }
 
 impl Service for Cors {
     type Request = (&'static Site, Request);
     type Response = Response;
     type Error = hyper::Error;
-    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, (site, req): Self::Request) -> Self::Future {
         trace!("[cors] request {} entered", req.uri());
@@ -32,6 +34,7 @@ impl Service for Cors {
         let client = self.client;
         let remote_ip = self.remote_ip;
         let handle = self.handle;
+        let state = self.state;
 
         let next = move || service::browse::Browse {
             config,
@@ -39,6 +42,7 @@ impl Service for Cors {
             client,
             remote_ip,
             handle,
+            state,
         };
 
         // Short-circuit if logging is disabled
@@ -60,7 +64,7 @@ impl Service for Cors {
 
         if *req.method() == Method::Options {
             let mut res = Response::new();
-            util::append_header_vary(&mut res.headers_mut(), Ascii::new("Origin".to_string()));
+            util::append_header_vary(res.headers_mut(), Ascii::new("Origin".to_string()));
             res.headers_mut().set(header::ContentLength(0));
             res.headers_mut()
                 .set(header::ContentType(hyper::mime::TEXT_PLAIN_UTF_8));
@@ -90,7 +94,7 @@ impl Service for Cors {
             let actual_header_keys: Vec<Ascii<String>> =
                 match req.headers().get::<header::AccessControlRequestHeaders>() {
                     None => Vec::new(),
-                    Some(&header::AccessControlRequestHeaders(ref keys)) => keys.to_vec(),
+                    Some(header::AccessControlRequestHeaders(keys)) => keys.to_vec(),
                 };
 
             // Bail if any header isn't in our approved set
@@ -125,7 +129,7 @@ impl Service for Cors {
                 }
 
                 // Always set Vary header if CORS is enabled.
-                util::append_header_vary(&mut res.headers_mut(), Ascii::new("Origin".to_string()));
+                util::append_header_vary(res.headers_mut(), Ascii::new("Origin".to_string()));
 
                 res.headers_mut()
                     .set(header::AccessControlAllowOrigin::Value(format!(
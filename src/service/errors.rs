@@ -0,0 +1,159 @@
+//! Replaces the bodies of error responses with a site's error pages.
+
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+use futures::{Future, future::{ok, Either}};
+use futures_cpupool::CpuPool;
+use hyper::{self, header, Client, Method, Request, Response, client::HttpConnector,
+            mime::Mime, server::Service};
+use serde_json;
+
+use config::{Config, Site};
+use mime;
+use negotiate;
+use service;
+use state::State;
+use streaming::Streaming;
+
+// The proxy marks upstream responses with this so that they can be told apart from
+// prox's own error responses. It never leaves prox.
+header! {
+    (FromUpstream, "X-Prox-Upstream") => [bool]
+}
+
+#[derive(Serialize)]
+struct JsonError<'a> {
+    status: u16,
+    error: &'a str,
+}
+
+pub struct Errors {
+    pub config: &'static Config,
+    pub pool: &'static CpuPool,
+    // For downstream,
+    pub client: &'static Client<HttpConnector>,
+    pub remote_ip: IpAddr,
+    pub handle: &'static ::tokio_core::reactor::Handle,
+    pub state: &'static State,
+}
+
+impl Service for Errors {
+    type Request = (&'static Site, Request);
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, (site, req): Self::Request) -> Self::Future {
+        let pool = self.pool;
+
+        let next = service::log::Log {
+            config: self.config,
+            pool: self.pool,
+            client: self.client,
+            remote_ip: self.remote_ip,
+            handle: self.handle,
+            state: self.state,
+        };
+
+        let is_head = *req.method() == Method::Head;
+        let wants_json =
+            site.json_errors && negotiate::prefers_json(req.headers().get::<header::Accept>());
+
+        Box::new(next.call((site, req)).and_then(move |mut res| {
+            let from_upstream = res.headers().has::<FromUpstream>();
+            res.headers_mut().remove::<FromUpstream>();
+            res.headers_mut().remove::<Streaming>();
+
+            let is_error = res.status().is_client_error() || res.status().is_server_error();
+
+            if !is_error || (from_upstream && !site.intercept_errors) {
+                return Either::A(ok(res));
+            }
+
+            if wants_json {
+                return Either::A(ok(json_error(res, is_head)));
+            }
+
+            match site.error_pages.get(&res.status().as_u16()) {
+                None => Either::A(ok(res)),
+                Some(path) => Either::B(error_page(pool, path, res, is_head)),
+            }
+        }))
+    }
+}
+
+fn error_page(
+    pool: &CpuPool,
+    path: &'static Path,
+    res: Response,
+    is_head: bool,
+) -> impl Future<Item = Response, Error = hyper::Error> {
+    pool.spawn_fn(move || fs::read(path)).then(move |result| match result {
+        Ok(body) => {
+            let content_type = mime::guess_mime_by_path(path).mime;
+            Ok(replace_body(res, content_type, body, is_head))
+        }
+        Err(e) => {
+            // Better to send the original body than nothing
+            error!("failed to read error page {:?}: {}", path, e);
+            Ok(res)
+        }
+    })
+}
+
+fn json_error(res: Response, is_head: bool) -> Response {
+    let status = res.status();
+    let body = serde_json::to_vec(&JsonError {
+        status: status.as_u16(),
+        error: status.canonical_reason().unwrap_or("Error"),
+    }).expect("error body always serializes");
+
+    replace_body(res, hyper::mime::APPLICATION_JSON, body, is_head)
+}
+
+// Keeps headers like Retry-After, Allow and WWW-Authenticate but drops the ones
+// that described the old body.
+fn replace_body(res: Response, content_type: Mime, body: Vec<u8>, is_head: bool) -> Response {
+    let mut headers = res.headers().clone();
+    headers.remove::<header::ContentEncoding>();
+    headers.remove::<header::TransferEncoding>();
+    headers.remove::<header::ETag>();
+    headers.remove::<header::LastModified>();
+    headers.set(header::ContentType(content_type));
+    headers.set(header::ContentLength(body.len() as u64));
+
+    let res = Response::new().with_status(res.status()).with_headers(headers);
+
+    // For HEAD requests, we do all the work except sending the body.
+    if is_head {
+        res
+    } else {
+        res.with_body(body)
+    }
+}
+
+#[test]
+fn test_replace_body() {
+    let res = Response::new()
+        .with_status(hyper::StatusCode::ServiceUnavailable)
+        .with_header(header::RetryAfter::Delay(::std::time::Duration::from_secs(5)))
+        .with_header(header::ContentEncoding(vec![header::Encoding::Gzip]))
+        .with_header(header::ContentLength(3));
+    let res = json_error(res, false);
+
+    assert_eq!(res.status(), hyper::StatusCode::ServiceUnavailable);
+    assert!(res.headers().has::<header::RetryAfter>());
+    assert!(!res.headers().has::<header::ContentEncoding>());
+    assert_eq!(
+        res.headers().get::<header::ContentType>(),
+        Some(&header::ContentType::json())
+    );
+
+    let body = r#"{"status":503,"error":"Service Unavailable"}"#;
+    assert_eq!(
+        res.headers().get::<header::ContentLength>(),
+        Some(&header::ContentLength(body.len() as u64))
+    );
+}
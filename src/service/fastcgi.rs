@@ -0,0 +1,73 @@
+use std::io;
+use std::net::IpAddr;
+
+use futures::{Future, Stream, future::ok};
+use hyper::{self, Request, Response, server::Service};
+
+use cgi::{self, Output};
+use config::{Config, Site};
+use fastcgi;
+use response;
+use state::State;
+
+pub struct FastCgi {
+    pub remote_ip: IpAddr,
+    pub config: &'static Config,
+    pub state: &'static State,
+}
+
+impl Service for FastCgi {
+    type Request = (&'static Site, Request);
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, (site, req): Self::Request) -> Self::Future {
+        let (opts, root, pool) = match (&site.fastcgi, &site.serve, &self.state.site(site).fastcgi) {
+            (Some(opts), Some(serve), Some(pool)) => (opts, &serve.root, pool),
+            _ => return Box::new(ok(response::not_found())),
+        };
+
+        let remote_ip = self.remote_ip;
+        let port = self.config.server.bind.port();
+        let timeout = self.config.server.timeouts.connect;
+
+        let (mut output, head) = Output::new();
+
+        // Talking to the responder blocks, so it happens on the site's own pool
+        pool.spawn_fn(move || {
+            let script = match fastcgi::resolve(root, req.path(), opts) {
+                None => {
+                    output.respond(response::not_found());
+                    return Ok(());
+                }
+                Some(x) => x,
+            };
+
+            let mut params = cgi::meta_variables(&req, remote_ip, port, root, &script);
+            for (name, value) in &opts.params {
+                params.retain(|(existing, _)| existing != name);
+                params.push((name.clone(), value.clone()));
+            }
+
+            let body = req.body()
+                .wait()
+                .map(|chunk| chunk.map_err(io::Error::other));
+
+            let result = fastcgi::connect(&opts.address, timeout, opts.timeout)
+                .and_then(|mut conn| fastcgi::run(&mut conn, &params, body, |data| output.write(data)));
+
+            // Other failures get a 502 when the output is dropped
+            if let Err(e) = result {
+                error!("[fastcgi] error running {}: {}", script.name, e);
+                if fastcgi::is_timeout(&e) {
+                    output.respond(response::gateway_timeout());
+                }
+            }
+
+            Ok::<(), ()>(())
+        }).forget();
+
+        Box::new(head.then(|res| Ok(res.unwrap_or_else(|_| response::bad_gateway()))))
+    }
+}
@@ -1,17 +1,23 @@
 use std::net::IpAddr;
 
-use flate2;
-use futures::Future;
-use futures::{Sink, Stream};
+use std::io;
+use std::mem;
+
+use futures::{future, stream, Async, Future, Poll, Stream};
 use futures_cpupool::CpuPool;
-use hyper::{self, header, Body, Client, Method, Request, Response, client::HttpConnector,
-            server::Service};
+use hyper::{self, header, Chunk, Client, Method, Request, Response, StatusCode,
+            client::HttpConnector, server::Service};
 use unicase::Ascii;
 
+use body;
+use compress::{Coding, Transcode};
 use config::{self, Config, Site};
 use mime;
 use negotiate;
+use response;
 use service;
+use state::State;
+use streaming;
 use util;
 
 pub struct Gzip {
@@ -21,13 +27,14 @@ pub struct Gzip {
     pub client: &'static Client<HttpConnector>,
     pub remote_ip: IpAddr,
     pub handle: &'static ::tokio_core::reactor::Handle,
+    pub state: &'static State,
 }
 
 impl Service for Gzip {
     type Request = (&'static Site, Request);
     type Response = Response;
     type Error = hyper::Error;
-    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, (site, req): Self::Request) -> Self::Future {
         trace!("[gzip] request {} entered", req.uri());
@@ -36,6 +43,7 @@ impl Service for Gzip {
         let client = self.client;
         let remote_ip = self.remote_ip;
         let handle = self.handle;
+        let state = self.state;
 
         let next = move || service::cors::Cors {
             config,
@@ -43,6 +51,7 @@ impl Service for Gzip {
             client,
             remote_ip,
             handle,
+            state,
         };
 
         let opts = match site.gzip {
@@ -56,99 +65,352 @@ impl Service for Gzip {
         }
 
         let req_accept_encoding = req.headers().get::<header::AcceptEncoding>().cloned();
+        let is_head = *req.method() == Method::Head;
 
         Box::new(
             next()
                 .call((site, req))
-                .map(move |res| handle_response(pool, res, opts, &req_accept_encoding)),
+                .and_then(move |res| handle_response(pool, res, opts, &req_accept_encoding, is_head)),
         )
     }
 }
 
 fn handle_response(
-    pool: &CpuPool,
+    pool: &'static CpuPool,
     mut res: Response,
-    opts: &config::Gzip,
+    opts: &'static config::Gzip,
     req_accept_encoding: &Option<header::AcceptEncoding>,
-) -> Response {
+    is_head: bool,
+) -> Box<dyn Future<Item = Response, Error = hyper::Error>> {
     // Only compress if successful response
     if !res.status().is_success() {
-        return res;
+        return Box::new(future::ok(res));
     }
 
-    let mime = match res.headers().get::<header::ContentType>() {
-        None => return res,
-        Some(&header::ContentType(ref mime)) => mime,
-    };
+    // A range of the body is only a range of the uncompressed one
+    if res.status() == StatusCode::PartialContent {
+        return Box::new(future::ok(res));
+    }
+
+    let no_transform = res.headers()
+        .get::<header::CacheControl>()
+        .map(|header::CacheControl(directives)| directives.contains(&header::CacheDirective::NoTransform))
+        .unwrap_or(false);
+    if no_transform {
+        return Box::new(future::ok(res));
+    }
+
+    // Compressing would hold chunks back from a client that's waiting on each of them
+    if streaming::is_streaming(res.headers()) {
+        return Box::new(future::ok(res));
+    }
+
+    // Already compressed, e.g. by the upstream or a precompressed file
+    if res.headers().has::<header::ContentEncoding>() {
+        let coding = match upstream_coding(res.headers()) {
+            Some(coding) if opts.decompress && !negotiate::accepts(req_accept_encoding.as_ref(), coding) => coding,
+            _ => return Box::new(future::ok(res)),
+        };
+        res = match decompress(pool, res, coding, is_head) {
+            Ok(res) => res,
+            Err(e) => {
+                error!("error creating {} decoder: {}", coding.token(), e);
+                return Box::new(future::ok(response::internal_server_error()));
+            }
+        };
+    }
 
-    // We'll consider an entity over the size threshold if their size is unknown.
-    let over_threshold = match res.headers().get::<header::ContentLength>() {
-        None => true,
-        Some(&header::ContentLength(length)) => length >= opts.threshold,
+    let compressible = match res.headers().get::<header::ContentType>() {
+        None => false,
+        Some(header::ContentType(mime)) => mime::is_mime_compressible(mime),
     };
+    if !compressible {
+        return Box::new(future::ok(res));
+    }
 
-    let should_compress = mime::is_mime_compressible(mime) && over_threshold && {
-        let encoding = negotiate::encoding(req_accept_encoding.as_ref());
-        encoding == Some(header::Encoding::Gzip)
+    let coding = match negotiate::encoding(req_accept_encoding.as_ref(), &opts.encodings) {
+        None => return Box::new(future::ok(res)),
+        Some(coding) => coding,
     };
 
-    if !should_compress {
-        return res;
+    // TODO: Would be nicer to have something like:
+    //
+    //     let (head, body) = response.split();
+    //     let response = Response::join(head, transform(body))
+    let status = res.status();
+    let mut headers = res.headers().clone();
+
+    match headers.get::<header::ContentLength>().cloned() {
+        Some(header::ContentLength(length)) => {
+            if length < opts.threshold {
+                return Box::new(future::ok(res));
+            }
+            Box::new(future::ok(compress(pool, status, headers, res.body(), coding, opts)))
+        }
+        // A HEAD response has no body to measure, so it says what a GET would get
+        None if is_head => Box::new(future::ok(compress(pool, status, headers, res.body(), coding, opts))),
+        // Read up to the threshold before deciding when the size is unknown
+        None => Box::new(Peek::new(res.body(), opts.threshold).map(move |(chunks, rest)| {
+            let length: usize = chunks.iter().map(|chunk| chunk.len()).sum();
+            match rest {
+                None if (length as u64) < opts.threshold => {
+                    headers.set(header::ContentLength(length as u64));
+                    headers.remove::<header::TransferEncoding>();
+                    let body = chunks.iter().fold(Vec::with_capacity(length), |mut body, chunk| {
+                        body.extend_from_slice(chunk);
+                        body
+                    });
+                    Response::new()
+                        .with_status(status)
+                        .with_headers(headers)
+                        .with_body(body)
+                }
+                rest => {
+                    let body = stream::iter_ok(chunks).chain(rest.unwrap_or_default());
+                    compress(pool, status, headers, body, coding, opts)
+                }
+            }
+        })),
     }
+}
+
+/// The coding of a body that was compressed once with a coding that we know.
+fn upstream_coding(headers: &header::Headers) -> Option<Coding> {
+    match headers.get::<header::ContentEncoding>() {
+        Some(header::ContentEncoding(encodings)) if encodings.len() == 1 => {
+            Coding::from_encoding(&encodings[0])
+        }
+        _ => None,
+    }
+}
+
+fn decompress(pool: &CpuPool, res: Response, coding: Coding, is_head: bool) -> io::Result<Response> {
+    let status = res.status();
+    let mut headers = res.headers().clone();
+
+    headers.remove::<header::ContentEncoding>();
+    headers.remove::<header::ContentLength>();
+    // A HEAD response has no body to chunk
+    if !is_head {
+        headers.set(header::TransferEncoding(vec![header::Encoding::Chunked]));
+    }
+
+    // Whether the body is decoded depends on what the client accepts
+    util::append_header_vary(&mut headers, Ascii::new("Accept-Encoding".to_string()));
+
+    // Weaken ETag
+    if let Some(etag) = headers.get::<header::ETag>().cloned() {
+        if !etag.weak {
+            let etag = header::EntityTag::weak(etag.tag().to_string());
+            headers.set::<header::ETag>(header::ETag(etag))
+        }
+    }
+
+    let res_decoded = Response::new().with_status(status).with_headers(headers);
+
+    // There's no body to decode
+    if is_head {
+        return Ok(res_decoded);
+    }
+
+    let decode = Transcode::decode(res.body(), coding)?;
+    Ok(res_decoded.with_body(body::spawn(pool, decode)))
+}
+
+fn compress<S>(
+    pool: &CpuPool,
+    status: StatusCode,
+    mut headers: header::Headers,
+    body: S,
+    coding: Coding,
+    opts: &config::Gzip,
+) -> Response
+where
+    S: Stream<Item = Chunk, Error = hyper::Error> + Send + 'static,
+{
+    let encode = match Transcode::encode(body, coding, opts) {
+        Ok(encode) => encode,
+        Err(e) => {
+            error!("error creating {} encoder: {}", coding.token(), e);
+            return response::internal_server_error();
+        }
+    };
 
     // Remove Content-Length
-    res.headers_mut()
-        .set(header::TransferEncoding(vec![header::Encoding::Chunked]));
-    res.headers_mut().remove::<header::ContentLength>();
+    headers.set(header::TransferEncoding(vec![header::Encoding::Chunked]));
+    headers.remove::<header::ContentLength>();
 
     // Set Content-Encoding
-    res.headers_mut()
-        .set(header::ContentEncoding(vec![header::Encoding::Gzip]));
+    headers.set(header::ContentEncoding(vec![coding.encoding()]));
 
     // Append Accept-Encoding
-    util::append_header_vary(
-        &mut res.headers_mut(),
-        Ascii::new("Accept-Encoding".to_string()),
-    );
+    util::append_header_vary(&mut headers, Ascii::new("Accept-Encoding".to_string()));
 
     // Weaken ETag
-    if let Some(etag) = res.headers().get::<header::ETag>() {
+    if let Some(etag) = headers.get::<header::ETag>().cloned() {
         if !etag.weak {
             let etag = header::EntityTag::weak(etag.tag().to_string());
-            res.headers_mut().set::<header::ETag>(header::ETag(etag))
+            headers.set::<header::ETag>(header::ETag(etag))
         }
     }
 
-    // TODO: Would be nicer to have something like:
-    //
-    //     let (head, body) = response.split();
-    //     let response = Response::join(head, transform(body))
     Response::new()
-        .with_status(res.status())
-        .with_headers(res.headers().clone())
-        .with_body(gzip(pool, flate2::Compression::new(1), res.body()))
+        .with_status(status)
+        .with_headers(headers)
+        .with_body(body::spawn(pool, encode))
+}
+
+/// Reads a body until `threshold` bytes of it have arrived or it ends.
+///
+/// Resolves to the chunks read so far and the rest of the body if there is any.
+struct Peek<S> {
+    body: Option<S>,
+    chunks: Vec<Chunk>,
+    length: u64,
+    threshold: u64,
 }
 
-/// Transforms the body into a new one where all of its chunks will be gzipped.
-//
-// TODO: Convert back to Stream -> Stream transformation when Hyper 0.12.x releases
-// since it'll have Body::wrap_stream().
-pub fn gzip(pool: &CpuPool, level: flate2::Compression, body: Body) -> Body {
-    use flate2::write::GzEncoder;
-    use std::io::Write;
+impl<S> Peek<S> {
+    fn new(body: S, threshold: u64) -> Self {
+        Peek {
+            body: Some(body),
+            chunks: Vec::new(),
+            length: 0,
+            threshold,
+        }
+    }
+}
 
-    let stream = body.and_then(move |chunk| {
-        let mut encoder = GzEncoder::new(Vec::new(), level);
-        encoder
-            .write_all(&chunk)
-            .and_then(|_| encoder.finish())
-            .map(|vec| vec.into())
-            .map_err(|e| e.into())
-    });
+impl<S> Future for Peek<S>
+where
+    S: Stream<Item = Chunk, Error = hyper::Error>,
+{
+    type Item = (Vec<Chunk>, Option<S>);
+    type Error = hyper::Error;
 
-    let (tx, body) = Body::pair();
+    fn poll(&mut self) -> Poll<Self::Item, hyper::Error> {
+        loop {
+            if self.length >= self.threshold {
+                let chunks = mem::take(&mut self.chunks);
+                return Ok(Async::Ready((chunks, self.body.take())));
+            }
 
-    pool.spawn(tx.send_all(stream.then(Ok))).forget();
+            let next = match self.body {
+                Some(ref mut body) => match body.poll()? {
+                    Async::NotReady => return Ok(Async::NotReady),
+                    Async::Ready(next) => next,
+                },
+                None => None,
+            };
+            match next {
+                Some(chunk) => {
+                    self.length += chunk.len() as u64;
+                    self.chunks.push(chunk);
+                }
+                None => {
+                    let chunks = mem::take(&mut self.chunks);
+                    return Ok(Async::Ready((chunks, None)));
+                }
+            }
+        }
+    }
+}
 
-    body
+#[test]
+fn test_peek() {
+    let body = |chunks: &[&'static str]| {
+        let chunks = chunks.iter().map(|&chunk| Chunk::from(chunk)).collect::<Vec<_>>();
+        stream::iter_ok::<_, hyper::Error>(chunks)
+    };
+
+    // Ends before the threshold
+    let (chunks, rest) = Peek::new(body(&["ab", "c"]), 10).wait().unwrap();
+    assert_eq!(chunks.len(), 2);
+    assert!(rest.is_none());
+
+    // Stops reading once it's over the threshold
+    let (chunks, rest) = Peek::new(body(&["abcd", "efgh", "ijkl"]), 6).wait().unwrap();
+    assert_eq!(chunks.len(), 2);
+    let rest = rest.unwrap().collect().wait().unwrap();
+    assert_eq!(rest.len(), 1);
+    assert_eq!(&rest[0][..], b"ijkl");
+}
+
+#[test]
+fn test_handle_response() {
+    use std::io::Read;
+
+    use flate2;
+    use leak::Leak;
+    use zstd;
+
+    let pool = Box::new(CpuPool::new(1)).leak();
+    let opts = Box::new(config::Gzip {
+        threshold: 0,
+        ..config::Gzip::default()
+    }).leak();
+    let accept = |value: &str| {
+        use hyper::header::{Header, Raw};
+        Some(header::AcceptEncoding::parse_header(&Raw::from(value)).unwrap())
+    };
+    let text = || {
+        Response::new()
+            .with_header(header::ContentType("text/plain".parse().unwrap()))
+            .with_body("Hello, world!")
+    };
+    let encoding = |res: &Response| res.headers().get::<header::ContentEncoding>().cloned();
+
+    let res = handle_response(pool, text(), opts, &accept("gzip"), false).wait().unwrap();
+    assert_eq!(encoding(&res), Some(header::ContentEncoding(vec![header::Encoding::Gzip])));
+
+    // Ranges, no-transform and encoded responses are left alone
+    let res = text().with_status(StatusCode::PartialContent);
+    let res = handle_response(pool, res, opts, &accept("gzip"), false).wait().unwrap();
+    assert_eq!(encoding(&res), None);
+
+    let res = text().with_header(header::CacheControl(vec![header::CacheDirective::NoTransform]));
+    let res = handle_response(pool, res, opts, &accept("gzip"), false).wait().unwrap();
+    assert_eq!(encoding(&res), None);
+
+    let gzipped = || {
+        let mut body = Vec::new();
+        flate2::read::GzEncoder::new(&b"Hello, world!"[..], flate2::Compression::default())
+            .read_to_end(&mut body)
+            .unwrap();
+        text()
+            .with_header(header::ContentEncoding(vec![header::Encoding::Gzip]))
+            .with_body(body)
+    };
+    let res = handle_response(pool, gzipped(), opts, &accept("identity"), false).wait().unwrap();
+    assert_eq!(encoding(&res), Some(header::ContentEncoding(vec![header::Encoding::Gzip])));
+
+    // Decompressed for clients that can't take the upstream's coding, then recompressed
+    let opts = Box::new(config::Gzip {
+        decompress: true,
+        ..opts.clone()
+    }).leak();
+    let res = handle_response(pool, gzipped(), opts, &accept("identity"), false).wait().unwrap();
+    assert_eq!(encoding(&res), None);
+    assert_eq!(
+        res.headers().get::<header::Vary>(),
+        Some(&header::Vary::Items(vec!["Accept-Encoding".parse().unwrap()]))
+    );
+    let body = res.body().concat2().wait().unwrap();
+    assert_eq!(&body[..], b"Hello, world!");
+
+    let res = handle_response(pool, gzipped(), opts, &accept("zstd"), false).wait().unwrap();
+    assert_eq!(encoding(&res), Some(header::ContentEncoding(vec![Coding::Zstd.encoding()])));
+    let body = res.body().concat2().wait().unwrap();
+    assert_eq!(zstd::stream::decode_all(&body[..]).unwrap(), b"Hello, world!");
+
+    // The upstream's Vary is kept, and a HEAD response isn't chunked
+    let res = gzipped().with_header(header::Vary::Items(vec!["Cookie".parse().unwrap()]));
+    let res = handle_response(pool, res, opts, &accept("identity"), true).wait().unwrap();
+    assert_eq!(encoding(&res), None);
+    assert_eq!(
+        res.headers().get::<header::Vary>(),
+        Some(&header::Vary::Items(vec!["Cookie".parse().unwrap(), "Accept-Encoding".parse().unwrap()]))
+    );
+    assert!(!res.headers().has::<header::TransferEncoding>());
+    assert!(!res.headers().has::<header::ContentLength>());
 }
@@ -7,6 +7,8 @@ use hyper::{self, header, Client, Request, Response, client::HttpConnector, serv
 
 use config::{self, Config, Site};
 use service;
+use split::ChosenVariant;
+use state::State;
 
 pub struct Log {
     pub config: &'static Config,
@@ -15,13 +17,14 @@ pub struct Log {
     pub client: &'static Client<HttpConnector>,
     pub remote_ip: IpAddr,
     pub handle: &'static ::tokio_core::reactor::Handle,
+    pub state: &'static State,
 }
 
 impl Service for Log {
     type Request = (&'static Site, Request);
     type Response = Response;
     type Error = hyper::Error;
-    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, (site, req): Self::Request) -> Self::Future {
         let config = self.config;
@@ -29,6 +32,7 @@ impl Service for Log {
         let client = self.client;
         let remote_ip = self.remote_ip;
         let handle = self.handle;
+        let state = self.state;
 
         let next = move || service::gzip::Gzip {
             config,
@@ -36,6 +40,7 @@ impl Service for Log {
             client,
             remote_ip,
             handle,
+            state,
         };
 
         // Short-circuit if logging is disabled
@@ -67,24 +72,28 @@ pub fn log(remote_ip: ::std::net::IpAddr, opts: &config::Log, req: &Request, res
     let remote_host = Some(remote_ip);
     let method = format!("{}", req.method());
     let path = req.path();
-    let query = req.query().unwrap_or_else(|| "");
+    let query = req.query().unwrap_or("");
     let url = if query.is_empty() {
-        format!("{}", path)
+        path.to_string()
     } else {
         format!("{}?{}", path, query)
     };
     let proto = format!("{}", req.version());
     let status = format!("{}", res.status().as_u16());
+    let variant = match req.headers().get::<ChosenVariant>() {
+        Some(ChosenVariant(name)) => name.as_str(),
+        None => "-",
+    };
 
     // TODO: Send actual transferred byte count somehow, not entity length
-    let bytes_tx = if let Some(&header::ContentLength(ref n)) = res.headers().get() {
+    let bytes_tx = if let Some(header::ContentLength(n)) = res.headers().get() {
         *n
     } else {
         0
     };
 
     let line = opts.format
-        .replace(":remote_host", &remote_host.map(|x| format!("{}", x)) .unwrap_or_else(|| "".to_string()))
+        .replace(":remote_host", &remote_host.map(|x| format!("{}", x)) .unwrap_or_default())
 //        .replace(":remote_port", &remote_port .map(|x| format!("{}", x)) .unwrap_or_else (|| "".to_string()))
         .replace(":date_clf", &format!("{}", now.format(date_formats::CLF)))
         .replace(":date_iso8601", &format!("{}", now.format(date_formats::ISO_8601_UTC)))
@@ -93,6 +102,7 @@ pub fn log(remote_ip: ::std::net::IpAddr, opts: &config::Log, req: &Request, res
         .replace(":url", &url)
         .replace(":proto", &proto)
         .replace(":status", &status)
+        .replace(":variant", variant)
         .replace(":bytes_tx", &format!("{}", bytes_tx));
 
     //    match opts.output {
@@ -102,14 +112,14 @@ pub fn log(remote_ip: ::std::net::IpAddr, opts: &config::Log, req: &Request, res
     println!("{}", line)
 }
 
-pub static COMMON_LOG_FORMAT: &'static str =
+pub static COMMON_LOG_FORMAT: &str =
     ":remote_host - - [:date_clf] \":method :url :proto\" :status :bytes_tx";
 
 #[allow(dead_code)]
 mod date_formats {
-    pub static CLF: &'static str = "%d/%b/%Y:%H:%M:%S %z";
+    pub static CLF: &str = "%d/%b/%Y:%H:%M:%S %z";
     // ISO-8601, e.g. javascript's new Date().toISOString()
-    pub static ISO_8601_UTC: &'static str = "%Y-%m-%dT%H:%M:%S%.3fZ";
+    pub static ISO_8601_UTC: &str = "%Y-%m-%dT%H:%M:%S%.3fZ";
     // When offset from UTC != 0, then the offset is displayed instead of "Z".
-    pub static ISO_8601_OFFSET: &'static str = "%Y-%m-%dT%H:%M:%S%.3f%:z";
+    pub static ISO_8601_OFFSET: &str = "%Y-%m-%dT%H:%M:%S%.3f%:z";
 }
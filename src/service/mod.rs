@@ -1,5 +1,8 @@
 pub mod browse;
+pub mod cache;
 pub mod cors;
+pub mod errors;
+pub mod fastcgi;
 pub mod gzip;
 pub mod log;
 pub mod proxy;
@@ -3,23 +3,40 @@ use std::net::IpAddr;
 
 use futures::{Future, future::ok};
 use futures::future::Either;
-use hyper::{self, header, Client, Request, Response, Uri, client::HttpConnector, server::Service};
+use futures_cpupool::CpuPool;
+use hyper::{self, header, Client, Method, Request, Response, StatusCode, Uri, client::HttpConnector,
+            server::Service};
 use tokio_core::reactor::Handle;
 use tokio_core::reactor::Timeout;
+use url::Url;
 
-use config::{Config, Site};
+use balance::ChosenTarget;
+use body;
+use config::{self, Config, Site, SubFilter};
+use header_rewrite;
 use hop;
+use mirror;
+use queue::{Hold, Permit, Rejected};
 use response;
+use rewrite::RewriteBody;
+use service;
+use service::errors::FromUpstream;
+use split::{self, ChosenVariant};
+use streaming::{self, Idle, Streaming};
+use state::State;
+use util;
 
 header! {
     (XForwardedFor, "X-Forwarded-For") => (IpAddr)+
 }
 
 pub struct Proxy {
+    pub pool: &'static CpuPool,
     pub client: &'static Client<HttpConnector>,
     pub remote_ip: IpAddr,
     pub config: &'static Config,
     pub handle: &'static Handle,
+    pub state: &'static State,
 }
 
 /// Return a new headers map with any hop-to-hop headers removed.
@@ -34,6 +51,8 @@ fn make_proxy_request(mut req: Request, uri: Uri, remote_ip: IpAddr) -> Request
     req.set_uri(uri);
 
     *req.headers_mut() = without_hop_headers(req.headers());
+    req.headers_mut().remove::<ChosenVariant>();
+    req.headers_mut().remove::<ChosenTarget>();
 
     // Update forwarded-for header
     match req.headers_mut().get_mut::<XForwardedFor>() {
@@ -44,83 +63,307 @@ fn make_proxy_request(mut req: Request, uri: Uri, remote_ip: IpAddr) -> Request
     req
 }
 
-fn make_proxy_response(mut res: Response) -> Response {
+fn make_proxy_response(mut res: Response, site: &Site, site_url: &Url, public: Option<&Url>) -> Response {
+    res.headers_mut().remove::<Streaming>();
+    let is_streaming = streaming::is_streaming(res.headers()) || streaming::is_unbounded(res.headers());
+
     *res.headers_mut() = without_hop_headers(res.headers());
+    header_rewrite::rewrite(&site.rewrite_headers, site_url, public, res.headers_mut());
+    res.headers_mut().set(FromUpstream(true));
+    if is_streaming {
+        res.headers_mut().set(Streaming(true));
+    }
     res
 }
 
+/// Passes a streaming response through as it arrives until the upstream goes quiet.
+fn stream_body(handle: &Handle, opts: &'static config::Streaming, res: Response) -> Response {
+    let event_stream = streaming::is_event_stream(res.headers());
+    let res_headers = res.headers().clone();
+    let status = res.status();
+
+    match Idle::new(res.body(), opts, event_stream, handle) {
+        Ok(idle) => Response::new()
+            .with_status(status)
+            .with_headers(res_headers)
+            .with_body(body::spawn_local(handle, idle)),
+        Err(e) => {
+            error!("error creating timeout: {}", e);
+            response::internal_server_error()
+        }
+    }
+}
+
+/// The scheme and host the client used to reach us.
+///
+/// A TLS-terminating proxy in front of prox can say it was https with `X-Forwarded-Proto`.
+fn public_url(req: &Request) -> Option<Url> {
+    let host = req.headers().get::<header::Host>()?;
+    let scheme = match req.headers().get_raw("X-Forwarded-Proto").and_then(|raw| raw.one()) {
+        Some(proto) if proto.eq_ignore_ascii_case(b"https") => "https",
+        _ => "http",
+    };
+    Url::parse(&format!("{}://{}", scheme, host)).ok()
+}
+
+/// Concatenates the site url and the request's path and query into the upstream uri.
+fn upstream_uri(site_url: &Url, uri: &Uri) -> Option<Uri> {
+    site_url
+        .join(&util::path_and_query(uri))
+        .ok()
+        .and_then(|url| url.to_string().parse::<Uri>().ok())
+}
+
+/// Runs the site's sub_filter rules over the body if its content type matches.
+///
+/// Only full 200 bodies are rewritten. HEAD, 204 and 304 responses have no body, and
+/// rewriting a 206 would corrupt the range. Bodies in an encoding other than gzip are
+/// passed through untouched.
+fn rewrite_body(pool: &CpuPool, filter: &'static SubFilter, is_head: bool, mut res: Response) -> Response {
+    if is_head || res.status() != StatusCode::Ok {
+        return res;
+    }
+
+    let matches = match res.headers().get::<header::ContentType>() {
+        None => false,
+        Some(header::ContentType(mime)) => {
+            let essence = format!("{}/{}", mime.type_(), mime.subtype()).to_lowercase();
+            filter.types.contains(&essence)
+        }
+    };
+
+    if !matches || filter.rules.is_empty() {
+        return res;
+    }
+
+    let gzipped = match res.headers().get::<header::ContentEncoding>() {
+        None => false,
+        Some(header::ContentEncoding(encodings)) => match encodings.as_slice() {
+            [] | [header::Encoding::Identity] => false,
+            [header::Encoding::Gzip] => true,
+            _ => return res,
+        },
+    };
+
+    // The new length isn't known until the body has streamed through
+    res.headers_mut().remove::<header::ContentLength>();
+
+    // The body is no longer byte-for-byte what the upstream sent
+    if let Some(etag) = res.headers().get::<header::ETag>().cloned() {
+        if !etag.weak {
+            let etag = header::EntityTag::weak(etag.tag().to_string());
+            res.headers_mut().set::<header::ETag>(header::ETag(etag))
+        }
+    }
+
+    Response::new()
+        .with_status(res.status())
+        .with_headers(res.headers().clone())
+        .with_body(body::spawn(
+            pool,
+            RewriteBody::new(res.body(), &filter.rules, gzipped),
+        ))
+}
+
 impl Service for Proxy {
     type Request = (&'static Site, Request);
     type Response = Response;
     type Error = hyper::Error;
-    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, (site, req): Self::Request) -> Self::Future {
+        // FastCGI sites have a responder instead of an upstream url
+        if site.fastcgi.is_some() {
+            let fastcgi = service::fastcgi::FastCgi {
+                remote_ip: self.remote_ip,
+                config: self.config,
+                state: self.state,
+            };
+            return fastcgi.call((site, req));
+        }
+
         // Proxy only enabled if site.url is given.
         let site_url = match site.url {
             None => return Box::new(ok(response::not_found())),
             Some(ref url) => url,
         };
 
-        // Concatenate site url and request path into target uri
-        let uri = site_url
-            .join(req.path())
-            .ok()
-            .and_then(|url| url.to_string().parse::<Uri>().ok());
+        // Root tagged the request if the site's traffic is split or balanced
+        let target = req.headers().get::<ChosenTarget>().map(|&ChosenTarget(index)| index);
+
+        let site_url = match (&site.split, req.headers().get::<ChosenVariant>(), target) {
+            (Some(opts), Some(ChosenVariant(name)), _) if name.as_str() != split::PRIMARY => {
+                split::url(opts, site_url, name)
+            }
+            (_, _, Some(index)) => &site.targets[index],
+            _ => site_url,
+        };
+
+        // Track the health of the target if there are others to fall back on
+        let balancer = self.state
+            .site(site)
+            .balancer
+            .as_ref()
+            .zip(target);
 
         // Bail if it doesn't parse into a uri
-        let uri = match uri {
+        let uri = match upstream_uri(site_url, req.uri()) {
             Some(x) => x,
             None => return Box::new(ok(response::not_found())),
         };
 
-        let proxy_req = make_proxy_request(req, uri, self.remote_ip);
+        let public = public_url(&req);
+        let is_head = *req.method() == Method::Head;
+        let mut proxy_req = make_proxy_request(req, uri, self.remote_ip);
         trace!("proxy_req: {:#?}", proxy_req);
 
-        // Set up timeouts and make the proxied request
-
         let conn_duration = self.config.server.timeouts.connect;
 
-        let conn_timeout = match Timeout::new(conn_duration, self.handle) {
-            Ok(x) => x,
-            Err(e) => {
-                error!("error creating timeout: {}", e);
-                return Box::new(ok(response::internal_server_error()))
-            },
+        // Copy the request to the mirror in the background
+        if let (Some(opts), Some(mirror)) = (&site.mirror, &self.state.site(site).mirror) {
+            mirror::spawn(
+                opts,
+                mirror,
+                self.client,
+                self.handle,
+                self.pool,
+                conn_duration,
+                &mut proxy_req,
+            );
+        }
+
+        // Wait for a free connection slot if the upstream is limited
+        let limiter = self.state.site(site).limiters.get(site_url.as_str());
+        let queue_timeout = site.upstream.as_ref().map(|opts| opts.queue_timeout);
+
+        let permit: Box<dyn Future<Item = Option<Permit>, Error = Rejected>> = match (limiter, queue_timeout) {
+            (Some(limiter), Some(timeout)) => Box::new(limiter.acquire(timeout, self.handle).map(Some)),
+            _ => Box::new(ok(None)),
         };
 
-        // The future of the origin's response
-        let res_future = self.client.request(proxy_req).then(|res| match res {
-            Ok(res) => Ok(make_proxy_response(res)),
-            Err(e) => {
-                error!("error making client request: {:?}", e);
-                match e {
-                    // TODO: How should other errors be handled?
-                    hyper::Error::Io(ref e) if e.kind() == io::ErrorKind::ConnectionRefused
-                        || e.kind() == io::ErrorKind::ConnectionAborted
-                        || e.kind() == io::ErrorKind::ConnectionReset =>
-                        Ok(response::bad_gateway()),
-                    _ =>
-                        Ok(response::internal_server_error()),
+        let pool = self.pool;
+        let client = self.client;
+        let handle = self.handle;
+
+        let future = permit.then(move |permit| {
+            let permit = match permit {
+                Ok(x) => x,
+                Err(_) => {
+                    let retry_after = queue_timeout.unwrap_or_default();
+                    return Either::A(ok(response::service_unavailable(retry_after)));
                 }
-            },
-        });
+            };
+
+            // Set up timeouts and make the proxied request
+
+            let conn_timeout = match Timeout::new(conn_duration, handle) {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("error creating timeout: {}", e);
+                    return Either::A(ok(response::internal_server_error()));
+                },
+            };
+
+            // The future of the origin's response
+            let res_future = client.request(proxy_req).then(move |res| match res {
+                Ok(res) => {
+                    if let Some((balancer, index)) = balancer {
+                        balancer.mark_up(index);
+                    }
+                    let res = make_proxy_response(res, site, site_url, public.as_ref());
+
+                    // The slot is taken until the body has streamed
+                    let res = match permit {
+                        None => res,
+                        Some(permit) => Response::new()
+                            .with_status(res.status())
+                            .with_headers(res.headers().clone())
+                            .with_body(body::spawn(pool, Hold::new(res.body(), permit))),
+                    };
 
-        let future = res_future
-            .select2(conn_timeout)
-            .then(|result| match result {
-                Ok(Either::A((res, _err))) => Ok(res),
-                Ok(Either::B((_timeout_error, _res))) => {
-                    // TODO: Look into future lifecycle. Surely I don't need to drop(res_future) myself?
-                    Err(hyper::Error::Io(io::Error::new(
-                        io::ErrorKind::TimedOut,
-                        "[timeout] client timed out during connect",
-                    )))
+                    // Streaming bodies are never held back for rewriting
+                    if res.headers().has::<Streaming>() {
+                        return Ok(stream_body(handle, &site.streaming, res));
+                    }
+
+                    match site.sub_filter {
+                        None => Ok(res),
+                        Some(ref filter) => Ok(rewrite_body(pool, filter, is_head, res)),
+                    }
                 }
-                Err(Either::A((res_error, _))) => Err(res_error),
-                Err(Either::B((timeout_error, _res))) => Err(From::from(timeout_error)),
+                Err(e) => {
+                    error!("error making client request: {:?}", e);
+                    match e {
+                        // TODO: How should other errors be handled?
+                        hyper::Error::Io(ref e) if e.kind() == io::ErrorKind::ConnectionRefused
+                            || e.kind() == io::ErrorKind::ConnectionAborted
+                            || e.kind() == io::ErrorKind::ConnectionReset => {
+                            if let Some((balancer, index)) = balancer {
+                                balancer.mark_down(index);
+                            }
+                            Ok(response::bad_gateway())
+                        }
+                        _ =>
+                            Ok(response::internal_server_error()),
+                    }
+                },
             });
 
+            let future = res_future
+                .select2(conn_timeout)
+                .then(|result| match result {
+                    Ok(Either::A((res, _err))) => Ok(res),
+                    Ok(Either::B((_timeout_error, _res))) => {
+                        // TODO: Look into future lifecycle. Surely I don't need to drop(res_future) myself?
+                        Err(hyper::Error::Io(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "[timeout] client timed out during connect",
+                        )))
+                    }
+                    Err(Either::A((res_error, _))) => Err(res_error),
+                    Err(Either::B((timeout_error, _res))) => Err(From::from(timeout_error)),
+                });
+
+            Either::B(future)
+        });
+
         Box::new(future)
     }
 }
+
+#[test]
+fn test_upstream_uri() {
+    let site_url = Url::parse("http://localhost:3001").unwrap();
+    let uri = |s: &str| upstream_uri(&site_url, &s.parse().unwrap()).map(|x| x.to_string());
+
+    assert_eq!(uri("/"), Some("http://localhost:3001/".to_string()));
+    assert_eq!(uri("/a/b"), Some("http://localhost:3001/a/b".to_string()));
+    assert_eq!(uri("/search?q=prox&page=2"), Some("http://localhost:3001/search?q=prox&page=2".to_string()));
+    assert_eq!(uri("http://example.com/a?b"), Some("http://localhost:3001/a?b".to_string()));
+}
+
+#[test]
+fn test_rewrite_body_without_a_body() {
+    use rewrite::Rule;
+
+    let filter: &'static SubFilter = Box::leak(Box::new(SubFilter {
+        types: vec!["text/html".to_string()],
+        rules: vec![Rule::literal("old", "new").unwrap()],
+    }));
+    let pool = CpuPool::new(1);
+    let res = |status: StatusCode| {
+        Response::new()
+            .with_status(status)
+            .with_header(header::ContentType::html())
+            .with_header(header::ContentEncoding(vec![header::Encoding::Gzip]))
+            .with_header(header::ContentLength(20))
+    };
+    // Rewritten bodies lose their Content-Length
+    let rewritten = |res: Response| !res.headers().has::<header::ContentLength>();
+
+    assert!(rewritten(rewrite_body(&pool, filter, false, res(StatusCode::Ok))));
+    assert!(!rewritten(rewrite_body(&pool, filter, true, res(StatusCode::Ok))));
+    assert!(!rewritten(rewrite_body(&pool, filter, false, res(StatusCode::NotModified))));
+    assert!(!rewritten(rewrite_body(&pool, filter, false, res(StatusCode::NoContent))));
+    assert!(!rewritten(rewrite_body(&pool, filter, false, res(StatusCode::PartialContent))));
+}
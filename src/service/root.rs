@@ -1,14 +1,20 @@
 use std::collections::HashMap;
 use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use futures::{Future, future::ok};
 use futures_cpupool::CpuPool;
 use hyper::{self, header, Client, Request, Response, client::HttpConnector, server::Service};
 
-use config::{Config, Site};
+use balance::{self, ChosenTarget};
+use body;
+use config::{self, Config, Site};
 use host::Host;
 use response;
 use service;
+use split::{self, ChosenVariant};
+use state::State;
 
 pub struct Root {
     pub config: &'static Config,
@@ -17,13 +23,14 @@ pub struct Root {
     pub client: &'static Client<HttpConnector>,
     pub sites: &'static HashMap<Host, Site>,
     pub handle: &'static ::tokio_core::reactor::Handle,
+    pub state: &'static State,
 }
 
 impl Service for Root {
     type Request = Request;
     type Response = Response;
     type Error = hyper::Error;
-    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
         trace!("[root] request {:?} entered", req.path());
@@ -51,21 +58,125 @@ impl Service for Root {
             None => return Box::new(ok(response::not_found())),
         };
 
-        let next = service::log::Log {
+        // Enforce the body size limit
+
+        let (mut req, exceeded) = match site.max_body_size.or(self.config.server.max_body_size) {
+            None => (req, None),
+            Some(limit) => match limit_body(self.pool, req, limit) {
+                Err(res) => return Box::new(ok(res)),
+                Ok((req, exceeded)) => (req, Some(exceeded)),
+            },
+        };
+
+        // Pick a split variant. Clients don't get to pick it by sending the header themselves.
+
+        req.headers_mut().remove::<ChosenVariant>();
+
+        let assignment = match (&site.split, &site.url) {
+            (Some(split), Some(url)) => {
+                let assignment = split::choose(split, url, self.remote_ip, req.headers());
+                req.headers_mut().set(ChosenVariant(assignment.name.to_string()));
+                Some((split, assignment))
+            }
+            _ => None,
+        };
+
+        // Pick one of the site's upstream targets unless a canary variant was picked
+
+        req.headers_mut().remove::<ChosenTarget>();
+
+        let is_primary = assignment
+            .as_ref()
+            .map(|(_, assignment)| assignment.name == split::PRIMARY)
+            .unwrap_or(true);
+
+        let choice = match self.state.site(site).balancer {
+            Some(ref balancer) if is_primary => {
+                let choice = balancer.choose(site, req.headers());
+                req.headers_mut().set(ChosenTarget(choice.index));
+                Some(choice)
+            }
+            _ => None,
+        };
+
+        let next = service::errors::Errors {
             config: self.config,
             pool: self.pool,
             client: self.client,
             remote_ip: self.remote_ip,
             handle: self.handle,
+            state: self.state,
         };
 
-        Box::new(next.call((site, req)).map(|mut res| {
+        let future = next.call((site, req)).then(move |result| match exceeded {
+            // Whatever happened downstream, it was because the body was cut off
+            Some(ref exceeded) if exceeded.load(Ordering::SeqCst) => {
+                Ok(response::payload_too_large())
+            }
+            _ => result,
+        });
+
+        Box::new(future.map(move |mut res| {
             res.headers_mut().set(header::Server::new("prox"));
+            if let Some((split, assignment)) = assignment {
+                expose_variant(split, &assignment, &mut res);
+            }
+            if let (Some(choice), Some(sticky)) = (choice, site.sticky.as_ref()) {
+                if choice.set_cookie {
+                    let cookie = balance::cookie(&sticky.cookie, &site.targets[choice.index]);
+                    append_set_cookie(&mut res, cookie);
+                }
+            }
             res
         }))
     }
 }
 
+fn expose_variant(split: &config::Split, assignment: &split::Assignment, res: &mut Response) {
+    if let Some(ref name) = split.header {
+        res.headers_mut().set_raw(name.clone(), assignment.name);
+    }
+
+    if let (true, Some(name)) = (assignment.set_cookie, split.cookie.as_ref()) {
+        append_set_cookie(res, split::cookie(name, assignment.name));
+    }
+}
+
+fn append_set_cookie(res: &mut Response, cookie: String) {
+    let mut cookies = res.headers()
+        .get::<header::SetCookie>()
+        .map(|cookies| cookies.0.clone())
+        .unwrap_or_default();
+    cookies.push(cookie);
+    res.headers_mut().set(header::SetCookie(cookies));
+}
+
+/// Rejects a request whose Content-Length is over the limit, else cuts off its body
+/// stream once it grows past the limit.
+///
+/// The returned flag is set if the body was cut off.
+fn limit_body(pool: &CpuPool, mut req: Request, limit: u64) -> Result<(Request, Arc<AtomicBool>), Response> {
+    let exceeded = Arc::new(AtomicBool::new(false));
+
+    // No body to read
+    if req.headers().get::<header::ContentLength>() == Some(&header::ContentLength(0)) {
+        return Ok((req, exceeded));
+    }
+
+    if let Some(&header::ContentLength(len)) = req.headers().get::<header::ContentLength>() {
+        if len > limit {
+            return Err(response::payload_too_large());
+        }
+    }
+
+    if let Some(stream) = req.body_mut().take() {
+        let limited = body::Limit::new(stream, limit, exceeded.clone());
+        req.set_body(body::spawn(pool, limited));
+    }
+
+    Ok((req, exceeded))
+}
+
 /// If the request path is absolute, then the Host header is replaced with it.
 ///
 /// <https://tools.ietf.org/html/draft-ietf-httpbis-p1-messaging-14#section-9.4>
@@ -83,6 +194,22 @@ fn fix_host_header(mut req: Request) -> Request {
     req
 }
 
+#[test]
+fn test_limit_body_content_length() {
+    let pool = CpuPool::new(1);
+    let req = |len| {
+        let mut req = Request::new(hyper::Method::Post, "/".parse::<hyper::Uri>().unwrap());
+        req.headers_mut().set(header::ContentLength(len));
+        req
+    };
+
+    assert!(limit_body(&pool, req(10), 10).is_ok());
+    assert_eq!(
+        limit_body(&pool, req(11), 10).err().map(|res| res.status()),
+        Some(hyper::StatusCode::PayloadTooLarge)
+    );
+}
+
 #[test]
 fn test_fix_host_header() {
     let mut req = Request::new(
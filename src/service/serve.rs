@@ -1,20 +1,29 @@
 use std::fs::File;
+use std::io;
 use std::net::IpAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
-use futures::{Future, future::ok};
+use futures::{stream, Future, Stream, future::ok};
 use futures_cpupool::CpuPool;
-use hyper::{self, header, Client, Method, Request, Response, StatusCode, client::HttpConnector,
-            server::Service};
+use hyper::{self, header, Chunk, Client, Method, Request, Response, StatusCode,
+            client::HttpConnector, server::Service};
+use unicase::Ascii;
 
+use body;
+use cgi;
+use compress::Coding;
 use config::{self, Config, Site};
 use entity;
+use fastcgi;
 use mime;
 use negotiate;
 use path;
 use range;
 use response;
 use service;
+use state::State;
+use util;
 
 const CHUNK_SIZE: u64 = 65_536;
 
@@ -25,14 +34,17 @@ pub struct Serve {
     pub client: &'static Client<HttpConnector>,
     pub remote_ip: IpAddr,
     pub handle: &'static ::tokio_core::reactor::Handle,
+    pub state: &'static State,
 }
 
 fn handle_request_sync(
     pool: CpuPool,
-    root: &'static Path,
+    opts: &'static config::Serve,
     req: Request,
-    dotfiles: &bool,
+    fastcgi: Option<&'static config::FastCgi>,
 ) -> (Request, Option<Response>) {
+    let config::Serve { ref root, dotfiles, ref index, ref precompressed, clean_urls, ref cache_control, .. } = *opts;
+
     if *req.method() != Method::Get && *req.method() != Method::Head
         && *req.method() != Method::Options
     {
@@ -40,7 +52,7 @@ fn handle_request_sync(
         return (req, None);
     }
 
-    let entity_path = match path::get_entity_path(root, req.path()) {
+    let entity_path = match resolve_entity_path(root, req.path(), clean_urls) {
         None => //return Box::new(ok(response::not_found())),
             return (req, None),
         Some(path) => path,
@@ -51,6 +63,35 @@ fn handle_request_sync(
         return (req, None)
     }
 
+    // Directories and scripts are for the FastCGI responder to run, never to be sent as files
+    if let Some(opts) = fastcgi {
+        let is_script = match (entity_path.extension().and_then(|x| x.to_str()), opts.extension()) {
+            (Some(ext), Some(script_ext)) => ext.eq_ignore_ascii_case(script_ext),
+            _ => false,
+        };
+        if is_script || entity_path.is_dir() {
+            return (req, None);
+        }
+    }
+
+    // Folders are served by their index file
+    let entity_path = if entity_path.is_dir() {
+        // Relative links in the index resolve against the slashed url
+        if !req.path().ends_with('/') {
+            let location = match req.query() {
+                None => format!("{}/", req.path()),
+                Some(query) => format!("{}/?{}", req.path(), query),
+            };
+            return (req, Some(response::moved_permanently(location)));
+        }
+        match find_index(&entity_path, index) {
+            None => return (req, Some(response::not_found())),
+            Some(path) => path,
+        }
+    } else {
+        entity_path
+    };
+
     let file = match File::open(&entity_path) {
         Err(_) =>// return Box::new(ok(response::not_found())),
             return (req, None),
@@ -62,10 +103,19 @@ fn handle_request_sync(
         return (req, Some(response::not_found()));
     }
 
+    // A precompressed copy has the original's type, and caching
+    let content_type = mime::guess_mime_by_path(&entity_path);
+    let url_path = url_path(root, &entity_path);
+
+    let (file, coding) = match open_precompressed(&entity_path, precompressed, req.headers().get()) {
+        Some((file, coding)) => (file, Some(coding)),
+        None => (file, None),
+    };
+
     let entity = match entity::Entity::new(
         file,
-        pool,
-        mime::guess_mime_by_path(&entity_path),
+        pool.clone(),
+        content_type,
     ) {
         Err(_) => // return Box::new(ok(response::not_found())),
             return (req, None),
@@ -76,10 +126,23 @@ fn handle_request_sync(
 
     // HANDLE CACHING HEADERS
 
-    let entity_etag = entity.etag(&entity::ETagKind::Strong);
+    let entity_etag = match coding {
+        None => entity.etag(&entity::ETagKind::Strong),
+        // Each coding is its own representation
+        Some(coding) => {
+            let etag = entity.etag(&entity::ETagKind::Strong);
+            header::EntityTag::strong(format!("{}.{}", etag.tag(), coding.token()))
+        }
+    };
 
     if is_not_modified(&entity, req.headers(), &entity_etag) {
-        return (req, Some(response::not_modified(entity_etag)));
+        let mut res = response::not_modified(entity_etag);
+        set_cache_control(res.headers_mut(), cache_control, &url_path);
+        // Same as the 200 so that caches keep choosing by Accept-Encoding
+        if !precompressed.is_empty() {
+            util::append_header_vary(res.headers_mut(), Ascii::new("Accept-Encoding".to_string()));
+        }
+        return (req, Some(res));
     }
 
     if is_precondition_failed(&entity, req.headers(), &entity_etag) {
@@ -90,12 +153,25 @@ fn handle_request_sync(
     // - Comes after evaluating precondition headers.
     //   <https://tools.ietf.org/html/rfc7233#section-3.1>
 
-    let range = range::parse_range_header(
-        req.headers().has::<header::Range>(),
-        req.headers().get::<header::Range>(),
-        entity.len(),
+    // A range of a representation that the client doesn't have would corrupt its copy,
+    // so it gets the whole thing instead.
+    let if_range_matches = range::if_range_matches(
+        req.headers().has::<header::IfRange>(),
+        req.headers().get::<header::IfRange>(),
+        &entity_etag,
+        entity.last_modified(),
     );
 
+    let range = if if_range_matches {
+        range::parse_range_header(
+            req.headers().has::<header::Range>(),
+            req.headers().get::<header::Range>(),
+            entity.len(),
+        )
+    } else {
+        range::RequestedRange::None
+    };
+
     // Client provided a bad range
     if let range::RequestedRange::NotSatisfiable = range {
         return (req, Some(response::invalid_range(entity.len())));
@@ -111,47 +187,93 @@ fn handle_request_sync(
     res.headers_mut()
         .set(header::ContentType(entity.content_type().mime.clone()));
 
+    if let Some(coding) = coding {
+        res.headers_mut()
+            .set(header::ContentEncoding(vec![coding.encoding()]));
+    }
+    if !precompressed.is_empty() {
+        util::append_header_vary(
+            res.headers_mut(),
+            Ascii::new("Accept-Encoding".to_string()),
+        );
+    }
+
     // More about Content-Length: <https://tools.ietf.org/html/rfc2616#section-4.4>
     // - Represents length *after* transfer-encoding.
     // - Don't set Content-Length if Transfer-Encoding != 'identity'
     res.headers_mut().set(header::ContentLength(entity.len()));
 
-    // Only set max-age if it's configured at all.
-    //    if let Some(max_age) = ctx.config.cache.as_ref().map(|opts| opts.max_age) {
-    //        res.headers_mut().set(header::CacheControl(vec![
-    //            header::CacheDirective::Public,
-    //            header::CacheDirective::MaxAge(max_age.as_secs() as u32),
-    //        ]));
-    //    }
+    set_cache_control(res.headers_mut(), cache_control, &url_path);
 
     // Start streaming the file.
 
-    let body = {
-        let range = match range {
-            range::RequestedRange::Satisfiable(mut range) => {
-                res.set_status(StatusCode::PartialContent);
-                res.headers_mut()
-                    .set(header::ContentRange(header::ContentRangeSpec::Bytes {
-                        range: Some((range.start, range.end)),
-                        instance_length: Some(entity.len()),
-                    }));
-
-                // NOTE: Range header is end-inclusive but std::ops::Range is end-exclusive.
-                range.end += 1;
-
-                range
-            }
-            _ => 0..entity.len(),
-        };
-
-        entity.get_range(range, CHUNK_SIZE)
+    let ranges = match range {
+        range::RequestedRange::Satisfiable(ranges) => ranges,
+        _ => Vec::new(),
     };
 
+    let boundary = range::boundary();
+    let content_type = entity.content_type().mime.to_string();
+
+    match ranges.len() {
+        0 => {}
+        1 => {
+            res.set_status(StatusCode::PartialContent);
+            res.headers_mut()
+                .set(header::ContentRange(header::ContentRangeSpec::Bytes {
+                    range: Some((ranges[0].start, ranges[0].end)),
+                    instance_length: Some(entity.len()),
+                }));
+            res.headers_mut()
+                .set(header::ContentLength(ranges[0].end - ranges[0].start + 1));
+        }
+        // Several ranges are sent as parts of a multipart/byteranges body.
+        // <https://tools.ietf.org/html/rfc7233#section-4.1>
+        _ => {
+            res.set_status(StatusCode::PartialContent);
+            let multipart = format!("multipart/byteranges; boundary={}", boundary);
+            res.headers_mut()
+                .set(header::ContentType(multipart.parse().unwrap()));
+            res.headers_mut().set(header::ContentLength(range::multipart_len(
+                &ranges,
+                &boundary,
+                &content_type,
+                entity.len(),
+            )));
+        }
+    }
+
     // For HEAD requests, we do all the work except sending the body.
     if *req.method() == Method::Head {
         return (req, Some(res));
     }
 
+    // NOTE: Range header is end-inclusive but std::ops::Range is end-exclusive.
+    let body = match ranges.len() {
+        0 => entity.get_range(0..entity.len(), CHUNK_SIZE),
+        1 => entity.get_range(ranges[0].start..ranges[0].end + 1, CHUNK_SIZE),
+        _ => {
+            let tail = range::multipart_tail(&boundary);
+            let parts = ranges
+                .iter()
+                .map(|range| {
+                    let head = range::part_head(&boundary, &content_type, range, entity.len());
+                    (head, range.start..range.end + 1)
+                })
+                .collect::<Vec<_>>();
+
+            // Each part is only read from the file once the one before it was sent
+            let stream = stream::iter_ok::<_, hyper::Error>(parts)
+                .map(move |(head, range)| {
+                    stream::once(Ok(Chunk::from(head))).chain(entity.get_range(range, CHUNK_SIZE))
+                })
+                .flatten()
+                .chain(stream::once(Ok(Chunk::from(tail))));
+
+            body::spawn(&pool, stream)
+        }
+    };
+
     (req, Some(res.with_body(body)))
 }
 
@@ -160,50 +282,277 @@ fn handle_request_sync(
 // TODO: Get pool.spawn(handle_request()) working again instead of the weird _sync helper.
 fn handle_request(
     pool: &CpuPool,
-    root: &'static Path,
+    opts: &'static config::Serve,
     req: Request,
-    dotfiles: &bool,
+    fastcgi: Option<&'static config::FastCgi>,
 ) -> impl Future<Item = (Request, Option<Response>), Error = hyper::Error> {
-    pool.spawn(ok(handle_request_sync(pool.clone(), root, req, dotfiles)))
+    pool.spawn(ok(handle_request_sync(pool.clone(), opts, req, fastcgi)))
+}
+
+/// Finds what a request path names: a file or folder, or else the `.html` file of a clean url.
+fn resolve_entity_path(root: &Path, req_path: &str, clean_urls: bool) -> Option<PathBuf> {
+    let entity_path = path::get_entity_path(root, req_path)?;
+
+    // A page wins over a folder of the same name
+    if clean_urls && !req_path.ends_with('/') && !entity_path.is_file() {
+        let mut html = entity_path.as_os_str().to_owned();
+        html.push(".html");
+        let html = PathBuf::from(html);
+        if html.is_file() {
+            return Some(html);
+        }
+    }
+
+    Some(entity_path)
+}
+
+/// The clean url that serves the same `.html` file, e.g. `/about` for `/about.html`
+/// and `/docs/` for `/docs/index.html`.
+fn clean_url(root: &Path, req_path: &str, index: &[String]) -> Option<String> {
+    if !req_path.ends_with(".html") {
+        return None;
+    }
+    let entity_path = path::get_entity_path(root, req_path)?;
+    if !entity_path.is_file() {
+        return None;
+    }
+
+    let clean = if req_path.ends_with("/index.html") {
+        &req_path[..req_path.len() - "index.html".len()]
+    } else {
+        &req_path[..req_path.len() - ".html".len()]
+    };
+
+    // Unless something else is served there
+    let target = resolve_entity_path(root, clean, true)?;
+    let target = if target.is_dir() { find_index(&target, index)? } else { target };
+    if target == entity_path {
+        Some(clean.to_string())
+    } else {
+        None
+    }
+}
+
+/// The first of the `try_files` paths that has something to serve.
+fn try_files(
+    root: &Path,
+    req_path: &str,
+    try_files: &[String],
+    index: &[String],
+    clean_urls: bool,
+) -> Option<String> {
+    try_files
+        .iter()
+        .map(|pattern| pattern.replace("{path}", req_path))
+        .find(|path| match resolve_entity_path(root, path, clean_urls) {
+            None => false,
+            Some(ref entity_path) if entity_path.is_dir() => find_index(entity_path, index).is_some(),
+            Some(ref entity_path) => entity_path.is_file(),
+        })
+}
+
+/// Determines if the FastCGI responder, rather than serve, handles the path.
+fn is_fastcgi_script(root: &Path, req_path: &str, fastcgi: Option<&config::FastCgi>) -> bool {
+    fastcgi
+        .and_then(|opts| fastcgi::resolve(root, req_path, opts))
+        .is_some()
+}
+
+/// The path of a file under the root as it would be requested, e.g. `/docs/index.html`.
+pub fn url_path(root: &Path, entity_path: &Path) -> String {
+    let relative = entity_path
+        .strip_prefix(root)
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    format!("/{}", relative)
+}
+
+/// Sets the Cache-Control of the first rule that matches the path, and an Expires
+/// from its max-age for HTTP/1.0 caches.
+pub fn set_cache_control(headers: &mut header::Headers, rules: &[config::CacheControlRule], path: &str) {
+    let value = match rules.iter().find(|rule| rule.path.is_match(path)) {
+        None => return,
+        Some(rule) => &rule.value,
+    };
+    if value.is_empty() {
+        return;
+    }
+
+    headers.set_raw("Cache-Control", value.clone());
+
+    let max_age = headers
+        .get::<header::CacheControl>()
+        .and_then(|header::CacheControl(directives)| {
+            directives
+                .iter()
+                .filter_map(|directive| match *directive {
+                    header::CacheDirective::MaxAge(secs) => Some(secs),
+                    _ => None,
+                })
+                .next()
+        });
+    if let Some(secs) = max_age {
+        let expires = SystemTime::now() + Duration::from_secs(u64::from(secs));
+        headers.set(header::Expires(header::HttpDate::from(expires)));
+    }
+}
+
+/// The first index file that a folder has.
+pub fn find_index(dir: &Path, index: &[String]) -> Option<PathBuf> {
+    index.iter().map(|name| dir.join(name)).find(|path| path.is_file())
+}
+
+fn sidecar_path(path: &Path, coding: Coding) -> Option<PathBuf> {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(coding.extension()?);
+    Some(PathBuf::from(name))
+}
+
+/// Opens the precompressed copy of a file, if there's one in a coding that the client accepts.
+fn open_precompressed(
+    path: &Path,
+    precompressed: &[Coding],
+    accept_encoding: Option<&header::AcceptEncoding>,
+) -> Option<(File, Coding)> {
+    let available = precompressed
+        .iter()
+        .cloned()
+        .filter(|&coding| sidecar_path(path, coding).map(|x| x.is_file()).unwrap_or(false))
+        .collect::<Vec<_>>();
+    let coding = negotiate::encoding(accept_encoding, &available)?;
+    let file = File::open(sidecar_path(path, coding)?).ok()?;
+    Some((file, coding))
+}
+
+impl Serve {
+    fn run_cgi(
+        &self,
+        site: &'static Site,
+        root: &'static Path,
+        opts: &'static config::Cgi,
+        script: cgi::Script,
+        req: Request,
+    ) -> Box<dyn Future<Item = Response, Error = hyper::Error>> {
+        let pool = match self.state.site(site).cgi {
+            Some(ref pool) => pool,
+            None => return Box::new(ok(response::internal_server_error())),
+        };
+
+        let port = self.config.server.bind.port();
+        let vars = cgi::meta_variables(&req, self.remote_ip, port, root, &script);
+        let body = req.body()
+            .wait()
+            .map(|chunk| chunk.map_err(io::Error::other));
+
+        let (output, head) = cgi::Output::new();
+
+        // Scripts wait their turn on the site's own pool
+        pool.spawn_fn(move || {
+            if let Err(e) = cgi::run(&script, &vars, body, opts.timeout, output) {
+                error!("[cgi] error running {}: {}", script.name, e);
+            }
+            Ok::<(), ()>(())
+        }).forget();
+
+        Box::new(head.then(|res| Ok(res.unwrap_or_else(|_| response::bad_gateway()))))
+    }
 }
 
 impl Service for Serve {
     type Request = (&'static Site, Request);
     type Response = Response;
     type Error = hyper::Error;
-    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, (site, req): Self::Request) -> Self::Future {
+        let config = self.config;
+        let pool = self.pool;
         let client = self.client;
         let remote_ip = self.remote_ip;
-        let config = self.config;
         let handle = self.handle;
+        let state = self.state;
 
-        let next = move || service::proxy::Proxy {
+        let next = move || service::cache::Cache {
+            config,
+            pool,
             client,
             remote_ip,
-            config,
             handle,
+            state,
         };
 
         // Short-circuit if serve is not set.
-        let config::Serve { ref root, ref dotfiles, .. } = match &site.serve {
+        let opts = match &site.serve {
             Some(x) => x,
             None => return next().call((site, req)),
         };
+        let config::Serve { ref root, ref dotfiles, ref index, cgi: ref cgi_opts, .. } = opts;
+
+        // See if path hits a CGI script.
+
+        if let Some(ref opts) = *cgi_opts {
+            if let Some(script) = cgi::resolve(root, &opts.dir, req.path()) {
+                if !dotfiles && script.name.split('/').any(|x| x.starts_with('.')) {
+                    return next().call((site, req));
+                }
+                // Whatever isn't a script in there must not be served as a file either
+                if !cgi::is_executable(&script.filename) {
+                    return Box::new(ok(response::forbidden()));
+                }
+                return self.run_cgi(site, root, opts, script, req);
+            }
+        }
 
-        // See if path hits a static file.
+        let is_get = *req.method() == Method::Get || *req.method() == Method::Head;
 
-        let future = handle_request(
-            self.pool,
-            root,
-            req,
-            dotfiles,
-        );
+        // Pages are only linked to by their clean url
+
+        if opts.clean_urls && is_get {
+            if let Some(path) = clean_url(root, req.path(), index) {
+                let location = match req.query() {
+                    None => path,
+                    Some(query) => format!("{}?{}", path, query),
+                };
+                return Box::new(ok(response::moved_permanently(location)));
+            }
+        }
+
+        // See if path hits a static file, or one of the try_files.
+
+        let mut req = req;
+        let mut original_uri = None;
+        if !opts.try_files.is_empty()
+            && is_get
+            && !opts.try_files_exclude.iter().any(|x| path::glob_match(x, req.path()))
+        {
+            let uri = try_files(root, req.path(), &opts.try_files, index, opts.clean_urls).and_then(|path| {
+                match req.query() {
+                    None => path,
+                    Some(query) => format!("{}?{}", path, query),
+                }.parse::<hyper::Uri>()
+                    .ok()
+            });
+            if let Some(uri) = uri {
+                // A script that try_files fell back to must reach the FastCGI responder as itself
+                if !is_fastcgi_script(root, uri.path(), site.fastcgi.as_ref()) {
+                    original_uri = Some(req.uri().clone());
+                }
+                req.set_uri(uri);
+            }
+        }
+
+        let future = handle_request(self.pool, opts, req, site.fastcgi.as_ref());
 
         Box::new(future.then(move |result| match result {
             Ok((_, Some(res))) => Box::new(ok(res)),
-            Ok((req, None)) => next().call((site, req)),
+            Ok((mut req, None)) => {
+                // The rest of the chain sees what the client asked for
+                if let Some(uri) = original_uri {
+                    req.set_uri(uri);
+                }
+                next().call((site, req))
+            }
             Err(e) => {
                 error!("io error when fetching static file: {:?}", e);
                 Box::new(ok(response::internal_server_error()))
@@ -239,3 +588,213 @@ fn is_precondition_failed(
         false
     }
 }
+
+#[test]
+fn test_open_precompressed() {
+    use std::fs;
+
+    use hyper::header::{AcceptEncoding, Header, Raw};
+
+    let root = util::TempDir::new("serve_precompressed");
+    let path = root.join("app.js");
+    fs::write(&path, "raw").unwrap();
+    fs::write(root.join("app.js.gz"), "gzip").unwrap();
+    fs::write(root.join("app.js.br"), "brotli").unwrap();
+
+    let parse = |s: &[u8]| AcceptEncoding::parse_header(&Raw::from(s)).unwrap();
+    let coding = |precompressed: &[Coding], accept: &[u8]| {
+        open_precompressed(&path, precompressed, Some(&parse(accept))).map(|(_, coding)| coding)
+    };
+
+    assert_eq!(coding(&[Coding::Brotli, Coding::Gzip], b"gzip, br"), Some(Coding::Brotli));
+    assert_eq!(coding(&[Coding::Brotli, Coding::Gzip], b"gzip"), Some(Coding::Gzip));
+    assert_eq!(coding(&[Coding::Gzip, Coding::Brotli], b"gzip, br"), Some(Coding::Gzip));
+    // No such file
+    assert_eq!(coding(&[Coding::Zstd], b"zstd"), None);
+    // Not configured
+    assert_eq!(coding(&[Coding::Gzip], b"br"), None);
+    assert_eq!(open_precompressed(&path, &[Coding::Gzip], None).map(|(_, coding)| coding), None);
+}
+
+#[test]
+fn test_precompressed_not_modified() {
+    use std::fs;
+
+    use hyper::header::{Header, Raw};
+    use leak::Leak;
+    use toml;
+
+    let root = util::TempDir::new("serve_precompressed_not_modified");
+    fs::write(root.join("app.js"), "raw").unwrap();
+    fs::write(root.join("app.js.gz"), "gzip").unwrap();
+
+    let opts: config::Serve = toml::from_str(&format!(
+        "root = {:?}\nprecompressed = [\"gzip\"]",
+        root.to_str().unwrap()
+    )).unwrap();
+    let opts = Box::new(opts).leak();
+    let pool = CpuPool::new(1);
+    let vary = Some(header::Vary::Items(vec!["Accept-Encoding".parse().unwrap()]));
+
+    let mut req = Request::new(Method::Get, "/app.js".parse().unwrap());
+    req.headers_mut()
+        .set(header::AcceptEncoding::parse_header(&Raw::from("gzip")).unwrap());
+    let (mut req, res) = handle_request_sync(pool.clone(), opts, req, None);
+    let res = res.unwrap();
+    assert_eq!(res.status(), StatusCode::Ok);
+    assert_eq!(res.headers().get::<header::Vary>().cloned(), vary);
+
+    let header::ETag(etag) = res.headers().get::<header::ETag>().cloned().unwrap();
+    req.headers_mut().set(header::IfNoneMatch::Items(vec![etag]));
+    let (_, res) = handle_request_sync(pool, opts, req, None);
+    let res = res.unwrap();
+    assert_eq!(res.status(), StatusCode::NotModified);
+    assert_eq!(res.headers().get::<header::Vary>().cloned(), vary);
+}
+
+#[test]
+fn test_find_index() {
+    use std::fs;
+
+    let root = util::TempDir::new("serve_find_index");
+    fs::create_dir_all(root.join("docs/index.html")).unwrap();
+    fs::write(root.join("index.htm"), "").unwrap();
+    fs::write(root.join("docs/index.htm"), "").unwrap();
+
+    let index = vec!["index.html".to_string(), "index.htm".to_string()];
+    assert_eq!(find_index(&root, &index), Some(root.join("index.htm")));
+    // Folders named like an index don't count
+    assert_eq!(find_index(&root.join("docs"), &index), Some(root.join("docs/index.htm")));
+    assert_eq!(find_index(&root, &index[..1]), None);
+    assert_eq!(find_index(&root, &[]), None);
+}
+
+#[test]
+fn test_try_files() {
+    use std::fs;
+
+    let root = util::TempDir::new("serve_try_files");
+    fs::create_dir_all(root.join("docs")).unwrap();
+    fs::create_dir_all(root.join("empty")).unwrap();
+    fs::write(root.join("index.html"), "").unwrap();
+    fs::write(root.join("about.html"), "").unwrap();
+    fs::write(root.join("app.js"), "").unwrap();
+    fs::write(root.join("docs/index.html"), "").unwrap();
+
+    let index = vec!["index.html".to_string()];
+    let patterns = vec!["{path}".to_string(), "{path}.html".to_string(), "/index.html".to_string()];
+    let try_files = |req_path| try_files(&root, req_path, &patterns, &index, false);
+
+    assert_eq!(try_files("/app.js"), Some("/app.js".to_string()));
+    assert_eq!(try_files("/about"), Some("/about.html".to_string()));
+    assert_eq!(try_files("/docs/"), Some("/docs/".to_string()));
+    assert_eq!(try_files("/empty/"), Some("/index.html".to_string()));
+    assert_eq!(try_files("/users/42"), Some("/index.html".to_string()));
+    assert_eq!(try_files("/../etc/passwd"), Some("/index.html".to_string()));
+    assert_eq!(self::try_files(&root, "/nope", &patterns[..2], &index, false), None);
+    // Clean urls count as being there
+    assert_eq!(self::try_files(&root, "/about", &patterns[..1], &index, true), Some("/about".to_string()));
+}
+
+#[test]
+fn test_try_files_fastcgi() {
+    use std::fs;
+    use std::time::Duration;
+
+    let root = util::TempDir::new("serve_try_files_fastcgi");
+    fs::write(root.join("index.php"), "").unwrap();
+    fs::write(root.join("app.js"), "").unwrap();
+
+    let opts = config::FastCgi {
+        address: config::FastCgiAddress::Tcp("127.0.0.1:9000".to_string()),
+        index: "index.php".to_string(),
+        params: Default::default(),
+        max_connections: 1,
+        timeout: Duration::from_secs(1),
+    };
+    let index = vec!["index.html".to_string()];
+    let patterns = vec!["{path}".to_string(), "/index.php".to_string()];
+
+    // A front controller is run with the path that try_files picked
+    let target = try_files(&root, "/users/42", &patterns, &index, false).unwrap();
+    assert_eq!(target, "/index.php");
+    assert!(is_fastcgi_script(&root, &target, Some(&opts)));
+
+    // Static files and sites without FastCGI keep the client's path for the rest of the chain
+    let target = try_files(&root, "/app.js", &patterns, &index, false).unwrap();
+    assert!(!is_fastcgi_script(&root, &target, Some(&opts)));
+    assert!(!is_fastcgi_script(&root, "/index.php", None));
+}
+
+#[test]
+fn test_clean_urls() {
+    use std::fs;
+
+    let root = util::TempDir::new("serve_clean_urls");
+    fs::create_dir_all(root.join("docs")).unwrap();
+    fs::create_dir_all(root.join("blog")).unwrap();
+    fs::write(root.join("index.html"), "").unwrap();
+    fs::write(root.join("about.html"), "").unwrap();
+    fs::write(root.join("docs/index.html"), "").unwrap();
+    fs::write(root.join("blog.html"), "").unwrap();
+    fs::write(root.join("notes"), "").unwrap();
+    fs::write(root.join("notes.html"), "").unwrap();
+
+    // Resolving
+    assert_eq!(resolve_entity_path(&root, "/about", true), Some(root.join("about.html")));
+    assert_eq!(resolve_entity_path(&root, "/about", false), Some(root.join("about")));
+    assert_eq!(resolve_entity_path(&root, "/blog", true), Some(root.join("blog.html")));
+    assert_eq!(resolve_entity_path(&root, "/blog/", true), Some(root.join("blog/")));
+    assert_eq!(resolve_entity_path(&root, "/notes", true), Some(root.join("notes")));
+
+    // Redirecting
+    let index = vec!["index.html".to_string()];
+    let clean_url = |req_path| clean_url(&root, req_path, &index);
+    assert_eq!(clean_url("/about.html"), Some("/about".to_string()));
+    assert_eq!(clean_url("/index.html"), Some("/".to_string()));
+    assert_eq!(clean_url("/docs/index.html"), Some("/docs/".to_string()));
+    assert_eq!(clean_url("/blog.html"), Some("/blog".to_string()));
+    assert_eq!(clean_url("/missing.html"), None);
+    assert_eq!(clean_url("/about"), None);
+    // /notes is another file
+    assert_eq!(clean_url("/notes.html"), None);
+}
+
+#[test]
+fn test_set_cache_control() {
+    use path::PathPattern;
+    use regex::Regex;
+
+    let rule = |path: PathPattern, value: &str| config::CacheControlRule {
+        path,
+        value: value.to_string(),
+    };
+    let rules = vec![
+        rule(PathPattern::Glob("/sw.js".to_string()), "no-cache"),
+        rule(
+            PathPattern::Regex(Regex::new(r"\.[0-9a-f]{8}\.(js|css)$").unwrap()),
+            "public, max-age=31536000, immutable",
+        ),
+        rule(PathPattern::Glob("/private/*".to_string()), ""),
+        rule(PathPattern::Glob("*.html".to_string()), "no-cache"),
+        rule(PathPattern::Glob("*".to_string()), "public, max-age=60"),
+    ];
+    let headers = |path| {
+        let mut headers = header::Headers::new();
+        set_cache_control(&mut headers, &rules, path);
+        headers
+    };
+    let value = |path| headers(path).get_raw("Cache-Control").map(|raw| raw.one().unwrap().to_vec());
+
+    assert_eq!(value("/app.3f2a9c1b.js"), Some(b"public, max-age=31536000, immutable".to_vec()));
+    assert!(headers("/app.3f2a9c1b.js").has::<header::Expires>());
+    assert_eq!(value("/docs/index.html"), Some(b"no-cache".to_vec()));
+    assert!(!headers("/docs/index.html").has::<header::Expires>());
+    // Earlier rules win
+    assert_eq!(value("/sw.js"), Some(b"no-cache".to_vec()));
+    assert_eq!(value("/private/notes.html"), None);
+    assert_eq!(value("/app.js"), Some(b"public, max-age=60".to_vec()));
+
+    assert_eq!(url_path(Path::new("public"), Path::new("public/docs/index.html")), "/docs/index.html");
+    assert_eq!(url_path(Path::new("public"), Path::new("public")), "/");
+}
@@ -0,0 +1,192 @@
+//! Splitting a site's traffic between its upstream and canary variants.
+//!
+//! A request goes to the first variant whose `match_header` or `match_cookie` it matches.
+//! Otherwise it's assigned by weight. The assignment sticks by cookie if `split.cookie`
+//! is set, else by a hash of the client's IP.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::time::SystemTime;
+
+use hyper::header::{self, Headers};
+use url::Url;
+
+use config::{Split, Variant};
+
+/// The name of the site's own `url` when it's chosen.
+pub const PRIMARY: &str = "primary";
+
+// Root tags the request with the chosen variant for the layers below it:
+// the log shows it, the cache keys on it and the proxy picks its upstream by it.
+header! {
+    (ChosenVariant, "X-Prox-Variant") => [String]
+}
+
+// Weights are percents with two decimals of precision
+const BUCKETS: u64 = 10_000;
+
+#[derive(Debug, PartialEq)]
+pub struct Assignment {
+    pub name: &'static str,
+    pub url: &'static Url,
+    /// The client should be sent a cookie that pins it to this variant.
+    pub set_cookie: bool,
+}
+
+pub fn choose(split: &'static Split, primary: &'static Url, remote_ip: IpAddr, headers: &Headers) -> Assignment {
+    let assignment = |name: &'static str, url: &'static Url, set_cookie: bool| Assignment {
+        name,
+        url,
+        set_cookie,
+    };
+
+    let cookies = headers.get::<header::Cookie>();
+
+    // Forced by the request
+    for variant in &split.variants {
+        if is_forced(variant, headers, cookies) {
+            return assignment(variant.name.as_str(), &variant.url, false);
+        }
+    }
+
+    // Already pinned
+    let pinned = split
+        .cookie
+        .as_ref()
+        .and_then(|name| cookies.and_then(|cookies| cookies.get(name)));
+    if let Some(pinned) = pinned {
+        if pinned == PRIMARY {
+            return assignment(PRIMARY, primary, false);
+        }
+        if let Some(variant) = split.variants.iter().find(|v| v.name == pinned) {
+            return assignment(variant.name.as_str(), &variant.url, false);
+        }
+    }
+
+    // With a cookie, a fresh assignment doesn't have to be stable across requests
+    let bucket = if split.cookie.is_some() {
+        hash(&(remote_ip, SystemTime::now())) % BUCKETS
+    } else {
+        hash(&remote_ip) % BUCKETS
+    };
+
+    let set_cookie = split.cookie.is_some();
+    let mut upper = 0;
+    for variant in &split.variants {
+        upper += (variant.percent * BUCKETS as f64 / 100.0).round() as u64;
+        if bucket < upper {
+            return assignment(variant.name.as_str(), &variant.url, set_cookie);
+        }
+    }
+
+    assignment(PRIMARY, primary, set_cookie)
+}
+
+/// The upstream of the variant a request was tagged with.
+pub fn url<'a>(split: &'a Split, primary: &'a Url, name: &str) -> &'a Url {
+    split
+        .variants
+        .iter()
+        .find(|variant| variant.name == name)
+        .map(|variant| &variant.url)
+        .unwrap_or(primary)
+}
+
+/// The Set-Cookie value that pins a client to a variant.
+pub fn cookie(name: &str, variant: &str) -> String {
+    format!("{}={}; Path=/; HttpOnly", name, variant)
+}
+
+fn is_forced(variant: &Variant, headers: &Headers, cookies: Option<&header::Cookie>) -> bool {
+    let header_matches = variant.match_header.as_ref().map(|(name, expected)| {
+        match headers.get_raw(name).and_then(|raw| raw.one()) {
+            None => false,
+            Some(actual) => expected
+                .as_ref()
+                .map(|expected| actual == expected.as_bytes())
+                .unwrap_or(true),
+        }
+    });
+
+    let cookie_matches = variant.match_cookie.as_ref().map(|(name, expected)| {
+        match cookies.and_then(|cookies| cookies.get(name)) {
+            None => false,
+            Some(actual) => expected
+                .as_ref()
+                .map(|expected| actual == expected.as_str())
+                .unwrap_or(true),
+        }
+    });
+
+    header_matches.unwrap_or(false) || cookie_matches.unwrap_or(false)
+}
+
+fn hash<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[test]
+fn test_choose() {
+    use leak::Leak;
+
+    fn split(cookie: Option<&str>, canary_percent: f64) -> &'static Split {
+        let split = Split {
+            cookie: cookie.map(String::from),
+            header: None,
+            variants: vec![Variant {
+                name: "canary".to_string(),
+                url: Url::parse("http://localhost:4002").unwrap(),
+                percent: canary_percent,
+                match_header: Some(("X-Canary".to_string(), Some("1".to_string()))),
+                match_cookie: Some(("canary".to_string(), None)),
+            }],
+        };
+        Box::new(split).leak()
+    }
+
+    fn with_cookie(name: &str, value: &str) -> Headers {
+        let mut headers = Headers::new();
+        let mut cookies = header::Cookie::new();
+        cookies.set(name.to_string(), value.to_string());
+        headers.set(cookies);
+        headers
+    }
+
+    let primary = Box::new(Url::parse("http://localhost:4001").unwrap()).leak();
+    let ip = "10.0.0.1".parse().unwrap();
+
+    // Forced by header or cookie
+    let none = split(None, 0.0);
+    let mut headers = Headers::new();
+    assert_eq!(choose(none, primary, ip, &headers).name, PRIMARY);
+    headers.set_raw("X-Canary", "0");
+    assert_eq!(choose(none, primary, ip, &headers).name, PRIMARY);
+    headers.set_raw("X-Canary", "1");
+    assert_eq!(choose(none, primary, ip, &headers).name, "canary");
+    assert_eq!(choose(none, primary, ip, &with_cookie("canary", "yes")).name, "canary");
+
+    // By IP, nothing needs to be pinned
+    let all = split(None, 100.0);
+    let chosen = choose(all, primary, ip, &Headers::new());
+    assert_eq!(chosen.name, "canary");
+    assert!(!chosen.set_cookie);
+
+    // A pinned client stays put even though the canary gets all new traffic
+    let all = split(Some("prox_variant"), 100.0);
+    assert_eq!(
+        choose(all, primary, ip, &with_cookie("prox_variant", PRIMARY)),
+        Assignment {
+            name: PRIMARY,
+            url: primary,
+            set_cookie: false,
+        }
+    );
+
+    // A new client gets pinned
+    let chosen = choose(all, primary, ip, &Headers::new());
+    assert_eq!(chosen.name, "canary");
+    assert!(chosen.set_cookie);
+}
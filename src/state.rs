@@ -0,0 +1,92 @@
+//! Runtime state that lives as long as the server, e.g. each site's response cache.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use futures_cpupool::CpuPool;
+
+use balance::Balancer;
+use cache;
+use config::{Config, Site};
+use host::Host;
+use mirror::Mirror;
+use queue::Limiter;
+
+/// State owned by a single site and shared by all of its hosts.
+pub struct SiteState {
+    /// Cached upstream responses if `site.cache` is configured.
+    pub cache: Option<cache::Store>,
+
+    /// Stats about mirrored requests if `site.mirror` is configured.
+    pub mirror: Option<Mirror>,
+
+    /// Which upstream targets are up if the site has more than one.
+    pub balancer: Option<Balancer>,
+
+    /// Connection slots by upstream url if `site.upstream` is configured.
+    pub limiters: HashMap<String, Limiter>,
+
+    /// Threads that talk to the FastCGI responder if `site.fastcgi` is configured.
+    ///
+    /// Kept apart from the main pool since they block on the responder and the client.
+    pub fastcgi: Option<CpuPool>,
+
+    /// Threads that run CGI scripts if `site.serve.cgi` is configured. One per script.
+    pub cgi: Option<CpuPool>,
+}
+
+impl SiteState {
+    fn new(site: &Site) -> Self {
+        let mut limiters = HashMap::new();
+        if let Some(ref opts) = site.upstream {
+            let variants = site.split.iter().flat_map(|split| &split.variants);
+            let urls = site.targets.iter().chain(variants.map(|variant| &variant.url));
+            for url in urls {
+                limiters.insert(url.to_string(), Limiter::new(url.as_str(), opts));
+            }
+        }
+
+        SiteState {
+            cache: site.cache.as_ref().map(cache::Store::new),
+            mirror: site.mirror.as_ref().map(|_| Mirror::default()),
+            balancer: if site.targets.len() > 1 {
+                Some(Balancer::new(site.targets.len()))
+            } else {
+                None
+            },
+            limiters,
+            fastcgi: site.fastcgi
+                .as_ref()
+                .map(|opts| CpuPool::new(opts.max_connections)),
+            cgi: site.serve
+                .as_ref()
+                .and_then(|serve| serve.cgi.as_ref())
+                .map(|opts| CpuPool::new(opts.max_processes)),
+        }
+    }
+}
+
+pub struct State {
+    sites: HashMap<Host, Arc<SiteState>>,
+}
+
+impl State {
+    pub fn new(config: &Config) -> Self {
+        let mut sites = HashMap::new();
+        for site in &config.sites {
+            let state = Arc::new(SiteState::new(site));
+            for host in &site.host {
+                sites.insert(host.clone(), Arc::clone(&state));
+            }
+        }
+        State { sites }
+    }
+
+    /// Get the state of a site that was configured when the server booted.
+    pub fn site(&self, site: &Site) -> &SiteState {
+        site.host
+            .first()
+            .and_then(|host| self.sites.get(host))
+            .expect("site state is built for every configured site")
+    }
+}
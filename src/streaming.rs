@@ -0,0 +1,198 @@
+//! Responses that are sent to the client as they're produced, like Server-Sent Events.
+//!
+//! They're passed through without buffering or compression, and they end once the upstream
+//! has been quiet for `streaming.idle_timeout`.
+
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll, Stream};
+use hyper::{self, header::{self, Headers}, Chunk};
+use tokio_core::reactor::{Handle, Timeout};
+
+use config;
+
+// The proxy tags streaming responses for the layers above it since the upstream's
+// Transfer-Encoding is gone by then.
+header! {
+    (Streaming, "X-Prox-Streaming") => [bool]
+}
+
+const KEEPALIVE: &[u8] = b": keepalive\n\n";
+
+pub fn is_event_stream(headers: &Headers) -> bool {
+    match headers.get::<header::ContentType>() {
+        None => false,
+        Some(header::ContentType(mime)) => {
+            mime.type_() == "text" && mime.subtype() == "event-stream"
+        }
+    }
+}
+
+/// The response should reach the client chunk by chunk as the upstream sends it.
+pub fn is_streaming(headers: &Headers) -> bool {
+    let unbuffered = headers
+        .get_raw("X-Accel-Buffering")
+        .and_then(|raw| raw.one())
+        .map(|value| value.eq_ignore_ascii_case(b"no"))
+        .unwrap_or(false);
+
+    headers.has::<Streaming>() || unbuffered || is_event_stream(headers)
+}
+
+/// The upstream is sending a chunked body of unknown length.
+///
+/// Only known before the proxy strips hop-by-hop headers.
+pub fn is_unbounded(headers: &Headers) -> bool {
+    let chunked = headers
+        .get::<header::TransferEncoding>()
+        .map(|header::TransferEncoding(encodings)| encodings.contains(&header::Encoding::Chunked))
+        .unwrap_or(false);
+
+    chunked && !headers.has::<header::ContentLength>()
+}
+
+/// Ends the stream once it's been idle for too long.
+///
+/// An event stream that's idle between events gets a comment every `sse_keepalive`
+/// so that the client and anything in between know that it's still alive.
+pub struct Idle<S> {
+    stream: S,
+    opts: &'static config::Streaming,
+    deadline: Timeout,
+    keepalive: Option<Timeout>,
+    // The last few bytes sent, to tell if the client is between events
+    tail: Vec<u8>,
+}
+
+impl<S> Idle<S> {
+    pub fn new(
+        stream: S,
+        opts: &'static config::Streaming,
+        event_stream: bool,
+        handle: &Handle,
+    ) -> Result<Self, hyper::Error> {
+        let deadline = Timeout::new(opts.idle_timeout, handle)?;
+        let keepalive = if event_stream && opts.sse_keepalive != Duration::from_secs(0) {
+            Some(Timeout::new(opts.sse_keepalive, handle)?)
+        } else {
+            None
+        };
+
+        Ok(Idle {
+            stream,
+            opts,
+            deadline,
+            keepalive,
+            tail: b"\n\n".to_vec(),
+        })
+    }
+
+    fn remember(&mut self, chunk: &[u8]) {
+        self.tail.extend_from_slice(&chunk[chunk.len().saturating_sub(4)..]);
+        let excess = self.tail.len().saturating_sub(4);
+        self.tail.drain(..excess);
+    }
+}
+
+/// Events end with a blank line.
+fn is_between_events(tail: &[u8]) -> bool {
+    tail.ends_with(b"\n\n") || tail.ends_with(b"\r\r") || tail.ends_with(b"\r\n\r\n")
+}
+
+impl<S> Stream for Idle<S>
+where
+    S: Stream<Item = Chunk, Error = hyper::Error>,
+{
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        match self.stream.poll()? {
+            Async::Ready(Some(chunk)) => {
+                let now = Instant::now();
+                self.deadline.reset(now + self.opts.idle_timeout);
+                if let Some(ref mut keepalive) = self.keepalive {
+                    keepalive.reset(now + self.opts.sse_keepalive);
+                }
+                self.remember(&chunk);
+                return Ok(Async::Ready(Some(chunk)));
+            }
+            Async::Ready(None) => return Ok(Async::Ready(None)),
+            Async::NotReady => {}
+        }
+
+        if let Async::Ready(()) = self.deadline.poll()? {
+            debug!("[streaming] upstream was idle for too long, ending the response");
+            return Ok(Async::Ready(None));
+        }
+
+        let between_events = is_between_events(&self.tail);
+
+        if let Some(ref mut keepalive) = self.keepalive {
+            if let Async::Ready(()) = keepalive.poll()? {
+                keepalive.reset(Instant::now() + self.opts.sse_keepalive);
+                if between_events {
+                    return Ok(Async::Ready(Some(Chunk::from(KEEPALIVE))));
+                }
+                // Wakes us up when the reset timer fires
+                keepalive.poll()?;
+            }
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+#[test]
+fn test_is_streaming() {
+    let mut headers = Headers::new();
+    assert!(!is_streaming(&headers));
+    headers.set_raw("X-Accel-Buffering", "yes");
+    assert!(!is_streaming(&headers));
+    headers.set_raw("X-Accel-Buffering", "no");
+    assert!(is_streaming(&headers));
+
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", "text/event-stream; charset=utf-8");
+    assert!(is_streaming(&headers));
+
+    let mut headers = Headers::new();
+    headers.set(header::TransferEncoding(vec![header::Encoding::Chunked]));
+    assert!(is_unbounded(&headers));
+    headers.set(header::ContentLength(42));
+    assert!(!is_unbounded(&headers));
+}
+
+#[test]
+fn test_idle() {
+    use futures::stream;
+    use leak::Leak;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let opts = Box::new(config::Streaming {
+        idle_timeout: Duration::from_millis(50),
+        sse_keepalive: Duration::from_millis(10),
+    }).leak();
+
+    // Sends one chunk and then nothing ever again
+    let quiet_after = |first: &'static str| {
+        stream::iter_ok::<_, hyper::Error>(vec![Chunk::from(first)]).chain(stream::poll_fn(|| Ok(Async::NotReady)))
+    };
+
+    // Kept alive between events until the upstream was idle for too long
+    let idle = Idle::new(quiet_after("data: a\n\n"), opts, true, &handle).unwrap();
+    let chunks = core.run(idle.collect()).unwrap();
+    assert_eq!(&chunks[0][..], b"data: a\n\n");
+    assert!(chunks.len() > 1);
+    assert!(chunks[1..].iter().all(|chunk| &chunk[..] == KEEPALIVE));
+
+    // Never in the middle of an event
+    let idle = Idle::new(quiet_after("data: a\n"), opts, true, &handle).unwrap();
+    assert_eq!(core.run(idle.collect()).unwrap().len(), 1);
+
+    // Other streams are just ended
+    let idle = Idle::new(quiet_after("a"), opts, false, &handle).unwrap();
+    assert_eq!(core.run(idle.collect()).unwrap().len(), 1);
+}
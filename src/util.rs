@@ -1,5 +1,12 @@
+#[cfg(test)]
+use std::{env, fs, process};
+#[cfg(test)]
+use std::ops::Deref;
+#[cfg(test)]
+use std::path::{Path, PathBuf};
+#[cfg(test)]
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
-use std::u64;
 
 use unicase::Ascii;
 
@@ -7,6 +14,14 @@ pub fn duration_as_millis(d: Duration) -> u64 {
     d.as_secs() * 1_000 + u64::from(d.subsec_nanos()) / 1_000_000
 }
 
+// The request target without the scheme and authority, e.g. "/search?q=prox".
+pub fn path_and_query(uri: &::hyper::Uri) -> String {
+    match uri.query() {
+        None => uri.path().to_string(),
+        Some(query) => format!("{}?{}", uri.path(), query),
+    }
+}
+
 // If the Vary header is empty, then create it.
 // If it's Vary::Any, then do nothing. (i.e. will already Vary)
 // If it's Vary::Items, append to the array.
@@ -15,11 +30,48 @@ pub fn append_header_vary(headers: &mut ::hyper::Headers, item: Ascii<String>) {
 
     match headers.get_mut::<Vary>() {
         Some(&mut Vary::Any) => {}
-        Some(&mut Vary::Items(ref mut xs)) => xs.push(item),
+        Some(&mut Vary::Items(ref mut xs)) => if !xs.contains(&item) {
+            xs.push(item)
+        },
         None => headers.set(Vary::Items(vec![item])),
     }
 }
 
+/// A test's own folder under the system temp folder. It's removed when dropped.
+#[cfg(test)]
+pub struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "prox_test_{}_{}_{}",
+            name,
+            process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+#[cfg(test)]
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
 macro_rules! hash_set {
     ( $( $k:expr ),* $(,)? ) => {
         {